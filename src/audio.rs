@@ -1,66 +1,192 @@
 use anyhow::Result;
-use cpal::{
-    traits::{DeviceTrait, HostTrait},
-    Device, Host, SampleRate, StreamConfig,
+use crate::config::AudioConfig;
+use crate::metadata::TrackMetadata;
+use crate::mixer::ChannelMixer;
+use crate::replaygain::ReplayGain;
+use crate::resample::{ResampleQuality, StreamResampler};
+use crate::sink::{
+    AudioSink, CpalSink, DeviceId, FormatRequest, NullSink, OutputControl, OutputSource,
+    SinkConfig, WavSink,
+};
+use ringbuf::{
+    traits::{Consumer, Observer, Producer, Split},
+    HeapCons, HeapProd, HeapRb,
 };
-use rubato::{Resampler, SincFixedIn, SincInterpolationType, SincInterpolationParameters, WindowFunction};
 use std::{
     collections::VecDeque,
-    sync::{Arc, Mutex},
-    thread,
+    fmt,
+    sync::{
+        atomic::Ordering,
+        mpsc::{self, Receiver, RecvTimeoutError, Sender, TryRecvError},
+        Arc, Mutex,
+    },
+    thread::{self, JoinHandle},
     time::Duration,
 };
 use symphonia::{
     core::{
-        audio::{AudioBufferRef, Signal, SignalSpec},
-        codecs::DecoderOptions,
-        formats::{FormatOptions},
+        audio::{AudioBufferRef, SampleBuffer},
+        codecs::{Decoder, DecoderOptions},
+        errors::Error as SymphoniaError,
+        formats::{FormatOptions, FormatReader, SeekMode, SeekTo},
         io::MediaSourceStream,
         meta::MetadataOptions,
         probe::Hint,
+        units::{Time, TimeBase},
     },
     default::get_probe,
 };
 
-pub struct AudioPlayer {
-    host: Host,
-    device: Device,
-    stream_config: StreamConfig,
-    sample_buffer: Arc<Mutex<VecDeque<f32>>>,
-    is_playing: Arc<Mutex<bool>>,
-    current_position: Arc<Mutex<Duration>>,
-    duration: Arc<Mutex<Duration>>,
-    volume: f32,
+// Seconds of decoded audio buffered ahead of the output callback
+const PLAYBACK_BUFFER_SECS: usize = 2;
+// Mono samples kept for the visualizer between UI updates
+const VISUALIZER_TAP_SIZE: usize = 8192;
+
+// Selects the output backend: "cpal" (default), "null" or "wav:<path>"
+const OUTPUT_ENV_VAR: &str = "RUST_MUSICPLAYER_OUTPUT";
+// Channel count used when there is no device to ask
+const FALLBACK_CHANNELS: u16 = 2;
+
+/// Maps frames played by the sink back to a position in a track.
+#[derive(Debug, Clone, Default)]
+struct PlaybackClock {
+    path: String,
+    duration: Duration,
+    start_frame: u64,
+    start_position: Duration,
+    // Rate the output runs at while this track plays
+    sample_rate: u32,
 }
 
-impl AudioPlayer {
-    pub fn new() -> Result<Self> {
-        let host = cpal::default_host();
-        let device = host
-            .default_output_device()
-            .ok_or_else(|| anyhow::anyhow!("No output device available"))?;
+impl PlaybackClock {
+    fn position(&self, frames_played: u64) -> Duration {
+        if self.sample_rate == 0 {
+            return self.start_position;
+        }
+        let frames = frames_played.saturating_sub(self.start_frame);
+        let position = self.start_position
+            + Duration::from_secs_f64(frames as f64 / self.sample_rate as f64);
 
-        let mut supported_configs = device.supported_output_configs()?;
-        let config = supported_configs
-            .next()
-            .ok_or_else(|| anyhow::anyhow!("No supported configs"))?
-            .with_sample_rate(SampleRate(48000));
+        if self.duration > Duration::ZERO {
+            position.min(self.duration)
+        } else {
+            position
+        }
+    }
+}
 
-        let stream_config = config.into();
+/// What the sink is playing now and what the decoder has already lined up behind it.
+#[derive(Debug, Default)]
+struct Timeline {
+    current: PlaybackClock,
+    // Tracks decoded gaplessly after the current one, not yet reached by the sink
+    pending: VecDeque<PlaybackClock>,
+}
 
-        Ok(Self {
-            host,
-            device,
-            stream_config,
-            sample_buffer: Arc::new(Mutex::new(VecDeque::new())),
-            is_playing: Arc::new(Mutex::new(false)),
-            current_position: Arc::new(Mutex::new(Duration::ZERO)),
-            duration: Arc::new(Mutex::new(Duration::ZERO)),
-            volume: 0.7,
-        })
+impl Timeline {
+    fn new(current: PlaybackClock) -> Self {
+        Self {
+            current,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// The clock of the track that is audible once the sink has played `frames_played`.
+    fn clock_at(&self, frames_played: u64) -> &PlaybackClock {
+        self.pending
+            .iter()
+            .rev()
+            .find(|clock| clock.start_frame <= frames_played)
+            .unwrap_or(&self.current)
     }
 
-    pub fn load_file(&mut self, path: &str) -> Result<()> {
+    /// Promotes queued tracks the sink has reached, returning their paths.
+    fn advance(&mut self, frames_played: u64) -> Vec<String> {
+        let mut reached = Vec::new();
+        while self
+            .pending
+            .front()
+            .is_some_and(|next| next.start_frame <= frames_played)
+        {
+            self.current = self.pending.pop_front().unwrap();
+            reached.push(self.current.path.clone());
+        }
+        reached
+    }
+}
+
+/// Where the player is. Only the engine thread moves between states.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub enum PlayerState {
+    /// Nothing loaded.
+    #[default]
+    Stopped,
+    /// Opening a file.
+    Loading(String),
+    Playing,
+    /// A track is loaded but not playing, including right after loading.
+    Paused,
+    /// The last track played out and nothing was queued after it.
+    Ended,
+    /// The file couldn't be opened or decoded.
+    Error(String),
+}
+
+impl PlayerState {
+    pub fn label(&self) -> &'static str {
+        match self {
+            PlayerState::Stopped => "STOPPED",
+            PlayerState::Loading(_) => "LOADING",
+            PlayerState::Playing => "PLAYING",
+            PlayerState::Paused => "PAUSED",
+            PlayerState::Ended => "ENDED",
+            PlayerState::Error(_) => "ERROR",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PlayerEvent {
+    StateChanged(PlayerState),
+    /// Playback moved on to the queued track without a gap.
+    TrackChanged(String),
+    /// Playback moved to another output, named as in `AudioPlayer::output_name`.
+    OutputChanged(String),
+    /// An output couldn't be opened or went away, and what the player did about it.
+    OutputError(String),
+}
+
+/// Requests from the player handle to the engine thread, handled in order.
+enum PlayerCommand {
+    /// Drops the current decoder and opens a file, leaving it paused at the start.
+    Load(String),
+    Play,
+    Pause,
+    Stop,
+    Seek(Duration),
+    /// The file to continue with gaplessly, or `None` to end after the current one.
+    QueueNext(Option<String>),
+    /// Moves playback to a device, or to the default one for `None`.
+    SetOutputDevice(Option<DeviceId>),
+    Shutdown,
+}
+
+/// An opened file, ready to hand out decoded packets.
+struct TrackSource {
+    path: String,
+    format: Box<dyn FormatReader>,
+    decoder: Box<dyn Decoder>,
+    track_id: u32,
+    time_base: Option<TimeBase>,
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: Option<u32>,
+    duration: Duration,
+    replay_gain: ReplayGain,
+}
+
+impl TrackSource {
+    fn open(path: &str) -> Result<Self> {
         let file = std::fs::File::open(path)?;
         let mss = MediaSourceStream::new(Box::new(file), Default::default());
         let hint = Hint::new();
@@ -68,7 +194,7 @@ impl AudioPlayer {
         let fmt_opts: FormatOptions = Default::default();
 
         let probed = get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-        let mut format = probed.format;
+        let format = probed.format;
 
         let track = format
             .tracks()
@@ -76,173 +202,976 @@ impl AudioPlayer {
             .find(|t| t.codec_params.codec != symphonia::core::codecs::CODEC_TYPE_NULL)
             .ok_or_else(|| anyhow::anyhow!("No supported audio tracks"))?;
 
-        let track_id = track.id;
-        let mut decoder = symphonia::default::get_codecs()
+        let decoder = symphonia::default::get_codecs()
             .make(&track.codec_params, &DecoderOptions::default())?;
+        let sample_rate = track.codec_params.sample_rate.unwrap_or(48000);
 
-        let spec = track.codec_params.sample_rate.map(|rate| {
-            SignalSpec::new(rate, symphonia::core::audio::Channels::FRONT_LEFT | symphonia::core::audio::Channels::FRONT_RIGHT)
-        }).unwrap_or_else(|| SignalSpec::new(48000, symphonia::core::audio::Channels::FRONT_LEFT | symphonia::core::audio::Channels::FRONT_RIGHT));
-        let sample_rate = spec.rate as usize;
-        let _channels = spec.channels.count();
+        // Calculate duration
+        let duration = track
+            .codec_params
+            .n_frames
+            .map(|frames| Duration::from_secs_f64(frames as f64 / sample_rate as f64))
+            .unwrap_or_default();
+
+        Ok(Self {
+            path: path.to_string(),
+            track_id: track.id,
+            time_base: track.codec_params.time_base,
+            sample_rate,
+            channels: track
+                .codec_params
+                .channels
+                .map_or(2, |channels| channels.count() as u16),
+            bits_per_sample: track.codec_params.bits_per_sample,
+            duration,
+            // Untagged or unreadable tags just mean no leveling
+            replay_gain: TrackMetadata::from_file(path)
+                .map(|metadata| metadata.replay_gain)
+                .unwrap_or_default(),
+            format,
+            decoder,
+        })
+    }
+
+    fn clock(&self, output: &DecodeOutput, start_position: Duration) -> PlaybackClock {
+        PlaybackClock {
+            path: self.path.clone(),
+            duration: self.duration,
+            start_frame: output.frames_written,
+            start_position,
+            sample_rate: output.output_rate,
+        }
+    }
+
+    fn format_request(&self, settings: &AudioConfig) -> FormatRequest {
+        FormatRequest {
+            sample_rate: self.sample_rate,
+            channels: self.channels,
+            bits_per_sample: self.bits_per_sample,
+            fallback_rate: settings.sample_rate,
+            bit_perfect: settings.bit_perfect,
+        }
+    }
+}
+
+/// Turns decoded buffers into output-ready samples and pushes them to the sink's buffer.
+struct DecodeOutput {
+    producer: HeapProd<f32>,
+    output_rate: u32,
+    output_channels: usize,
+    sample_buf: Option<SampleBuffer<f32>>,
+    mixer: Option<ChannelMixer>,
+    resampler: Option<StreamResampler>,
+    resample_quality: ResampleQuality,
+    source_rate: u32,
+    // ReplayGain of the track being decoded, as a linear factor
+    gain: f32,
+    // Frames produced so far, on the same scale as OutputControl::frames_played
+    frames_written: u64,
+    // Samples that didn't fit in the playback buffer yet
+    backlog: VecDeque<f32>,
+}
+
+impl DecodeOutput {
+    /// Writes to a newly opened output stream from now on. Anything still buffered for
+    /// the old one is dropped.
+    fn set_output(&mut self, producer: HeapProd<f32>, config: SinkConfig, frames_played: u64) {
+        self.producer = producer;
+        self.output_rate = config.sample_rate;
+        self.output_channels = config.channels as usize;
+        self.mixer = None;
+        self.resampler = None;
+        // Forces the next set_source_rate to set up resampling for the new rate
+        self.source_rate = 0;
+        self.backlog.clear();
+        self.frames_written = frames_played;
+    }
+
+    /// Prepares for a source at `sample_rate`. The resampler is kept when the rate is
+    /// unchanged so consecutive tracks join without a discontinuity; otherwise the old
+    /// one's tail is played out first.
+    fn set_source_rate(&mut self, sample_rate: u32) -> Result<()> {
+        if sample_rate == self.source_rate {
+            return Ok(());
+        }
+        self.finish()?;
+        self.source_rate = sample_rate;
 
         // Create resampler if needed
-        let mut resampler = if sample_rate != 48000 {
-            Some(SincFixedIn::<f32>::new(
-                (48000.0 / sample_rate as f32) as f64,
-                2.0,
-                SincInterpolationParameters {
-                    sinc_len: 256,
-                    f_cutoff: 0.95,
-                    interpolation: SincInterpolationType::Linear,
-                    oversampling_factor: 256,
-                    window: WindowFunction::BlackmanHarris2,
-                },
-                256,
-                256,
+        self.resampler = if sample_rate != self.output_rate {
+            Some(StreamResampler::new(
+                sample_rate,
+                self.output_rate,
+                self.output_channels,
+                self.resample_quality,
             )?)
         } else {
             None
         };
+        Ok(())
+    }
 
-        let sample_buffer = Arc::clone(&self.sample_buffer);
-        let is_playing = Arc::clone(&self.is_playing);
-        let current_position = Arc::clone(&self.current_position);
-        let duration = Arc::clone(&self.duration);
-        let volume = self.volume;
+    fn reset(&mut self) {
+        if let Some(resampler) = &mut self.resampler {
+            resampler.reset();
+        }
+    }
 
-        // Calculate duration
-        if let Some(dur) = track.codec_params.n_frames {
-            let duration_secs = dur as f64 / sample_rate as f64;
-            *duration.lock().unwrap() = Duration::from_secs_f64(duration_secs);
+    /// Writes out what the resampler is still holding, at the end of the stream.
+    fn finish(&mut self) -> Result<()> {
+        if let Some(resampler) = &mut self.resampler {
+            let tail = resampler.flush()?;
+            self.push(tail);
         }
+        Ok(())
+    }
 
-        thread::spawn(move || {
-            let _samples: Vec<f32> = Vec::new();
-            let mut position = Duration::ZERO;
+    /// Drops everything buffered, so the next samples written are heard straight away.
+    fn flush(&mut self, control: &OutputControl) {
+        self.reset();
+        self.backlog.clear();
+        control.flush();
+        self.frames_written = control.frames_played();
+    }
 
-            loop {
-                if !*is_playing.lock().unwrap() {
-                    thread::sleep(Duration::from_millis(10));
-                    continue;
+    /// Moves as many whole frames of the backlog as fit into the playback buffer.
+    /// Returns whether all of it went in.
+    fn drain_backlog(&mut self) -> bool {
+        // A partial frame would leave the output a channel out of step from then on
+        let room = self.producer.vacant_len().min(self.backlog.len());
+        let len = room - room % self.output_channels.max(1);
+        let (front, back) = self.backlog.as_slices();
+        let from_front = len.min(front.len());
+        self.producer.push_slice(&front[..from_front]);
+        self.producer.push_slice(&back[..len - from_front]);
+        self.backlog.drain(..len);
+        self.backlog.is_empty()
+    }
+
+    fn write(&mut self, audio_buf: AudioBufferRef, skip_frames: usize) -> Result<()> {
+        let spec = *audio_buf.spec();
+
+        // Convert to interleaved f32 samples, keeping every channel
+        let buf = match &mut self.sample_buf {
+            Some(buf) if buf.capacity() >= audio_buf.capacity() * spec.channels.count() => buf,
+            _ => self
+                .sample_buf
+                .insert(SampleBuffer::new(audio_buf.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(audio_buf);
+        let skipped = (skip_frames * spec.channels.count()).min(buf.len());
+
+        // Up- or downmix to the output channel layout
+        let mixer = match &mut self.mixer {
+            Some(mixer) if mixer.source() == spec.channels => mixer,
+            _ => self
+                .mixer
+                .insert(ChannelMixer::new(spec.channels, self.output_channels)),
+        };
+        let mut samples = mixer.mix(&buf.samples()[skipped..]);
+        if self.gain != 1.0 {
+            for sample in &mut samples {
+                *sample *= self.gain;
+            }
+        }
+
+        // Resample if needed
+        if let Some(resampler) = &mut self.resampler {
+            samples = resampler.process(&samples)?;
+        }
+
+        self.push(samples);
+        Ok(())
+    }
+
+    fn push(&mut self, samples: Vec<f32>) {
+        self.frames_written += (samples.len() / self.output_channels) as u64;
+        self.backlog.extend(samples);
+        self.drain_backlog();
+    }
+}
+
+// How often the engine checks on the sink while audio is playing out
+const ENGINE_POLL_INTERVAL: Duration = Duration::from_millis(5);
+// How often the engine checks the output is still there while nothing plays
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Owns the decoder. Runs on its own thread for the lifetime of the player, so there
+/// is only ever one decoder writing to the playback buffer.
+struct Engine {
+    commands: Receiver<PlayerCommand>,
+    events: Sender<PlayerEvent>,
+    settings: AudioConfig,
+    sink: Box<dyn AudioSink>,
+    control: Arc<OutputControl>,
+    visualizer_tap: Arc<Mutex<HeapCons<f32>>>,
+    output_info: Arc<Mutex<OutputInfo>>,
+    timeline: Arc<Mutex<Timeline>>,
+    state: Arc<Mutex<PlayerState>>,
+    output: DecodeOutput,
+    source: Option<TrackSource>,
+    next_source: Option<TrackSource>,
+    // Queued track that needs the output in another format, so it starts once the
+    // current one has played out instead of gaplessly
+    waiting_source: Option<TrackSource>,
+    // Packets before this timestamp are decoded but not played, to land exactly on a seek target
+    skip_until_ts: u64,
+    // Frame at which the sink runs dry, once the last track is fully decoded
+    end_frame: Option<u64>,
+}
+
+impl Engine {
+    fn run(mut self) {
+        loop {
+            let state = self.state.lock().unwrap().clone();
+            let decoding = state == PlayerState::Playing && self.end_frame.is_none();
+
+            // Between packets only look for commands; otherwise wait for one, waking up
+            // often while the sink still has audio to play or room to fill
+            let command = if decoding && self.output.backlog.is_empty() {
+                match self.commands.try_recv() {
+                    Ok(command) => Some(command),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
                 }
+            } else {
+                let timeout = if state == PlayerState::Playing {
+                    ENGINE_POLL_INTERVAL
+                } else {
+                    IDLE_POLL_INTERVAL
+                };
+                match self.commands.recv_timeout(timeout) {
+                    Ok(command) => Some(command),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => return,
+                }
+            };
 
-                match format.next_packet() {
-                    Ok(packet) => {
-                        if packet.track_id() != track_id {
-                            continue;
-                        }
-
-                        match decoder.decode(&packet) {
-                            Ok(audio_buf) => {
-                                let spec = audio_buf.spec();
-                                let _sample_rate = spec.rate as usize;
-                                let channels = spec.channels.count();
-
-                                // Convert to f32 samples
-                                let mut f32_samples = match audio_buf {
-                                    AudioBufferRef::F32(buf) => buf.chan(0).to_vec(),
-                                    AudioBufferRef::U8(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 128.0 - 1.0).collect()
-                                    }
-                                    AudioBufferRef::U16(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 32768.0).collect()
-                                    }
-                                    AudioBufferRef::U24(buf) => {
-                                        buf.chan(0).iter().map(|&s| s.inner() as f32 / 8388608.0).collect()
-                                    }
-                                    AudioBufferRef::U32(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 2147483648.0).collect()
-                                    }
-                                    AudioBufferRef::S8(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 128.0).collect()
-                                    }
-                                    AudioBufferRef::S16(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 32768.0).collect()
-                                    }
-                                    AudioBufferRef::S24(buf) => {
-                                        buf.chan(0).iter().map(|&s| s.inner() as f32 / 8388608.0).collect()
-                                    }
-                                    AudioBufferRef::S32(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32 / 2147483648.0).collect()
-                                    }
-                                    AudioBufferRef::F64(buf) => {
-                                        buf.chan(0).iter().map(|&s| s as f32).collect()
-                                    }
-                                };
-
-                                // Resample if needed
-                                if let Some(resampler) = &mut resampler {
-                                    let input = vec![f32_samples.clone()];
-                                    if let Ok(resampled) = resampler.process(&input, None) {
-                                        f32_samples = resampled[0].clone();
-                                    }
-                                }
-
-                                // Apply volume
-                                for sample in &mut f32_samples {
-                                    *sample *= volume;
-                                }
-
-                                // Add to buffer
-                                let sample_count = f32_samples.len();
-                                {
-                                    let mut buffer = sample_buffer.lock().unwrap();
-                                    for sample in f32_samples {
-                                        buffer.push_back(sample);
-                                    }
-                                }
-
-                                // Update position
-                                position += Duration::from_secs_f64(
-                                    sample_count as f64 / (48000.0 * channels as f64),
-                                );
-                                *current_position.lock().unwrap() = position;
-                            }
-                            Err(symphonia::core::errors::Error::ResetRequired) => {
-                                decoder.reset();
-                            }
-                            Err(_) => break,
-                        }
-                    }
-                    Err(symphonia::core::errors::Error::ResetRequired) => {
-                        decoder.reset();
-                    }
-                    Err(_) => break,
+            if let Some(command) = command {
+                if !self.handle(command) {
+                    return;
+                }
+                continue;
+            }
+
+            self.check_output();
+            self.follow_sink();
+            if decoding && self.output.drain_backlog() {
+                self.decode_packet();
+            }
+        }
+    }
+
+    /// Returns false once the player is shutting down.
+    fn handle(&mut self, command: PlayerCommand) -> bool {
+        match command {
+            PlayerCommand::Load(path) => self.load(path),
+            PlayerCommand::Play => {
+                if *self.state.lock().unwrap() == PlayerState::Paused {
+                    self.control.is_playing.store(true, Ordering::Relaxed);
+                    self.set_state(PlayerState::Playing);
+                }
+            }
+            PlayerCommand::Pause => {
+                if *self.state.lock().unwrap() == PlayerState::Playing {
+                    self.control.is_playing.store(false, Ordering::Relaxed);
+                    self.set_state(PlayerState::Paused);
                 }
             }
+            PlayerCommand::Stop => {
+                self.unload();
+                self.set_state(PlayerState::Stopped);
+            }
+            PlayerCommand::Seek(position) => self.seek(position),
+            PlayerCommand::QueueNext(path) => {
+                // An unreadable file simply ends playback after the current track
+                let source = path.and_then(|path| TrackSource::open(&path).ok());
+                if self.waiting_source.is_some() {
+                    self.waiting_source = source;
+                } else {
+                    self.next_source = source;
+                }
+            }
+            PlayerCommand::SetOutputDevice(device) => self.set_output_device(device),
+            PlayerCommand::Shutdown => {
+                self.unload();
+                return false;
+            }
+        }
+        true
+    }
+
+    fn set_state(&mut self, state: PlayerState) {
+        let mut current = self.state.lock().unwrap();
+        if *current != state {
+            *current = state.clone();
+            drop(current);
+            let _ = self.events.send(PlayerEvent::StateChanged(state));
+        }
+    }
+
+    /// Cancels the decoder and drops whatever it had buffered.
+    fn unload(&mut self) {
+        self.control.is_playing.store(false, Ordering::Relaxed);
+        self.source = None;
+        self.next_source = None;
+        self.waiting_source = None;
+        self.skip_until_ts = 0;
+        self.end_frame = None;
+        self.output.flush(&self.control);
+        // Nothing plays until the next load, so the position stays at zero
+        *self.timeline.lock().unwrap() = Timeline::new(PlaybackClock {
+            start_frame: self.output.frames_written,
+            ..PlaybackClock::default()
         });
+    }
+
+    /// Opens a new output stream at `config`, replacing the running one.
+    fn open_output(&mut self, config: SinkConfig) -> Result<()> {
+        let buffer_len =
+            config.sample_rate as usize * config.channels as usize * PLAYBACK_BUFFER_SECS;
+        let (producer, consumer) = HeapRb::<f32>::new(buffer_len).split();
+        let (tap_producer, tap_consumer) = HeapRb::<f32>::new(VISUALIZER_TAP_SIZE).split();
 
+        self.sink.start(
+            config,
+            OutputSource::new(consumer, tap_producer, Arc::clone(&self.control), config),
+        )?;
+        self.output
+            .set_output(producer, config, self.control.frames_played());
+        *self.visualizer_tap.lock().unwrap() = tap_consumer;
+        *self.output_info.lock().unwrap() = OutputInfo {
+            name: self.sink.name(),
+            device: self.sink.device(),
+            config,
+        };
         Ok(())
     }
 
+    fn set_output_device(&mut self, device: Option<DeviceId>) {
+        let name = device
+            .as_ref()
+            .map_or("the default device".to_string(), DeviceId::to_string);
+        match CpalSink::new(device.as_ref(), self.settings.sample_rate) {
+            Ok(sink) => match self.switch_output(Box::new(sink)) {
+                Ok(()) => {
+                    let name = self.output_info.lock().unwrap().to_string();
+                    let _ = self.events.send(PlayerEvent::OutputChanged(name));
+                }
+                Err(err) => self.fall_back(format!("Can't play through {name}: {err}")),
+            },
+            // The old output keeps playing
+            Err(err) => {
+                let _ = self
+                    .events
+                    .send(PlayerEvent::OutputError(format!("Can't open {name}: {err}")));
+            }
+        }
+    }
+
+    /// Moves playback to `sink`, carrying on from the position that was audible on the
+    /// old output.
+    fn switch_output(&mut self, sink: Box<dyn AudioSink>) -> Result<()> {
+        let frames_played = self.control.frames_played();
+        let (path, position) = {
+            let timeline = self.timeline.lock().unwrap();
+            let clock = timeline.clock_at(frames_played);
+            (clock.path.clone(), clock.position(frames_played))
+        };
+        self.sink = sink;
+
+        // The decoder may already be into a track the old output hadn't reached; go
+        // back to the audible one and queue the other again
+        if self.source.as_ref().is_some_and(|source| source.path != path) {
+            let ahead = self.source.take().map(|source| source.path);
+            self.next_source = ahead.and_then(|ahead| TrackSource::open(&ahead).ok());
+            match TrackSource::open(&path) {
+                Ok(source) => self.source = Some(source),
+                Err(err) => {
+                    self.unload();
+                    self.set_state(PlayerState::Error(format!("{path}: {err}")));
+                }
+            }
+        }
+
+        let config = match &self.source {
+            Some(source) => self.sink.negotiate(&source.format_request(&self.settings)),
+            None => self.sink.config(),
+        };
+        self.open_output(config)?;
+        if let Some(source) = &self.source {
+            self.output.gain = self.track_gain(source);
+            self.output.set_source_rate(source.sample_rate)?;
+        }
+        // After the end or a stop there's nothing to pick up
+        let state = self.state.lock().unwrap().clone();
+        if matches!(state, PlayerState::Playing | PlayerState::Paused) {
+            self.seek(position);
+        }
+        Ok(())
+    }
+
+    /// Moves to the default device when the output reports an error and turns out to
+    /// be gone, e.g. a USB DAC that was unplugged.
+    fn check_output(&mut self) {
+        if self.control.take_stream_error() && !self.sink.is_available() {
+            let name = self.sink.name();
+            self.fall_back(format!("{name} went away"));
+        }
+    }
+
+    /// Switches to the default device after `reason`, or to the null sink if that fails
+    /// too, so the player keeps going either way.
+    fn fall_back(&mut self, reason: String) {
+        let rate = self.settings.sample_rate;
+        let switched = CpalSink::new(None, rate)
+            .and_then(|sink| self.switch_output(Box::new(sink)))
+            .or_else(|_| {
+                let sink = NullSink::new(SinkConfig::float(rate, FALLBACK_CHANNELS));
+                self.switch_output(Box::new(sink))
+            });
+        let message = match switched {
+            Ok(()) => format!(
+                "{reason}; playing through {}",
+                self.output_info.lock().unwrap()
+            ),
+            Err(err) => format!("{reason}; no output left: {err}"),
+        };
+        let _ = self.events.send(PlayerEvent::OutputError(message));
+    }
+
+    /// Gets the output ready for `source`, switching its format if the one it
+    /// negotiates differs from the running one.
+    fn prepare_output(&mut self, source: &TrackSource) -> Result<()> {
+        let config = self.sink.negotiate(&source.format_request(&self.settings));
+        if config != self.sink.config() {
+            self.open_output(config)?;
+        }
+        self.output.set_source_rate(source.sample_rate)?;
+        self.output.gain = self.track_gain(source);
+        Ok(())
+    }
+
+    /// The ReplayGain to play `source` at. Bit-perfect output leaves the samples alone.
+    fn track_gain(&self, source: &TrackSource) -> f32 {
+        if self.sink.config().bit_perfect {
+            return 1.0;
+        }
+        source
+            .replay_gain
+            .factor(self.settings.replay_gain, self.settings.replay_gain_preamp)
+    }
+
+    /// Whether `source` can follow the current track without changing the output.
+    fn fits_output(&self, source: &TrackSource) -> bool {
+        self.sink.negotiate(&source.format_request(&self.settings)) == self.sink.config()
+    }
+
+    fn load(&mut self, path: String) {
+        self.unload();
+        self.set_state(PlayerState::Loading(path.clone()));
+
+        let opened = TrackSource::open(&path).and_then(|source| {
+            self.prepare_output(&source)?;
+            Ok(source)
+        });
+        match opened {
+            Ok(source) => {
+                *self.timeline.lock().unwrap() =
+                    Timeline::new(source.clock(&self.output, Duration::ZERO));
+                self.source = Some(source);
+                self.set_state(PlayerState::Paused);
+            }
+            Err(err) => self.set_state(PlayerState::Error(format!("{path}: {err}"))),
+        }
+    }
+
+    /// Jumps to `target` in the current track. After the end, this picks the track
+    /// back up, paused.
+    fn seek(&mut self, target: Duration) {
+        let Some(source) = &mut self.source else {
+            return;
+        };
+        let target = if source.duration > Duration::ZERO {
+            target.min(source.duration)
+        } else {
+            target
+        };
+        let seek_to = SeekTo::Time {
+            time: Time::new(target.as_secs(), target.subsec_nanos() as f64 / 1e9),
+            track_id: Some(source.track_id),
+        };
+        let Ok(seeked) = source.format.seek(SeekMode::Accurate, seek_to) else {
+            return;
+        };
+        source.decoder.reset();
+        self.skip_until_ts = seeked.required_ts;
+        self.end_frame = None;
+        if let Some(waiting) = self.waiting_source.take() {
+            self.next_source = Some(waiting);
+        }
+
+        let position = source
+            .time_base
+            .map(|tb| time_to_duration(tb.calc_time(seeked.required_ts)))
+            .unwrap_or(target);
+        self.output.flush(&self.control);
+        *self.timeline.lock().unwrap() =
+            Timeline::new(source.clock(&self.output, position));
+
+        if *self.state.lock().unwrap() == PlayerState::Ended {
+            self.set_state(PlayerState::Paused);
+        }
+    }
+
+    /// Reports the gapless track changes and the end of playback once the sink gets there.
+    fn follow_sink(&mut self) {
+        let frames_played = self.control.frames_played();
+        let reached = self.timeline.lock().unwrap().advance(frames_played);
+        for path in reached {
+            let _ = self.events.send(PlayerEvent::TrackChanged(path));
+        }
+
+        if self.end_frame.is_some_and(|end| end <= frames_played) {
+            self.end_frame = None;
+            match self.waiting_source.take() {
+                Some(next) => self.start_waiting(next),
+                None => {
+                    self.control.is_playing.store(false, Ordering::Relaxed);
+                    self.set_state(PlayerState::Ended);
+                }
+            }
+        }
+    }
+
+    /// Switches the output over for the queued track once the last one has played out.
+    fn start_waiting(&mut self, next: TrackSource) {
+        if let Err(err) = self.prepare_output(&next) {
+            let message = format!("{}: {err}", next.path);
+            self.unload();
+            self.set_state(PlayerState::Error(message));
+            return;
+        }
+        *self.timeline.lock().unwrap() = Timeline::new(next.clock(&self.output, Duration::ZERO));
+        let _ = self.events.send(PlayerEvent::TrackChanged(next.path.clone()));
+        self.source = Some(next);
+        self.skip_until_ts = 0;
+    }
+
+    fn decode_packet(&mut self) {
+        let Some(source) = &mut self.source else {
+            return;
+        };
+
+        match source.format.next_packet() {
+            Ok(packet) => {
+                if packet.track_id() != source.track_id {
+                    return;
+                }
+                if packet.ts() + packet.dur() <= self.skip_until_ts {
+                    // Still needs decoding so the decoder state stays valid
+                    let _ = source.decoder.decode(&packet);
+                    return;
+                }
+                // Timestamps count frames for the audio codecs symphonia supports
+                let skip_frames = self.skip_until_ts.saturating_sub(packet.ts()) as usize;
+
+                let result = match source.decoder.decode(&packet) {
+                    Ok(audio_buf) => self.output.write(audio_buf, skip_frames),
+                    Err(SymphoniaError::ResetRequired) => {
+                        source.decoder.reset();
+                        Ok(())
+                    }
+                    // A corrupt packet only costs a few milliseconds of audio
+                    Err(SymphoniaError::DecodeError(_)) => Ok(()),
+                    Err(err) => Err(err.into()),
+                };
+                if let Err(err) = result {
+                    let message = format!("{}: {err}", source.path);
+                    self.unload();
+                    self.set_state(PlayerState::Error(message));
+                }
+            }
+            Err(SymphoniaError::ResetRequired) => source.decoder.reset(),
+            Err(_) => {
+                // End of track: carry straight on into the queued one, if any
+                let next = self.next_source.take();
+                match next {
+                    Some(next) if !self.fits_output(&next) => {
+                        let _ = self.output.finish();
+                        self.end_frame = Some(self.output.frames_written);
+                        self.waiting_source = Some(next);
+                    }
+                    Some(next) if self.output.set_source_rate(next.sample_rate).is_ok() => {
+                        self.output.gain = self.track_gain(&next);
+                        self.timeline
+                            .lock()
+                            .unwrap()
+                            .pending
+                            .push_back(next.clock(&self.output, Duration::ZERO));
+                        self.source = Some(next);
+                        self.skip_until_ts = 0;
+                    }
+                    _ => {
+                        // Whatever the resampler still holds belongs to this track
+                        let _ = self.output.finish();
+                        self.end_frame = Some(self.output.frames_written);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The output and the format it's running at.
+#[derive(Debug, Clone)]
+struct OutputInfo {
+    name: String,
+    device: Option<DeviceId>,
+    config: SinkConfig,
+}
+
+impl fmt::Display for OutputInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.name, self.config.describe())
+    }
+}
+
+/// Handle to the playback engine. Commands are queued to the engine thread and take
+/// effect in order; what happened comes back through `poll_events`.
+pub struct AudioPlayer {
+    visualizer_tap: Arc<Mutex<HeapCons<f32>>>,
+    output_info: Arc<Mutex<OutputInfo>>,
+    control: Arc<OutputControl>,
+    timeline: Arc<Mutex<Timeline>>,
+    state: Arc<Mutex<PlayerState>>,
+    commands: Sender<PlayerCommand>,
+    events: Receiver<PlayerEvent>,
+    engine: Option<JoinHandle<()>>,
+}
+
+impl AudioPlayer {
+    /// Opens the output selected by `RUST_MUSICPLAYER_OUTPUT`, or else the configured
+    /// device. Falls back to the default device when that one isn't there, and to the
+    /// null sink when there is no sound device at all.
+    pub fn new(settings: AudioConfig) -> Result<Self> {
+        let fallback_config = SinkConfig::float(settings.sample_rate, FALLBACK_CHANNELS);
+        let sink: Box<dyn AudioSink> = match std::env::var(OUTPUT_ENV_VAR).as_deref() {
+            Ok("null") => Box::new(NullSink::new(fallback_config)),
+            Ok(value) if value.starts_with("wav:") => {
+                Box::new(WavSink::new(&value[4..], fallback_config)?)
+            }
+            _ => match CpalSink::new(settings.output_device().as_ref(), settings.sample_rate)
+                .or_else(|_| CpalSink::new(None, settings.sample_rate))
+            {
+                Ok(sink) => Box::new(sink),
+                Err(_) => Box::new(NullSink::new(fallback_config)),
+            },
+        };
+
+        Self::with_sink(sink, settings)
+    }
+
+    pub fn with_sink(sink: Box<dyn AudioSink>, settings: AudioConfig) -> Result<Self> {
+        let sink_config = sink.config();
+        let resample_quality = settings.resample_quality;
+        let control = Arc::new(OutputControl::new(settings.volume));
+        let visualizer_tap = Arc::new(Mutex::new(HeapRb::new(1).split().1));
+        let output_info = Arc::new(Mutex::new(OutputInfo {
+            name: sink.name(),
+            device: sink.device(),
+            config: sink_config,
+        }));
+        let timeline = Arc::new(Mutex::new(Timeline::default()));
+        let state = Arc::new(Mutex::new(PlayerState::Stopped));
+        let (commands, command_rx) = mpsc::channel();
+        let (event_tx, events) = mpsc::channel();
+
+        let mut engine = Engine {
+            commands: command_rx,
+            events: event_tx,
+            settings,
+            sink,
+            control: Arc::clone(&control),
+            visualizer_tap: Arc::clone(&visualizer_tap),
+            output_info: Arc::clone(&output_info),
+            timeline: Arc::clone(&timeline),
+            state: Arc::clone(&state),
+            output: DecodeOutput {
+                producer: HeapRb::new(1).split().0,
+                output_rate: sink_config.sample_rate,
+                output_channels: sink_config.channels as usize,
+                sample_buf: None,
+                mixer: None,
+                resampler: None,
+                resample_quality,
+                source_rate: 0,
+                gain: 1.0,
+                frames_written: 0,
+                backlog: VecDeque::new(),
+            },
+            source: None,
+            next_source: None,
+            waiting_source: None,
+            skip_until_ts: 0,
+            end_frame: None,
+        };
+        engine.open_output(sink_config)?;
+        let engine = thread::Builder::new()
+            .name("playback".to_string())
+            .spawn(move || engine.run())?;
+
+        Ok(Self {
+            visualizer_tap,
+            output_info,
+            control,
+            timeline,
+            state,
+            commands,
+            events,
+            engine: Some(engine),
+        })
+    }
+
+    fn send(&self, command: PlayerCommand) {
+        // Only fails if the engine thread is gone, and then there is nothing to control
+        let _ = self.commands.send(command);
+    }
+
+    /// Replaces whatever is loaded with `path`, paused at the start. A file that can't
+    /// be opened ends up as `PlayerState::Error`.
+    pub fn load_file(&self, path: &str) {
+        self.send(PlayerCommand::Load(path.to_string()));
+    }
+
+    /// Sets the track to play after the current one, so it can start without a gap.
+    pub fn queue_next(&self, path: Option<&str>) {
+        self.send(PlayerCommand::QueueNext(path.map(String::from)));
+    }
+
+    /// Returns what happened since the last call: state changes and gapless track changes.
+    pub fn poll_events(&self) -> Vec<PlayerEvent> {
+        self.events.try_iter().collect()
+    }
+
+    /// Jumps to `position` in the current track, dropping any audio already buffered.
+    pub fn seek(&self, position: Duration) {
+        self.send(PlayerCommand::Seek(position));
+    }
+
     pub fn play(&self) {
-        *self.is_playing.lock().unwrap() = true;
+        self.send(PlayerCommand::Play);
     }
 
     pub fn pause(&self) {
-        *self.is_playing.lock().unwrap() = false;
+        self.send(PlayerCommand::Pause);
+    }
+
+    /// Unloads the track, cancelling its decoder.
+    pub fn stop(&self) {
+        self.send(PlayerCommand::Stop);
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state.lock().unwrap().clone()
     }
 
     pub fn is_playing(&self) -> bool {
-        *self.is_playing.lock().unwrap()
+        self.state() == PlayerState::Playing
     }
 
+    /// Position of the audio currently coming out of the sink, not of the decoder.
     pub fn get_position(&self) -> Duration {
-        *self.current_position.lock().unwrap()
+        let frames_played = self.control.frames_played();
+        let timeline = self.timeline.lock().unwrap();
+        timeline.clock_at(frames_played).position(frames_played)
     }
 
     pub fn get_duration(&self) -> Duration {
-        *self.duration.lock().unwrap()
+        let frames_played = self.control.frames_played();
+        let timeline = self.timeline.lock().unwrap();
+        timeline.clock_at(frames_played).duration
     }
 
+    /// Drains the mono mix of everything the output stream has played since the last call.
     pub fn get_samples(&self) -> Vec<f32> {
-        let mut buffer = self.sample_buffer.lock().unwrap();
-        let samples: Vec<f32> = buffer.drain(..).collect();
-        samples
+        let mut tap = self.visualizer_tap.lock().unwrap();
+        tap.pop_iter().collect()
+    }
+
+    /// The output and the format it's running at, e.g. "Speakers (44.1 kHz, 2 ch, i16)".
+    pub fn output_name(&self) -> String {
+        self.output_info.lock().unwrap().to_string()
+    }
+
+    /// The sound device playing, or `None` for the null and WAV outputs.
+    pub fn output_device(&self) -> Option<DeviceId> {
+        self.output_info.lock().unwrap().device.clone()
+    }
+
+    /// Moves playback to `device`, or the default device for `None`, keeping the
+    /// position. `PlayerEvent::OutputChanged` or `OutputError` tells how it went.
+    pub fn set_output_device(&self, device: Option<DeviceId>) {
+        self.send(PlayerCommand::SetOutputDevice(device));
+    }
+
+    /// Whether the output currently carries the track untouched, so volume has no effect.
+    pub fn is_bit_perfect(&self) -> bool {
+        self.output_info.lock().unwrap().config.bit_perfect
+    }
+
+    /// Takes effect on audio already buffered, ramped to avoid clicks.
+    pub fn set_volume(&self, volume: f32) {
+        self.control.set_volume(volume);
+    }
+
+    pub fn get_volume(&self) -> f32 {
+        self.control.volume()
     }
 
-    pub fn set_volume(&mut self, volume: f32) {
-        self.volume = volume.clamp(0.0, 1.0);
+    pub fn toggle_mute(&self) {
+        self.control.set_muted(!self.control.is_muted());
     }
-}
\ No newline at end of file
+
+    pub fn is_muted(&self) -> bool {
+        self.control.is_muted()
+    }
+}
+
+impl Drop for AudioPlayer {
+    fn drop(&mut self) {
+        self.send(PlayerCommand::Shutdown);
+        if let Some(engine) = self.engine.take() {
+            let _ = engine.join();
+        }
+    }
+}
+
+fn time_to_duration(time: Time) -> Duration {
+    Duration::from_secs(time.seconds) + Duration::from_secs_f64(time.frac)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf, time::Instant};
+
+    const RATE: u32 = 44100;
+
+    /// Writes a 16-bit stereo WAV whose left channel ramps up and right channel ramps
+    /// down, so a swapped or shifted channel shows up in the output.
+    fn test_wav(name: &str, frames: usize) -> (PathBuf, Vec<f32>) {
+        let path = temp_path(&format!("{name}.wav"));
+        let spec = hound::WavSpec {
+            channels: 2,
+            sample_rate: RATE,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        let mut samples = Vec::new();
+        for i in 0..frames {
+            let left = (i % 20000) as i16;
+            for sample in [left, -left] {
+                writer.write_sample(sample).unwrap();
+                samples.push(sample as f32 / 32768.0);
+            }
+        }
+        writer.finalize().unwrap();
+        (path, samples)
+    }
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rust_musicplayer-{}-{name}", std::process::id()))
+    }
+
+    fn player(sink: Box<dyn AudioSink>) -> AudioPlayer {
+        let settings = AudioConfig {
+            volume: 1.0,
+            ..AudioConfig::default()
+        };
+        AudioPlayer::with_sink(sink, settings).unwrap()
+    }
+
+    fn wait_until_ended(player: &AudioPlayer) {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while player.state() != PlayerState::Ended {
+            assert!(Instant::now() < deadline, "still {:?}", player.state());
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn read_wav(path: &PathBuf) -> Vec<f32> {
+        hound::WavReader::open(path)
+            .unwrap()
+            .samples::<f32>()
+            .map(Result::unwrap)
+            .collect()
+    }
+
+    #[test]
+    fn plays_a_file_into_a_wav_sink_unchanged() {
+        let (input, expected) = test_wav("unchanged-in", 12345);
+        let output = temp_path("unchanged-out.wav");
+        let sink = WavSink::new(&output, SinkConfig::float(RATE, 2)).unwrap();
+
+        let player = player(Box::new(sink));
+        player.load_file(input.to_str().unwrap());
+        player.play();
+        wait_until_ended(&player);
+        assert_eq!(player.get_duration(), Duration::from_secs_f64(12345.0 / RATE as f64));
+        assert_eq!(player.get_position(), player.get_duration());
+        drop(player);
+
+        let written = read_wav(&output);
+        assert_eq!(written.len() / 2, 12345);
+        for (written, expected) in written.iter().zip(&expected) {
+            assert!((written - expected).abs() < 1e-6);
+        }
+        let _ = fs::remove_file(input);
+        let _ = fs::remove_file(output);
+    }
+
+    #[test]
+    fn queued_track_follows_without_a_gap() {
+        let (first, mut expected) = test_wav("gapless-a", 5000);
+        let (second, second_samples) = test_wav("gapless-b", 7000);
+        expected.extend(second_samples);
+        let output = temp_path("gapless-out.wav");
+        let sink = WavSink::new(&output, SinkConfig::float(RATE, 2)).unwrap();
+
+        let player = player(Box::new(sink));
+        let second_path = second.to_str().unwrap().to_string();
+        player.load_file(first.to_str().unwrap());
+        player.queue_next(Some(&second_path));
+        player.play();
+        wait_until_ended(&player);
+        assert!(player
+            .poll_events()
+            .contains(&PlayerEvent::TrackChanged(second_path)));
+        drop(player);
+
+        let written = read_wav(&output);
+        assert_eq!(written.len(), expected.len());
+        for (written, expected) in written.iter().zip(&expected) {
+            assert!((written - expected).abs() < 1e-6);
+        }
+        for path in [first, second, output] {
+            let _ = fs::remove_file(path);
+        }
+    }
+
+    #[test]
+    fn null_sink_plays_in_real_time() {
+        let (input, _) = test_wav("null", RATE as usize / 4);
+        let player = player(Box::new(NullSink::new(SinkConfig::float(48000, 2))));
+
+        let started = Instant::now();
+        player.load_file(input.to_str().unwrap());
+        player.play();
+        wait_until_ended(&player);
+        assert!(started.elapsed() >= Duration::from_millis(240));
+        assert_eq!(player.get_position(), Duration::from_millis(250));
+        let _ = fs::remove_file(input);
+    }
+}
@@ -0,0 +1,142 @@
+use crate::metadata::TrackMetadata;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+// Bump when the on-disk layout changes; older indexes are then discarded and rebuilt
+const CACHE_VERSION: u32 = 4;
+const CACHE_FILE_NAME: &str = "library.json";
+
+/// Size and modification time of a file, used to tell whether its tags need reading again.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    // Nanoseconds since the Unix epoch
+    pub modified: u64,
+}
+
+impl FileStamp {
+    pub fn from_metadata(metadata: &fs::Metadata) -> Self {
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        Self {
+            size: metadata.len(),
+            modified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    stamp: FileStamp,
+    metadata: TrackMetadata,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    version: u32,
+    // Keyed by file path
+    entries: HashMap<String, CacheEntry>,
+}
+
+/// On-disk index of parsed tags, so a rescan only reads files that are new or changed.
+#[derive(Debug)]
+pub struct LibraryCache {
+    file: Option<PathBuf>,
+    index: CacheIndex,
+}
+
+/// The one cache the scanner, the watcher and the tag editor all write through, so none
+/// of them saves over what another has just stored.
+pub type SharedCache = Arc<Mutex<LibraryCache>>;
+
+impl LibraryCache {
+    /// Opens the index in the XDG data dir. A missing or unreadable index starts empty.
+    pub fn load() -> Self {
+        let file = dirs::data_dir().map(|dir| dir.join("rust_musicplayer").join(CACHE_FILE_NAME));
+        let index = file
+            .as_deref()
+            .and_then(|file| fs::read(file).ok())
+            .and_then(|bytes| serde_json::from_slice::<CacheIndex>(&bytes).ok())
+            .filter(|index| index.version == CACHE_VERSION)
+            .unwrap_or_default();
+
+        Self { file, index }
+    }
+
+    /// The cached tags for `path`, if the file hasn't changed since they were read.
+    pub fn get(&self, path: &str, stamp: FileStamp) -> Option<&TrackMetadata> {
+        self.index
+            .entries
+            .get(path)
+            .filter(|entry| entry.stamp == stamp)
+            .map(|entry| &entry.metadata)
+    }
+
+    /// Stores freshly read tags. A file already in the index keeps its date added.
+    pub fn insert(&mut self, stamp: FileStamp, mut metadata: TrackMetadata) -> &TrackMetadata {
+        let added = self
+            .index
+            .entries
+            .get(&metadata.file_path)
+            .and_then(|entry| entry.metadata.date_added);
+        metadata.date_added = added.or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .ok()
+                .map(|d| d.as_secs())
+        });
+
+        let path = metadata.file_path.clone();
+        self.index
+            .entries
+            .insert(path.clone(), CacheEntry { stamp, metadata });
+        &self.index.entries[&path].metadata
+    }
+
+    /// Like `insert`, but only for files already in the index, so tracks from outside
+    /// the music roots don't turn up in the library. Returns whether it was stored.
+    pub fn refresh(&mut self, stamp: FileStamp, metadata: TrackMetadata) -> bool {
+        if !self.index.entries.contains_key(&metadata.file_path) {
+            return false;
+        }
+        self.insert(stamp, metadata);
+        true
+    }
+
+    /// Drops every entry `keep` rejects, e.g. files that were not seen during a rescan.
+    pub fn retain(&mut self, mut keep: impl FnMut(&str) -> bool) {
+        self.index.entries.retain(|path, _| keep(path));
+    }
+
+    pub fn tracks(&self) -> impl Iterator<Item = &TrackMetadata> {
+        self.index.entries.values().map(|entry| &entry.metadata)
+    }
+
+    /// Writes the index, replacing the old one atomically so a crash can't corrupt it.
+    pub fn save(&mut self) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        if let Some(dir) = file.parent() {
+            fs::create_dir_all(dir)?;
+        }
+
+        self.index.version = CACHE_VERSION;
+        let temp = file.with_extension("json.tmp");
+        fs::write(&temp, serde_json::to_vec(&self.index)?)?;
+        fs::rename(&temp, file)?;
+        Ok(())
+    }
+}
\ No newline at end of file
@@ -0,0 +1,342 @@
+use crate::{
+    audio::{AudioPlayer, PlayerEvent, PlayerState},
+    cache::{FileStamp, LibraryCache},
+    config::{AudioConfig, Config},
+    library::MusicLibrary,
+    metadata::TrackMetadata,
+    replaygain,
+    scanner::{self, LibraryScan, ScanUpdate},
+};
+use anyhow::{bail, Result};
+use clap::{Parser, Subcommand, ValueEnum};
+use rayon::prelude::*;
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+use walkdir::WalkDir;
+
+#[derive(Debug, Parser)]
+#[command(name = "rust_musicplayer", version, about = "A terminal music player")]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Music folder to add to the library, or a file to play
+    pub path: Option<PathBuf>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Scan the music folders and update the library cache
+    Scan {
+        /// Folder to add to the music folders before scanning
+        path: Option<PathBuf>,
+    },
+    /// Print the albums or tracks in the library
+    List {
+        #[arg(value_enum)]
+        what: ListKind,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Play a file
+    Play {
+        file: PathBuf,
+        /// Play in the terminal without the interface, and exit at the end of the track
+        #[arg(long)]
+        no_tui: bool,
+    },
+    /// Show a file's tags and duration
+    Info {
+        file: PathBuf,
+        /// Print JSON instead of text
+        #[arg(long)]
+        json: bool,
+    },
+    /// Measure loudness (EBU R128) and work out ReplayGain for tracks without gain tags
+    Analyze {
+        /// Files or folders; folders are searched for the library's file types
+        #[arg(required = true)]
+        paths: Vec<PathBuf>,
+        /// Write the gain tags to the files
+        #[arg(long)]
+        write: bool,
+        /// Measure tracks that already have gain tags too
+        #[arg(long)]
+        force: bool,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, ValueEnum)]
+pub enum ListKind {
+    Albums,
+    Tracks,
+}
+
+/// Runs a subcommand that doesn't need the TUI.
+pub fn run(command: Command, config: &mut Config) -> Result<()> {
+    match command {
+        Command::Scan { path } => scan(path.as_deref(), config),
+        Command::List { what, json } => list(what, json, config),
+        Command::Play { file, .. } => play(&file, config.audio.clone()),
+        Command::Info { file, json } => info(&file, json),
+        Command::Analyze {
+            paths,
+            write,
+            force,
+        } => analyze(&paths, write, force, config),
+    }
+}
+
+/// Turns a path from the command line into the absolute form the library stores.
+pub fn resolve_path(path: &Path) -> Result<PathBuf> {
+    match path.canonicalize() {
+        Ok(path) => Ok(path),
+        Err(err) => bail!("{}: {err}", path.display()),
+    }
+}
+
+fn scan(path: Option<&Path>, config: &mut Config) -> Result<()> {
+    let cache = LibraryCache::load();
+    let mut library = MusicLibrary::load_cached(&cache, config.library.roots.clone());
+    if let Some(path) = path {
+        library.add_root(resolve_path(path)?);
+        config.save_roots(&library.music_roots)?;
+    }
+    if library.music_roots.is_empty() {
+        bail!("No music folders yet; pass one to scan, e.g. `rust_musicplayer scan ~/Music`");
+    }
+
+    let scan = LibraryScan::start(
+        library.music_roots.clone(),
+        config.library.extensions.clone(),
+        Arc::new(Mutex::new(cache)),
+    );
+    loop {
+        thread::sleep(Duration::from_millis(100));
+        let counts = scan.progress();
+        eprint!(
+            "\rScanning: {} found | {} cached | {} parsed | {} failed",
+            counts.seen, counts.cached, counts.parsed, counts.failed
+        );
+
+        for update in scan.poll_updates() {
+            match update {
+                ScanUpdate::Tracks(tracks) => library.merge_tracks(tracks),
+                ScanUpdate::Finished { found } => {
+                    library.retain_paths(&found);
+                    eprintln!();
+                    println!(
+                        "{} tracks in {} albums",
+                        library.all_tracks.len(),
+                        library.albums.len()
+                    );
+                    return Ok(());
+                }
+                ScanUpdate::Cancelled => bail!("Scan cancelled"),
+                ScanUpdate::Failed(err) => bail!("Scan failed: {err}"),
+            }
+        }
+    }
+}
+
+fn list(what: ListKind, json: bool, config: &Config) -> Result<()> {
+    let mut library = MusicLibrary::load_cached(&LibraryCache::load(), Vec::new());
+    library.set_sort(config.library.sort);
+    let mut stdout = io::stdout().lock();
+
+    match (what, json) {
+        (ListKind::Albums, true) => serde_json::to_writer_pretty(&mut stdout, &library.albums)?,
+        (ListKind::Tracks, true) => {
+            serde_json::to_writer_pretty(&mut stdout, &library.all_tracks)?
+        }
+        (ListKind::Albums, false) => {
+            for album in &library.albums {
+                let year = album.year().map(|year| format!(" [{year}]")).unwrap_or_default();
+                writeln!(
+                    stdout,
+                    "{}{year} ({} tracks)",
+                    album.display_name(),
+                    album.tracks.len()
+                )?;
+            }
+        }
+        (ListKind::Tracks, false) => {
+            for track in &library.all_tracks {
+                writeln!(
+                    stdout,
+                    "{} - {} [{}]\t{}",
+                    track.display_artist(),
+                    track.title,
+                    track.display_album(),
+                    track.file_path
+                )?;
+            }
+        }
+    }
+    if json {
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+fn play(file: &Path, settings: AudioConfig) -> Result<()> {
+    let file = resolve_path(file)?;
+    let metadata = TrackMetadata::from_file(&file)?;
+
+    let player = AudioPlayer::new(settings)?;
+    player.load_file(&file.to_string_lossy());
+    player.play();
+    println!("Playing {} - {}", metadata.display_artist(), metadata.title);
+
+    loop {
+        thread::sleep(Duration::from_millis(250));
+        let mut ended = false;
+        for event in player.poll_events() {
+            match event {
+                PlayerEvent::StateChanged(PlayerState::Ended) => ended = true,
+                PlayerEvent::StateChanged(PlayerState::Error(message)) => {
+                    eprintln!();
+                    bail!(message);
+                }
+                _ => {}
+            }
+        }
+        if ended {
+            break;
+        }
+
+        let position = player.get_position().as_secs();
+        let duration = player.get_duration().as_secs();
+        eprint!(
+            "\r{:02}:{:02} / {:02}:{:02}",
+            position / 60,
+            position % 60,
+            duration / 60,
+            duration % 60
+        );
+    }
+    eprintln!();
+    Ok(())
+}
+
+fn info(file: &Path, json: bool) -> Result<()> {
+    let metadata = TrackMetadata::from_file(resolve_path(file)?)?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&metadata)?);
+        return Ok(());
+    }
+
+    for (label, value) in metadata.details() {
+        println!("{:<13}{value}", format!("{label}:"));
+    }
+    Ok(())
+}
+
+fn analyze(paths: &[PathBuf], write: bool, force: bool, config: &Config) -> Result<()> {
+    let mut files = Vec::new();
+    for path in paths {
+        let path = resolve_path(path)?;
+        if path.is_dir() {
+            files.extend(
+                WalkDir::new(&path)
+                    .into_iter()
+                    .filter_map(|e| e.ok())
+                    .filter(|e| {
+                        e.file_type().is_file()
+                            && scanner::is_supported(e.path(), &config.library.extensions)
+                    })
+                    .map(|e| e.into_path()),
+            );
+        } else {
+            files.push(path);
+        }
+    }
+
+    let mut tracks = Vec::new();
+    for (path, result) in files
+        .par_iter()
+        .map(|path| (path, TrackMetadata::from_file(path)))
+        .collect::<Vec<_>>()
+    {
+        match result {
+            Ok(track) => tracks.push(track),
+            Err(err) => eprintln!("Skipping {}: {err}", path.display()),
+        }
+    }
+
+    let done = AtomicUsize::new(0);
+    let report = replaygain::analyze(&tracks, force, |_| {
+        let done = done.fetch_add(1, Ordering::Relaxed) + 1;
+        eprint!("\rAnalyzing: {done} tracks measured");
+    });
+    if done.load(Ordering::Relaxed) > 0 {
+        eprintln!();
+    }
+    if report.tracks.is_empty() && report.failed.is_empty() {
+        println!("All {} tracks already have gain tags", tracks.len());
+        return Ok(());
+    }
+
+    let mut analyses = report.tracks;
+    analyses.sort_by(|a, b| a.path.cmp(&b.path));
+    for analysis in &analyses {
+        let gain = &analysis.gain;
+        let loudness = match analysis.loudness {
+            Some(lufs) => format!("{lufs:.1} LUFS"),
+            None => "silent".to_string(),
+        };
+        let track = replaygain::describe_gain(gain.track_gain, gain.track_peak);
+        let album = replaygain::describe_gain(gain.album_gain, gain.album_peak);
+        println!(
+            "{loudness:>11}  track {:<24} album {:<24} {}",
+            track.unwrap_or_else(|| "-".to_string()),
+            album.unwrap_or_else(|| "-".to_string()),
+            analysis.path
+        );
+    }
+    for (path, err) in &report.failed {
+        eprintln!("Couldn't measure {path}: {err}");
+    }
+    if !write {
+        println!("Nothing written; run again with --write to save these as tags");
+        return Ok(());
+    }
+
+    let mut rewritten = Vec::new();
+    for analysis in analyses.iter().filter(|analysis| analysis.loudness.is_some()) {
+        let path = &analysis.path;
+        let result = replaygain::write_tags(path, &analysis.gain).and_then(|()| {
+            let stamp = FileStamp::from_metadata(&fs::metadata(path)?);
+            Ok((stamp, TrackMetadata::from_file(path)?))
+        });
+        match result {
+            Ok(track) => rewritten.push(track),
+            Err(err) => eprintln!("Couldn't write {path}: {err}"),
+        }
+    }
+    let written = rewritten.len();
+
+    // Keep the library cache in step, as the tag editor does. It is loaded just before
+    // saving to keep the window small; a running player's watcher sees the files anyway.
+    let mut cache = LibraryCache::load();
+    let mut refreshed = false;
+    for (stamp, metadata) in rewritten {
+        refreshed |= cache.refresh(stamp, metadata);
+    }
+    if refreshed {
+        cache.save()?;
+    }
+    println!("Wrote gain tags to {written} file(s)");
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,253 @@
+use crate::keymap::{KeyBinding, Keymap};
+use crate::library::AlbumSort;
+use crate::replaygain::ReplayGainMode;
+use crate::resample::ResampleQuality;
+use crate::sink::DeviceId;
+use crate::ui::Theme;
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml_edit::{table, value, Array, DocumentMut};
+
+const CONFIG_FILE_NAME: &str = "config.toml";
+
+/// Settings from `config.toml` in the XDG config dir. Every key is optional; see the
+/// README for the full schema.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub theme: Theme,
+    pub library: LibraryConfig,
+    pub audio: AudioConfig,
+    /// Action name to one key sequence or a list of them, replacing the action's defaults.
+    pub keybindings: BTreeMap<String, KeyBinding>,
+    // Where the config was read from, and where changes are written back
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LibraryConfig {
+    /// Music folders to scan and watch.
+    pub roots: Vec<PathBuf>,
+    /// File extensions picked up by a scan, without the dot.
+    pub extensions: Vec<String>,
+    /// Order of each artist's albums.
+    pub sort: AlbumSort,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AudioConfig {
+    /// Output device name, as shown in the device picker. The default device is used
+    /// when this is unset or the device isn't there.
+    pub device: Option<String>,
+    /// Audio host the device belongs to, e.g. "ALSA"; the default host when unset.
+    pub host: Option<String>,
+    /// Output sample rate, in Hz, for files the device can't play at their own rate.
+    pub sample_rate: u32,
+    /// Volume at startup, from 0.0 to 1.0.
+    pub volume: f32,
+    /// Filter used for files whose rate the device doesn't support.
+    pub resample_quality: ResampleQuality,
+    /// Send files to the device untouched when it supports their format, with no
+    /// resampling and the volume fixed at 100%.
+    pub bit_perfect: bool,
+    /// Level tracks by their ReplayGain tags, per track or per album.
+    pub replay_gain: ReplayGainMode,
+    /// Extra gain in dB for tagged tracks, from -15 to 15. Limited so the track's peak
+    /// stays below full scale.
+    pub replay_gain_preamp: f32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            theme: Theme::System,
+            library: LibraryConfig::default(),
+            audio: AudioConfig::default(),
+            keybindings: BTreeMap::new(),
+            path: None,
+        }
+    }
+}
+
+impl Default for LibraryConfig {
+    fn default() -> Self {
+        Self {
+            roots: Vec::new(),
+            extensions: ["mp3", "flac", "wav", "ogg", "m4a", "aac"]
+                .map(String::from)
+                .to_vec(),
+            sort: AlbumSort::default(),
+        }
+    }
+}
+
+impl Default for AudioConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            host: None,
+            sample_rate: 48000,
+            volume: 0.7,
+            resample_quality: ResampleQuality::default(),
+            bit_perfect: false,
+            replay_gain: ReplayGainMode::default(),
+            replay_gain_preamp: 0.0,
+        }
+    }
+}
+
+impl AudioConfig {
+    /// The configured output device, or `None` for the default one.
+    pub fn output_device(&self) -> Option<DeviceId> {
+        let name = self.device.clone()?;
+        Some(match &self.host {
+            Some(host) => DeviceId {
+                host: host.clone(),
+                name,
+            },
+            None => DeviceId::on_default_host(name),
+        })
+    }
+}
+
+impl Config {
+    /// Reads the config file, or returns the defaults if there isn't one.
+    pub fn load() -> Result<Self> {
+        let Some(path) =
+            dirs::config_dir().map(|dir| dir.join("rust_musicplayer").join(CONFIG_FILE_NAME))
+        else {
+            return Ok(Self::default());
+        };
+        if !path.exists() {
+            return Ok(Self {
+                path: Some(path),
+                ..Self::default()
+            });
+        }
+
+        let mut config = Self::from_file(&path)
+            .with_context(|| format!("Invalid config file {}", path.display()))?;
+        config.path = Some(path);
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut config: Config = toml::from_str(&text)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&mut self) -> Result<()> {
+        if !(8000..=384_000).contains(&self.audio.sample_rate) {
+            bail!(
+                "audio.sample_rate must be between 8000 and 384000 Hz, got {}",
+                self.audio.sample_rate
+            );
+        }
+        if !(0.0..=1.0).contains(&self.audio.volume) {
+            bail!(
+                "audio.volume must be between 0.0 and 1.0, got {}",
+                self.audio.volume
+            );
+        }
+        if !(-15.0..=15.0).contains(&self.audio.replay_gain_preamp) {
+            bail!(
+                "audio.replay_gain_preamp must be between -15 and 15 dB, got {}",
+                self.audio.replay_gain_preamp
+            );
+        }
+
+        for extension in &mut self.library.extensions {
+            *extension = extension.trim_start_matches('.').to_lowercase();
+            if extension.is_empty() {
+                bail!("library.extensions must not contain empty entries");
+            }
+        }
+        if self.library.extensions.is_empty() {
+            bail!("library.extensions must list at least one file extension");
+        }
+
+        Keymap::new(&self.keybindings).map_err(|err| anyhow!("keybindings: {err}"))?;
+        Ok(())
+    }
+
+    /// The `[keybindings]` table, already checked by `load`.
+    pub fn keymap(&self) -> Keymap {
+        Keymap::new(&self.keybindings).expect("keybindings are checked when loading")
+    }
+
+    pub fn save_theme(&mut self, theme: Theme) -> Result<()> {
+        self.theme = theme;
+        let name = theme.name();
+        self.edit_file(|doc| doc["theme"] = value(name))
+    }
+
+    pub fn save_sort(&mut self, sort: AlbumSort) -> Result<()> {
+        self.library.sort = sort;
+        self.edit_file(|doc| {
+            doc.entry("library").or_insert(table())["sort"] = value(sort.name());
+        })
+    }
+
+    pub fn save_roots(&mut self, roots: &[PathBuf]) -> Result<()> {
+        if self.library.roots == roots {
+            return Ok(());
+        }
+        self.library.roots = roots.to_vec();
+
+        let array: Array = roots
+            .iter()
+            .map(|root| root.to_string_lossy().to_string())
+            .collect();
+        self.edit_file(|doc| {
+            doc.entry("library").or_insert(table())["roots"] = value(array);
+        })
+    }
+
+    /// Remembers the output device, or goes back to the default one for `None`.
+    pub fn save_output_device(&mut self, device: Option<&DeviceId>) -> Result<()> {
+        self.audio.device = device.map(|device| device.name.clone());
+        self.audio.host = device.map(|device| device.host.clone());
+        self.edit_file(|doc| {
+            let audio = doc.entry("audio").or_insert(table());
+            match device {
+                Some(device) => {
+                    audio["device"] = value(&device.name);
+                    audio["host"] = value(&device.host);
+                }
+                None => {
+                    if let Some(audio) = audio.as_table_like_mut() {
+                        audio.remove("device");
+                        audio.remove("host");
+                    }
+                }
+            }
+        })
+    }
+
+    /// Changes one setting in the file, leaving the user's comments and layout alone.
+    fn edit_file(&self, edit: impl FnOnce(&mut DocumentMut)) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+
+        let text = fs::read_to_string(path).unwrap_or_default();
+        let mut doc: DocumentMut = text.parse()?;
+        edit(&mut doc);
+
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, doc.to_string())?;
+        Ok(())
+    }
+}
\ No newline at end of file
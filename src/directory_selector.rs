@@ -0,0 +1,164 @@
+use ratatui::widgets::ListState;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Which half of the directory selector the keys act on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SelectorPane {
+    Browser,
+    Roots,
+}
+
+/// One row of the filesystem browser.
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub label: String,
+    pub path: PathBuf,
+}
+
+/// Filesystem browser used to pick the music roots.
+pub struct DirectorySelector {
+    current_dir: PathBuf,
+    entries: Vec<DirEntry>,
+    pub pane: SelectorPane,
+    pub browser_state: ListState,
+    pub roots_state: ListState,
+    // Shown at the bottom of the popup, e.g. why a scan failed
+    pub message: Option<String>,
+}
+
+impl DirectorySelector {
+    pub fn new<P: AsRef<Path>>(start_dir: P) -> Self {
+        let mut selector = Self {
+            current_dir: PathBuf::new(),
+            entries: Vec::new(),
+            pane: SelectorPane::Browser,
+            browser_state: ListState::default(),
+            roots_state: ListState::default(),
+            message: None,
+        };
+        selector.open(start_dir.as_ref().to_path_buf());
+        selector
+    }
+
+    /// Starts in the first music root, or the home directory if there are none.
+    pub fn for_roots(roots: &[PathBuf]) -> Self {
+        let start_dir = roots
+            .first()
+            .cloned()
+            .or_else(dirs::home_dir)
+            .unwrap_or_else(|| PathBuf::from("/"));
+        Self::new(start_dir)
+    }
+
+    pub fn current_dir(&self) -> &Path {
+        &self.current_dir
+    }
+
+    pub fn entries(&self) -> &[DirEntry] {
+        &self.entries
+    }
+
+    /// Lists the subdirectories of `dir`, hidden ones excluded, with `..` on top.
+    fn open(&mut self, dir: PathBuf) {
+        let mut subdirs: Vec<DirEntry> = fs::read_dir(&dir)
+            .map(|read_dir| {
+                read_dir
+                    .filter_map(|e| e.ok())
+                    .map(|e| e.path())
+                    .filter(|path| path.is_dir())
+                    .filter_map(|path| {
+                        let label = path.file_name()?.to_string_lossy().to_string();
+                        (!label.starts_with('.')).then_some(DirEntry { label, path })
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+        subdirs.sort_by_key(|entry| entry.label.to_lowercase());
+
+        self.entries.clear();
+        if let Some(parent) = dir.parent() {
+            self.entries.push(DirEntry {
+                label: "..".to_string(),
+                path: parent.to_path_buf(),
+            });
+        }
+        self.entries.extend(subdirs);
+        self.current_dir = dir;
+        self.browser_state
+            .select((!self.entries.is_empty()).then_some(0));
+    }
+
+    pub fn selected_entry(&self) -> Option<&DirEntry> {
+        self.browser_state.selected().and_then(|i| self.entries.get(i))
+    }
+
+    /// The directory that "add" should use: the highlighted one, or the current one
+    /// when `..` is highlighted.
+    pub fn selected_dir(&self) -> PathBuf {
+        match self.selected_entry() {
+            Some(entry) if entry.label != ".." => entry.path.clone(),
+            _ => self.current_dir.clone(),
+        }
+    }
+
+    /// Enters the highlighted directory.
+    pub fn enter(&mut self) {
+        if let Some(entry) = self.selected_entry() {
+            let path = entry.path.clone();
+            self.open(path);
+        }
+    }
+
+    /// Goes up one level, keeping the directory we came from highlighted.
+    pub fn leave(&mut self) {
+        let Some(parent) = self.current_dir.parent().map(Path::to_path_buf) else {
+            return;
+        };
+        let previous = std::mem::replace(&mut self.current_dir, PathBuf::new());
+        self.open(parent);
+        if let Some(index) = self.entries.iter().position(|e| e.path == previous) {
+            self.browser_state.select(Some(index));
+        }
+    }
+
+    pub fn toggle_pane(&mut self) {
+        self.pane = match self.pane {
+            SelectorPane::Browser => SelectorPane::Roots,
+            SelectorPane::Roots => SelectorPane::Browser,
+        };
+    }
+
+    pub fn move_up(&mut self, root_count: usize) {
+        let (state, len) = self.focused_list(root_count);
+        if len > 0 {
+            state.select(Some(state.selected().map_or(0, |i| i.saturating_sub(1))));
+        }
+    }
+
+    pub fn move_down(&mut self, root_count: usize) {
+        let (state, len) = self.focused_list(root_count);
+        if len > 0 {
+            state.select(Some(state.selected().map_or(0, |i| (i + 1).min(len - 1))));
+        }
+    }
+
+    /// Keeps the roots cursor in range after the roots list changed.
+    pub fn clamp_roots(&mut self, root_count: usize) {
+        let selected = match (self.roots_state.selected(), root_count) {
+            (_, 0) => None,
+            (Some(i), len) => Some(i.min(len - 1)),
+            (None, _) => Some(0),
+        };
+        self.roots_state.select(selected);
+    }
+
+    fn focused_list(&mut self, root_count: usize) -> (&mut ListState, usize) {
+        match self.pane {
+            SelectorPane::Browser => (&mut self.browser_state, self.entries.len()),
+            SelectorPane::Roots => (&mut self.roots_state, root_count),
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,463 @@
+use anyhow::{anyhow, bail, Result};
+use crossterm::event::{KeyCode, KeyModifiers};
+use serde::Deserialize;
+use std::{collections::BTreeMap, fmt};
+
+/// Something the player can do from the keyboard.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Quit,
+    PlayPause,
+    Stop,
+    Up,
+    Down,
+    Left,
+    Right,
+    Top,
+    Bottom,
+    Select,
+    Search,
+    TrackDetails,
+    EditTags,
+    ToggleMark,
+    SeekBack,
+    SeekForward,
+    SeekBackLong,
+    SeekForwardLong,
+    /// Jump to a tenth of the track, 0 to 9.
+    JumpTo(u8),
+    ToggleFocus,
+    CycleSort,
+    Enqueue,
+    PlayNext,
+    RemoveFromQueue,
+    MoveQueueEntryUp,
+    MoveQueueEntryDown,
+    ClearQueue,
+    NextTrack,
+    PreviousTrack,
+    ToggleShuffle,
+    CycleRepeat,
+    VolumeUp,
+    VolumeDown,
+    Mute,
+    CycleTheme,
+    ToggleRainbow,
+    ToggleShortcuts,
+    Help,
+    DirectorySelector,
+    Playlists,
+    OutputDevices,
+    CancelScan,
+}
+
+/// An entry of the action registry.
+pub struct ActionInfo {
+    pub action: Action,
+    /// Name used in the `[keybindings]` config table.
+    pub name: &'static str,
+    pub description: &'static str,
+    default_keys: &'static [&'static str],
+}
+
+const fn info(
+    action: Action,
+    name: &'static str,
+    description: &'static str,
+    default_keys: &'static [&'static str],
+) -> ActionInfo {
+    ActionInfo {
+        action,
+        name,
+        description,
+        default_keys,
+    }
+}
+
+/// Every action, in the order the help overlay lists them.
+pub const ACTIONS: [ActionInfo; 51] = [
+    info(Action::PlayPause, "play_pause", "Play / pause", &["space"]),
+    info(Action::Stop, "stop", "Stop", &["z"]),
+    info(Action::Up, "up", "Move up", &["up", "k"]),
+    info(Action::Down, "down", "Move down", &["down", "j"]),
+    info(Action::Left, "left", "Column to the left", &["left", "h"]),
+    info(Action::Right, "right", "Column to the right", &["right", "l"]),
+    info(Action::Top, "top", "Go to the top", &["g g", "home"]),
+    info(Action::Bottom, "bottom", "Go to the bottom", &["G", "end"]),
+    info(Action::Select, "select", "Play selection", &["enter"]),
+    info(Action::Search, "search", "Search the library", &["/"]),
+    info(Action::TrackDetails, "track_details", "Track details", &["i"]),
+    info(Action::EditTags, "edit_tags", "Edit tags", &["e"]),
+    info(Action::ToggleMark, "toggle_mark", "Mark track for editing", &["v"]),
+    info(Action::ToggleFocus, "toggle_focus", "Switch library / queue", &["tab"]),
+    info(Action::CycleSort, "cycle_sort", "Sort albums by title / year / added / size", &["o"]),
+    info(Action::SeekBack, "seek_back", "Seek back 5s", &[","]),
+    info(Action::SeekForward, "seek_forward", "Seek forward 5s", &["."]),
+    info(Action::SeekBackLong, "seek_back_long", "Seek back 30s", &["<"]),
+    info(Action::SeekForwardLong, "seek_forward_long", "Seek forward 30s", &[">"]),
+    info(Action::JumpTo(0), "jump_0", "Jump to start", &["0"]),
+    info(Action::JumpTo(1), "jump_1", "Jump to 10%", &["1"]),
+    info(Action::JumpTo(2), "jump_2", "Jump to 20%", &["2"]),
+    info(Action::JumpTo(3), "jump_3", "Jump to 30%", &["3"]),
+    info(Action::JumpTo(4), "jump_4", "Jump to 40%", &["4"]),
+    info(Action::JumpTo(5), "jump_5", "Jump to 50%", &["5"]),
+    info(Action::JumpTo(6), "jump_6", "Jump to 60%", &["6"]),
+    info(Action::JumpTo(7), "jump_7", "Jump to 70%", &["7"]),
+    info(Action::JumpTo(8), "jump_8", "Jump to 80%", &["8"]),
+    info(Action::JumpTo(9), "jump_9", "Jump to 90%", &["9"]),
+    info(Action::NextTrack, "next_track", "Next in queue", &["f"]),
+    info(Action::PreviousTrack, "previous_track", "Previous in queue", &["b"]),
+    info(Action::Enqueue, "enqueue", "Add track to queue", &["a"]),
+    info(Action::PlayNext, "play_next", "Play track next", &["n"]),
+    info(Action::RemoveFromQueue, "remove_from_queue", "Remove from queue", &["x", "delete"]),
+    info(Action::MoveQueueEntryUp, "move_queue_entry_up", "Move queue entry up", &["["]),
+    info(Action::MoveQueueEntryDown, "move_queue_entry_down", "Move queue entry down", &["]"]),
+    info(Action::ClearQueue, "clear_queue", "Clear queue", &["c"]),
+    info(Action::ToggleShuffle, "toggle_shuffle", "Shuffle", &["S"]),
+    info(Action::CycleRepeat, "cycle_repeat", "Repeat off / track / album / all", &["R"]),
+    info(Action::VolumeUp, "volume_up", "Volume up", &["+", "="]),
+    info(Action::VolumeDown, "volume_down", "Volume down", &["-"]),
+    info(Action::Mute, "mute", "Mute", &["m"]),
+    info(Action::CycleTheme, "cycle_theme", "Next theme", &["t"]),
+    info(Action::ToggleRainbow, "toggle_rainbow", "Rainbow mode", &["r"]),
+    info(Action::ToggleShortcuts, "toggle_shortcuts", "Show / hide shortcuts", &["s"]),
+    info(Action::Help, "help", "This help", &["?", "f1"]),
+    info(Action::DirectorySelector, "directory_selector", "Music folders", &["d"]),
+    info(Action::Playlists, "playlists", "Playlists", &["p"]),
+    info(Action::OutputDevices, "output_devices", "Output devices", &["O"]),
+    info(Action::CancelScan, "cancel_scan", "Cancel scan", &["esc"]),
+    info(Action::Quit, "quit", "Quit", &["ctrl+q"]),
+];
+
+impl Action {
+    pub fn info(self) -> &'static ActionInfo {
+        ACTIONS
+            .iter()
+            .find(|info| info.action == self)
+            .expect("every action is in the registry")
+    }
+
+    fn from_name(name: &str) -> Result<Self> {
+        match ACTIONS.iter().find(|info| info.name == name) {
+            Some(info) => Ok(info.action),
+            None => {
+                let names: Vec<&str> = ACTIONS.iter().map(|info| info.name).collect();
+                bail!("unknown action `{name}`, expected one of: {}", names.join(", "))
+            }
+        }
+    }
+}
+
+/// A single key press, with shift folded into letters (see [`Key::new`]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub code: KeyCode,
+    pub modifiers: KeyModifiers,
+}
+
+impl Key {
+    /// Shift is part of the character for letter keys, so `shift+s` and `S` are the same key.
+    pub fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        match code {
+            KeyCode::Char(c) if modifiers.contains(KeyModifiers::SHIFT) => Self {
+                code: KeyCode::Char(c.to_uppercase().next().unwrap_or(c)),
+                modifiers: modifiers - KeyModifiers::SHIFT,
+            },
+            _ => Self { code, modifiers },
+        }
+    }
+
+    /// Parses a key as written in the config file, e.g. `ctrl+q`, `space` or `G`.
+    pub fn parse(spec: &str) -> Result<Self> {
+        let mut modifiers = KeyModifiers::NONE;
+        let mut rest = spec.trim();
+
+        // "+" on its own is a key, so only split on a "+" that has something after it
+        while let Some((modifier, key)) = rest.split_once('+').filter(|(_, key)| !key.is_empty())
+        {
+            modifiers |= match modifier.to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                other => bail!("unknown modifier `{other}` in key `{spec}`"),
+            };
+            rest = key;
+        }
+
+        let code = match rest.to_lowercase().as_str() {
+            "space" => KeyCode::Char(' '),
+            "enter" | "return" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "esc" | "escape" => KeyCode::Esc,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            name if name.len() > 1 && name.starts_with('f') => match name[1..].parse() {
+                Ok(n @ 1..=12) => KeyCode::F(n),
+                _ => bail!("unknown key `{rest}` in `{spec}`"),
+            },
+            _ => {
+                let mut chars = rest.chars();
+                match (chars.next(), chars.next()) {
+                    (Some(c), None) => KeyCode::Char(c),
+                    _ => bail!("unknown key `{rest}` in `{spec}`"),
+                }
+            }
+        };
+
+        Ok(Self::new(code, modifiers))
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.modifiers.contains(KeyModifiers::CONTROL) {
+            write!(f, "CTRL+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::ALT) {
+            write!(f, "ALT+")?;
+        }
+        if self.modifiers.contains(KeyModifiers::SHIFT) {
+            write!(f, "SHIFT+")?;
+        }
+        match self.code {
+            KeyCode::Char(' ') => write!(f, "SPACE"),
+            KeyCode::Char(c) if c.is_uppercase() => write!(f, "SHIFT+{c}"),
+            KeyCode::Char(c) => write!(f, "{}", c.to_uppercase()),
+            KeyCode::Up => write!(f, "↑"),
+            KeyCode::Down => write!(f, "↓"),
+            KeyCode::Left => write!(f, "←"),
+            KeyCode::Right => write!(f, "→"),
+            KeyCode::F(n) => write!(f, "F{n}"),
+            KeyCode::Esc => write!(f, "ESC"),
+            KeyCode::PageUp => write!(f, "PGUP"),
+            KeyCode::PageDown => write!(f, "PGDN"),
+            other => write!(f, "{}", format!("{other:?}").to_uppercase()),
+        }
+    }
+}
+
+/// Keys pressed one after the other to trigger an action, like `g g`.
+pub type KeySequence = Vec<Key>;
+
+/// Parses a space separated key sequence, e.g. `g g` or `ctrl+w j`.
+pub fn parse_sequence(spec: &str) -> Result<KeySequence> {
+    let keys = spec
+        .split_whitespace()
+        .map(Key::parse)
+        .collect::<Result<KeySequence>>()?;
+    if keys.is_empty() {
+        bail!("empty key");
+    }
+    Ok(keys)
+}
+
+pub fn format_sequence(keys: &[Key]) -> String {
+    keys.iter()
+        .map(Key::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turns key presses into actions. Built from the defaults in [`ACTIONS`], with the
+/// `[keybindings]` config table replacing the keys of the actions it names.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    // In the order they were configured, so labels show the first key of each action
+    bindings: Vec<(KeySequence, Action)>,
+    // Keys of an unfinished sequence
+    pending: KeySequence,
+}
+
+impl Keymap {
+    pub fn new(config: &BTreeMap<String, KeyBinding>) -> Result<Self> {
+        let mut bindings: Vec<(KeySequence, Action)> = Vec::new();
+        let mut overridden = Vec::new();
+        // As written in the config, for error messages
+        let mut specs: Vec<&str> = Vec::new();
+
+        for (name, binding) in config {
+            let action = Action::from_name(name)?;
+            overridden.push(action);
+            for spec in binding.keys() {
+                let keys = parse_sequence(spec).map_err(|err| anyhow!("{name}: {err}"))?;
+                if let Some(index) = find_conflict(&bindings, &keys) {
+                    bail!(
+                        "`{spec}` for {name} clashes with `{}` for {}",
+                        specs[index],
+                        bindings[index].1.info().name
+                    );
+                }
+                bindings.push((keys, action));
+                specs.push(spec);
+            }
+        }
+
+        // Default keys fill in the rest, unless the config took them for something else
+        for info in &ACTIONS {
+            if overridden.contains(&info.action) {
+                continue;
+            }
+            for spec in info.default_keys {
+                let keys = parse_sequence(spec).expect("default keys parse");
+                if find_conflict(&bindings, &keys).is_none() {
+                    bindings.push((keys, info.action));
+                }
+            }
+        }
+
+        Ok(Self {
+            bindings,
+            pending: Vec::new(),
+        })
+    }
+
+    /// Feeds a key press, returning the action once a whole sequence has been typed.
+    pub fn handle(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        let key = Key::new(code, modifiers);
+        self.pending.push(key);
+
+        if let Some((_, action)) = self.bindings.iter().find(|(keys, _)| *keys == self.pending) {
+            self.pending.clear();
+            return Some(*action);
+        }
+        if self
+            .bindings
+            .iter()
+            .any(|(keys, _)| keys.starts_with(&self.pending))
+        {
+            return None;
+        }
+
+        // A key that doesn't continue the sequence starts over on its own
+        let retry = self.pending.len() > 1;
+        self.pending.clear();
+        if retry {
+            self.handle(key.code, key.modifiers)
+        } else {
+            None
+        }
+    }
+
+    /// The keys typed so far of an unfinished sequence.
+    pub fn pending(&self) -> &[Key] {
+        &self.pending
+    }
+
+    /// Every key sequence bound to `action`.
+    pub fn keys_for(&self, action: Action) -> impl Iterator<Item = &KeySequence> {
+        self.bindings
+            .iter()
+            .filter(move |(_, bound)| *bound == action)
+            .map(|(keys, _)| keys)
+    }
+
+    /// Label of the first key bound to `action`, if any.
+    pub fn label(&self, action: Action) -> Option<String> {
+        self.keys_for(action).next().map(|keys| format_sequence(keys))
+    }
+}
+
+/// The index of a binding that is the same as, or a prefix of, `keys`, or that `keys`
+/// is a prefix of.
+fn find_conflict(bindings: &[(KeySequence, Action)], keys: &[Key]) -> Option<usize> {
+    bindings
+        .iter()
+        .position(|(bound, _)| bound.starts_with(keys) || keys.starts_with(bound))
+}
+
+/// One key or a list of keys for an action in the config file.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum KeyBinding {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeyBinding {
+    pub fn keys(&self) -> &[String] {
+        match self {
+            KeyBinding::One(key) => std::slice::from_ref(key),
+            KeyBinding::Many(keys) => keys,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keymap(bindings: &[(&str, &str)]) -> Result<Keymap> {
+        let config = bindings
+            .iter()
+            .map(|(name, key)| (name.to_string(), KeyBinding::One(key.to_string())))
+            .collect();
+        Keymap::new(&config)
+    }
+
+    fn press(keymap: &mut Keymap, c: char) -> Option<Action> {
+        keymap.handle(KeyCode::Char(c), KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn parses_modifiers_and_named_keys() {
+        let ctrl_q = Key::parse("ctrl+q").unwrap();
+        assert_eq!(ctrl_q, Key::new(KeyCode::Char('q'), KeyModifiers::CONTROL));
+        assert_eq!(Key::parse("+").unwrap().code, KeyCode::Char('+'));
+        assert_eq!(Key::parse("shift+s").unwrap(), Key::parse("S").unwrap());
+        assert_eq!(Key::parse("F1").unwrap().code, KeyCode::F(1));
+        assert!(Key::parse("hyper+x").is_err());
+        assert!(Key::parse("f13").is_err());
+        assert!(parse_sequence("  ").is_err());
+    }
+
+    #[test]
+    fn sequences_wait_for_their_last_key() {
+        let mut keymap = keymap(&[]).unwrap();
+        assert_eq!(press(&mut keymap, 'g'), None);
+        assert_eq!(keymap.pending().len(), 1);
+        assert_eq!(press(&mut keymap, 'g'), Some(Action::Top));
+        assert!(keymap.pending().is_empty());
+    }
+
+    #[test]
+    fn a_key_that_breaks_a_sequence_counts_on_its_own() {
+        let mut keymap = keymap(&[]).unwrap();
+        press(&mut keymap, 'g');
+        assert_eq!(press(&mut keymap, 'j'), Some(Action::Down));
+        assert!(keymap.pending().is_empty());
+    }
+
+    #[test]
+    fn config_replaces_the_default_keys_of_an_action() {
+        let mut keymap = keymap(&[("quit", "q")]).unwrap();
+        assert_eq!(press(&mut keymap, 'q'), Some(Action::Quit));
+        assert_eq!(keymap.handle(KeyCode::Char('q'), KeyModifiers::CONTROL), None);
+        assert_eq!(keymap.label(Action::Quit).as_deref(), Some("Q"));
+    }
+
+    #[test]
+    fn config_takes_a_default_key_from_another_action() {
+        let mut keymap = keymap(&[("mute", "k")]).unwrap();
+        assert_eq!(press(&mut keymap, 'k'), Some(Action::Mute));
+        let up: Vec<_> = keymap.keys_for(Action::Up).map(|keys| format_sequence(keys)).collect();
+        assert_eq!(up, ["↑"]);
+    }
+
+    #[test]
+    fn clashing_config_keys_are_rejected() {
+        let err = keymap(&[("bottom", "g"), ("top", "g g")]).unwrap_err();
+        assert!(err.to_string().contains("clashes"), "{err}");
+        assert!(keymap(&[("help", "x"), ("mute", "x")]).is_err());
+        assert!(keymap(&[("no_such_action", "x")]).is_err());
+    }
+}
@@ -1,11 +1,11 @@
-use anyhow::Result;
-use crate::metadata::TrackMetadata;
+use crate::{cache::LibraryCache, metadata::TrackMetadata};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::HashMap,
+    cmp::{Ordering, Reverse},
+    collections::{HashMap, HashSet},
+    ops::Range,
     path::{Path, PathBuf},
 };
-use walkdir::WalkDir;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Album {
@@ -23,10 +23,30 @@ impl Album {
         }
     }
 
-    pub fn add_track(&mut self, track: TrackMetadata) {
-        self.tracks.push(track);
-        // Sort by track number
-        self.tracks.sort_by_key(|t| t.track_number.unwrap_or(0));
+    /// Disc by disc, then by track number. Untagged tracks go last, by file name.
+    fn sort_tracks(&mut self) {
+        self.tracks.sort_by(|a, b| {
+            let key = |t: &TrackMetadata| {
+                (t.disc_number.unwrap_or(1), t.track_number.unwrap_or(u32::MAX))
+            };
+            key(a).cmp(&key(b)).then_with(|| a.file_path.cmp(&b.file_path))
+        });
+    }
+
+    /// The earliest year on any of the tracks, i.e. the original release.
+    pub fn year(&self) -> Option<u32> {
+        self.tracks.iter().filter_map(|t| t.year).min()
+    }
+
+    /// When the first track of the album showed up in the library.
+    pub fn date_added(&self) -> Option<u64> {
+        self.tracks.iter().filter_map(|t| t.date_added).min()
+    }
+
+    pub fn is_multi_disc(&self) -> bool {
+        self.tracks
+            .iter()
+            .any(|t| t.disc_number.unwrap_or(1) != self.tracks[0].disc_number.unwrap_or(1))
     }
 
     pub fn display_name(&self) -> String {
@@ -38,66 +58,282 @@ impl Album {
     }
 }
 
+/// An album artist and where their albums are in `MusicLibrary::albums`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Artist {
+    pub name: String,
+    pub albums: Range<usize>,
+}
+
+/// How the albums of an artist are ordered.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlbumSort {
+    #[default]
+    Title,
+    Year,
+    /// Newest first.
+    DateAdded,
+    /// Biggest first.
+    TrackCount,
+}
+
+impl AlbumSort {
+    /// The name used in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            AlbumSort::Title => "title",
+            AlbumSort::Year => "year",
+            AlbumSort::DateAdded => "date_added",
+            AlbumSort::TrackCount => "track_count",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            AlbumSort::Title => "title",
+            AlbumSort::Year => "year",
+            AlbumSort::DateAdded => "date added",
+            AlbumSort::TrackCount => "track count",
+        }
+    }
+
+    pub fn next(self) -> Self {
+        match self {
+            AlbumSort::Title => AlbumSort::Year,
+            AlbumSort::Year => AlbumSort::DateAdded,
+            AlbumSort::DateAdded => AlbumSort::TrackCount,
+            AlbumSort::TrackCount => AlbumSort::Title,
+        }
+    }
+
+    fn compare(self, a: &Album, b: &Album) -> Ordering {
+        match self {
+            AlbumSort::Title => Ordering::Equal,
+            // Albums without a year after the dated ones
+            AlbumSort::Year => a
+                .year()
+                .unwrap_or(u32::MAX)
+                .cmp(&b.year().unwrap_or(u32::MAX)),
+            AlbumSort::DateAdded => Reverse(a.date_added()).cmp(&Reverse(b.date_added())),
+            AlbumSort::TrackCount => Reverse(a.tracks.len()).cmp(&Reverse(b.tracks.len())),
+        }
+        .then_with(|| a.name.to_lowercase().cmp(&b.name.to_lowercase()))
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MusicLibrary {
+    /// Grouped by artist, in artist order, and sorted by `sort` within each artist.
     pub albums: Vec<Album>,
+    pub artists: Vec<Artist>,
     pub all_tracks: Vec<TrackMetadata>,
+    pub current_artist_index: usize,
     pub current_album_index: usize,
     pub current_track_index: usize,
-    pub music_directory: Option<PathBuf>,
+    pub music_roots: Vec<PathBuf>,
+    pub sort: AlbumSort,
 }
 
 impl MusicLibrary {
     pub fn new() -> Self {
         Self {
             albums: Vec::new(),
+            artists: Vec::new(),
             all_tracks: Vec::new(),
+            current_artist_index: 0,
             current_album_index: 0,
             current_track_index: 0,
-            music_directory: None,
+            music_roots: Vec::new(),
+            sort: AlbumSort::default(),
         }
     }
 
-    pub fn scan_directory<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+    /// Builds the library from the cache without touching any music files.
+    pub fn load_cached(cache: &LibraryCache, music_roots: Vec<PathBuf>) -> Self {
+        let mut library = Self::new();
+        library.music_roots = music_roots;
+        library.all_tracks = cache.tracks().cloned().collect();
+        library.all_tracks.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        library.rebuild_albums();
+        library
+    }
+
+    /// Adds a directory to scan. Returns false if it is already covered by a root.
+    pub fn add_root<P: AsRef<Path>>(&mut self, path: P) -> bool {
         let path = path.as_ref();
-        self.music_directory = Some(path.to_path_buf());
-        self.albums.clear();
-        self.all_tracks.clear();
-
-        let mut album_map: HashMap<String, Album> = HashMap::new();
-        let supported_extensions = ["mp3", "flac", "wav", "ogg", "m4a", "aac"];
-
-        for entry in WalkDir::new(path)
-            .into_iter()
-            .filter_map(|e| e.ok())
-            .filter(|e| e.file_type().is_file())
-        {
-            let file_path = entry.path();
-            if let Some(extension) = file_path.extension().and_then(|s| s.to_str()) {
-                if supported_extensions.contains(&extension.to_lowercase().as_str()) {
-                    if let Ok(metadata) = TrackMetadata::from_file(file_path) {
-                        self.all_tracks.push(metadata.clone());
-
-                        let album_key = format!("{} - {}", metadata.artist, metadata.album);
-                        let album = album_map
-                            .entry(album_key.clone())
-                            .or_insert_with(|| Album::new(metadata.album.clone(), metadata.artist.clone()));
-
-                        album.add_track(metadata);
-                    }
+        if self.music_roots.iter().any(|root| path.starts_with(root)) {
+            return false;
+        }
+        // A new parent replaces any roots nested inside it
+        self.music_roots.retain(|root| !root.starts_with(path));
+        self.music_roots.push(path.to_path_buf());
+        self.music_roots.sort();
+        true
+    }
+
+    pub fn remove_root(&mut self, index: usize) {
+        if index < self.music_roots.len() {
+            self.music_roots.remove(index);
+        }
+    }
+
+    /// Adds tracks from a scan, replacing any already in the library with the same path.
+    pub fn merge_tracks(&mut self, tracks: Vec<TrackMetadata>) {
+        if tracks.is_empty() {
+            return;
+        }
+
+        let mut positions: HashMap<String, usize> = self
+            .all_tracks
+            .iter()
+            .enumerate()
+            .map(|(i, track)| (track.file_path.clone(), i))
+            .collect();
+        for track in tracks {
+            match positions.get(&track.file_path) {
+                Some(&i) => self.all_tracks[i] = track,
+                None => {
+                    positions.insert(track.file_path.clone(), self.all_tracks.len());
+                    self.all_tracks.push(track);
                 }
             }
         }
+        self.rebuild_albums();
+    }
+
+    /// Drops every track at or below any of `paths`.
+    pub fn remove_paths(&mut self, paths: &[PathBuf]) {
+        let before = self.all_tracks.len();
+        self.all_tracks.retain(|track| {
+            !paths
+                .iter()
+                .any(|path| Path::new(&track.file_path).starts_with(path))
+        });
+        if self.all_tracks.len() != before {
+            self.rebuild_albums();
+        }
+    }
+
+    /// Drops every track whose path is not in `paths`.
+    pub fn retain_paths(&mut self, paths: &HashSet<String>) {
+        let before = self.all_tracks.len();
+        self.all_tracks.retain(|track| paths.contains(&track.file_path));
+        if self.all_tracks.len() != before {
+            self.rebuild_albums();
+        }
+    }
+
+    /// Regroups `all_tracks` into artists and albums, keeping the selection where it was.
+    fn rebuild_albums(&mut self) {
+        let selected_album = self
+            .get_current_album()
+            .map(|album| (album.artist.clone(), album.name.clone()));
+        let selected_track = self.get_current_track().map(|track| track.file_path.clone());
+
+        // Filed under the album artist, so compilations stay in one piece
+        let mut album_map: HashMap<(&str, &str), Album> = HashMap::new();
+        for metadata in &self.all_tracks {
+            let artist = metadata.album_artist();
+            album_map
+                .entry((artist, &metadata.album))
+                .or_insert_with(|| Album::new(metadata.album.clone(), artist.to_string()))
+                .tracks
+                .push(metadata.clone());
+        }
+
+        let mut albums: Vec<Album> = album_map.into_values().collect();
+        for album in &mut albums {
+            album.sort_tracks();
+        }
+        let sort = self.sort;
+        albums.sort_by(|a, b| {
+            a.artist
+                .to_lowercase()
+                .cmp(&b.artist.to_lowercase())
+                .then_with(|| a.artist.cmp(&b.artist))
+                .then_with(|| sort.compare(a, b))
+        });
+        self.albums = albums;
+
+        self.artists.clear();
+        for (i, album) in self.albums.iter().enumerate() {
+            match self.artists.last_mut() {
+                Some(artist) if artist.name == album.artist => artist.albums.end = i + 1,
+                _ => self.artists.push(Artist {
+                    name: album.artist.clone(),
+                    albums: i..i + 1,
+                }),
+            }
+        }
+
+        // Put the cursors back on the same album and track, wherever they ended up
+        let album_index = selected_album.and_then(|(artist, name)| {
+            self.albums
+                .iter()
+                .position(|album| album.artist == artist && album.name == name)
+        });
+        self.current_album_index = album_index.unwrap_or(0);
+        self.current_artist_index = self.artist_of(self.current_album_index);
+        self.current_track_index = self
+            .get_current_album()
+            .zip(selected_track)
+            .filter(|_| album_index.is_some())
+            .and_then(|(album, path)| album.tracks.iter().position(|t| t.file_path == path))
+            .unwrap_or(0);
+    }
+
+    /// Changes the album order.
+    pub fn set_sort(&mut self, sort: AlbumSort) {
+        self.sort = sort;
+        self.rebuild_albums();
+    }
+
+    fn artist_of(&self, album_index: usize) -> usize {
+        self.artists
+            .iter()
+            .position(|artist| artist.albums.contains(&album_index))
+            .unwrap_or(0)
+    }
+
+    pub fn get_current_artist(&self) -> Option<&Artist> {
+        self.artists.get(self.current_artist_index)
+    }
 
-        // Convert to sorted vector
-        self.albums = album_map.into_values().collect();
-        self.albums.sort_by(|a, b| a.display_name().cmp(&b.display_name()));
+    /// The albums of the selected artist.
+    pub fn artist_albums(&self) -> &[Album] {
+        self.get_current_artist()
+            .map(|artist| &self.albums[artist.albums.clone()])
+            .unwrap_or_default()
+    }
+
+    /// The tracks of every album of the selected artist, album by album.
+    pub fn artist_tracks(&self) -> Vec<TrackMetadata> {
+        self.artist_albums()
+            .iter()
+            .flat_map(|album| album.tracks.iter().cloned())
+            .collect()
+    }
+
+    pub fn next_artist(&mut self) {
+        if !self.artists.is_empty() {
+            self.set_artist((self.current_artist_index + 1) % self.artists.len());
+        }
+    }
 
-        // Reset indices
-        self.current_album_index = 0;
-        self.current_track_index = 0;
+    pub fn prev_artist(&mut self) {
+        if !self.artists.is_empty() {
+            let last = self.artists.len() - 1;
+            self.set_artist(self.current_artist_index.checked_sub(1).unwrap_or(last));
+        }
+    }
 
-        Ok(())
+    pub fn set_artist(&mut self, index: usize) {
+        if let Some(artist) = self.artists.get(index) {
+            self.current_artist_index = index;
+            self.current_album_index = artist.albums.start;
+            self.current_track_index = 0;
+        }
     }
 
     pub fn get_current_album(&self) -> Option<&Album> {
@@ -109,21 +345,22 @@ impl MusicLibrary {
             .and_then(|album| album.tracks.get(self.current_track_index))
     }
 
-    pub fn get_current_track_path(&self) -> Option<String> {
-        self.get_current_track().map(|track| track.file_path.clone())
-    }
-
+    /// Moves to the next album of the selected artist, wrapping around.
     pub fn next_album(&mut self) {
-        if !self.albums.is_empty() {
-            self.current_album_index = (self.current_album_index + 1) % self.albums.len();
+        if let Some(albums) = self.get_current_artist().map(|artist| artist.albums.clone()) {
+            self.current_album_index = if self.current_album_index + 1 >= albums.end {
+                albums.start
+            } else {
+                self.current_album_index + 1
+            };
             self.current_track_index = 0;
         }
     }
 
     pub fn prev_album(&mut self) {
-        if !self.albums.is_empty() {
-            self.current_album_index = if self.current_album_index == 0 {
-                self.albums.len() - 1
+        if let Some(albums) = self.get_current_artist().map(|artist| artist.albums.clone()) {
+            self.current_album_index = if self.current_album_index <= albums.start {
+                albums.end - 1
             } else {
                 self.current_album_index - 1
             };
@@ -153,6 +390,7 @@ impl MusicLibrary {
 
     pub fn set_album(&mut self, index: usize) {
         if index < self.albums.len() {
+            self.current_artist_index = self.artist_of(index);
             self.current_album_index = index;
             self.current_track_index = 0;
         }
@@ -166,16 +404,16 @@ impl MusicLibrary {
         }
     }
 
-    pub fn get_album_tracks(&self, album_index: usize) -> Option<&Vec<TrackMetadata>> {
-        self.albums.get(album_index).map(|album| &album.tracks)
-    }
-
-    pub fn is_empty(&self) -> bool {
-        self.albums.is_empty()
-    }
-
-    pub fn album_count(&self) -> usize {
-        self.albums.len()
+    /// Moves the browse cursors to the track at `path`.
+    pub fn select_path(&mut self, path: &str) -> bool {
+        for (album_index, album) in self.albums.iter().enumerate() {
+            if let Some(track_index) = album.tracks.iter().position(|t| t.file_path == path) {
+                self.set_album(album_index);
+                self.set_track(track_index);
+                return true;
+            }
+        }
+        false
     }
 
     pub fn track_count(&self) -> usize {
@@ -0,0 +1,354 @@
+use anyhow::Result;
+use std::{collections::VecDeque, f64::consts::PI, fs::File, path::Path};
+use symphonia::{
+    core::{
+        audio::{Channels, SampleBuffer},
+        codecs::{DecoderOptions, CODEC_TYPE_NULL},
+        errors::Error as SymphoniaError,
+        formats::FormatOptions,
+        io::MediaSourceStream,
+        meta::MetadataOptions,
+        probe::Hint,
+    },
+    default::{get_codecs, get_probe},
+};
+
+// Gating blocks are 400 ms long and start every 100 ms
+const STEP_SECS: f64 = 0.1;
+const STEPS_PER_BLOCK: usize = 4;
+// Blocks quieter than this are silence and never count
+const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+// Blocks this far below the ungated loudness are left out, e.g. fade-outs and pauses
+const RELATIVE_GATE_LU: f64 = -10.0;
+
+/// Loudness of a mean square value, per BS.1770.
+fn lufs(mean_square: f64) -> f64 {
+    -0.691 + 10.0 * mean_square.log10()
+}
+
+/// Integrated loudness of a run of gating blocks, in LUFS, or `None` if every block is
+/// below the absolute gate.
+fn gated_loudness<'a>(blocks: impl Iterator<Item = &'a f64> + Clone) -> Option<f64> {
+    let mean = |threshold: f64| {
+        let (sum, count) = blocks
+            .clone()
+            .filter(|&&block| lufs(block) > threshold)
+            .fold((0.0, 0), |(sum, count), block| (sum + block, count + 1));
+        (count > 0).then(|| sum / count as f64)
+    };
+
+    let ungated = mean(ABSOLUTE_GATE_LUFS)?;
+    let threshold = (lufs(ungated) + RELATIVE_GATE_LU).max(ABSOLUTE_GATE_LUFS);
+    mean(threshold).map(lufs)
+}
+
+/// Second-order IIR section, direct form I.
+struct Biquad {
+    b: [f64; 3],
+    // a0 is normalized to 1
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Self {
+            b,
+            a,
+            x: [0.0; 2],
+            y: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+}
+
+/// The K-weighting of BS.1770: a high shelf for the effect of the head, then a high-pass
+/// for the ear's weak response to low frequencies. The standard only gives coefficients
+/// for 48 kHz, so they are derived from the analog prototypes for other rates.
+struct KWeighting {
+    shelf: Biquad,
+    high_pass: Biquad,
+}
+
+impl KWeighting {
+    fn new(sample_rate: u32) -> Self {
+        let rate = sample_rate as f64;
+
+        let (f0, gain_db, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+        let k = (PI * f0 / rate).tan();
+        let vh = 10f64.powf(gain_db / 20.0);
+        let vb = vh.powf(0.4996667741545416);
+        let a0 = 1.0 + k / q + k * k;
+        let shelf = Biquad::new(
+            [
+                (vh + vb * k / q + k * k) / a0,
+                2.0 * (k * k - vh) / a0,
+                (vh - vb * k / q + k * k) / a0,
+            ],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        let (f0, q) = (38.13547087602444, 0.5003270373238773);
+        let k = (PI * f0 / rate).tan();
+        let a0 = 1.0 + k / q + k * k;
+        let high_pass = Biquad::new(
+            [1.0, -2.0, 1.0],
+            [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        );
+
+        Self { shelf, high_pass }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        self.high_pass.process(self.shelf.process(x))
+    }
+}
+
+/// How much each channel counts: surround channels a bit more, the LFE not at all.
+fn channel_weights(channels: Channels) -> Vec<f64> {
+    channels
+        .iter()
+        .map(|channel| {
+            if channel == Channels::LFE1 || channel == Channels::LFE2 {
+                0.0
+            } else if channel == Channels::SIDE_LEFT
+                || channel == Channels::SIDE_RIGHT
+                || channel == Channels::REAR_LEFT
+                || channel == Channels::REAR_RIGHT
+            {
+                1.41
+            } else {
+                1.0
+            }
+        })
+        .collect()
+}
+
+/// Loudness measured over one track.
+#[derive(Debug, Clone)]
+pub struct TrackLoudness {
+    // Weighted mean square of every gating block, kept so an album can be gated as a whole
+    blocks: Vec<f64>,
+    /// Highest absolute sample value, 1.0 being full scale.
+    pub peak: f32,
+}
+
+impl TrackLoudness {
+    /// Integrated loudness in LUFS, or `None` for silence.
+    pub fn integrated(&self) -> Option<f64> {
+        gated_loudness(self.blocks.iter())
+    }
+}
+
+/// Integrated loudness of tracks played one after the other, e.g. an album, with the
+/// gates applied across all of them as if they were one programme.
+pub fn combined_loudness(tracks: &[TrackLoudness]) -> Option<f64> {
+    gated_loudness(tracks.iter().flat_map(|track| &track.blocks))
+}
+
+/// Measures interleaved audio as it is fed in.
+pub struct LoudnessMeter {
+    filters: Vec<KWeighting>,
+    weights: Vec<f64>,
+    step_frames: usize,
+    // Weighted sum of squares in the 100 ms step being filled, and its length so far
+    step_energy: f64,
+    step_filled: usize,
+    // The last few completed steps, which make up the current gating block
+    steps: VecDeque<f64>,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: Channels) -> Self {
+        let weights = channel_weights(channels);
+        Self {
+            filters: weights.iter().map(|_| KWeighting::new(sample_rate)).collect(),
+            weights,
+            step_frames: ((sample_rate as f64 * STEP_SECS).round() as usize).max(1),
+            step_energy: 0.0,
+            step_filled: 0,
+            steps: VecDeque::with_capacity(STEPS_PER_BLOCK),
+            blocks: Vec::new(),
+            peak: 0.0,
+        }
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        for frame in samples.chunks_exact(self.filters.len()) {
+            for ((filter, weight), &sample) in self.filters.iter_mut().zip(&self.weights).zip(frame)
+            {
+                self.peak = self.peak.max(sample.abs());
+                let filtered = filter.process(sample as f64);
+                self.step_energy += weight * filtered * filtered;
+            }
+
+            self.step_filled += 1;
+            if self.step_filled == self.step_frames {
+                self.end_step();
+            }
+        }
+    }
+
+    fn end_step(&mut self) {
+        if self.steps.len() == STEPS_PER_BLOCK {
+            self.steps.pop_front();
+        }
+        self.steps.push_back(self.step_energy);
+        self.step_energy = 0.0;
+        self.step_filled = 0;
+
+        if self.steps.len() == STEPS_PER_BLOCK {
+            let block_frames = (self.step_frames * STEPS_PER_BLOCK) as f64;
+            self.blocks.push(self.steps.iter().sum::<f64>() / block_frames);
+        }
+    }
+
+    /// The measurement so far. Audio shorter than one gating block has no loudness.
+    pub fn finish(self) -> TrackLoudness {
+        TrackLoudness {
+            blocks: self.blocks,
+            peak: self.peak,
+        }
+    }
+}
+
+/// Decodes the whole file and measures it.
+pub fn measure_file(path: &Path) -> Result<TrackLoudness> {
+    let file = File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(file), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = path.extension().and_then(|ext| ext.to_str()) {
+        hint.with_extension(extension);
+    }
+    let probed = get_probe().format(
+        &hint,
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format = probed.format;
+
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or_else(|| anyhow::anyhow!("No supported audio tracks"))?;
+    let track_id = track.id;
+    let mut decoder = get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut meter: Option<LoudnessMeter> = None;
+    let mut sample_buf: Option<SampleBuffer<f32>> = None;
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::ResetRequired) => {
+                decoder.reset();
+                continue;
+            }
+            // End of stream
+            Err(SymphoniaError::IoError(_)) => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let audio_buf = match decoder.decode(&packet) {
+            Ok(audio_buf) => audio_buf,
+            // A corrupt packet is skipped, as in playback
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(err) => return Err(err.into()),
+        };
+        let spec = *audio_buf.spec();
+        let buf = match &mut sample_buf {
+            Some(buf) if buf.capacity() >= audio_buf.capacity() * spec.channels.count() => buf,
+            _ => sample_buf.insert(SampleBuffer::new(audio_buf.capacity() as u64, spec)),
+        };
+        buf.copy_interleaved_ref(audio_buf);
+        meter
+            .get_or_insert_with(|| LoudnessMeter::new(spec.rate, spec.channels))
+            .push(buf.samples());
+    }
+
+    meter
+        .map(LoudnessMeter::finish)
+        .ok_or_else(|| anyhow::anyhow!("No audio could be decoded"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+
+    /// Interleaved stereo 1 kHz sine at `dbfs`, the same on both channels.
+    fn sine(rate: u32, seconds: f64, dbfs: f64) -> Vec<f32> {
+        let amplitude = 10f64.powf(dbfs / 20.0);
+        let frames = (rate as f64 * seconds) as usize;
+        (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (2.0 * PI * 1000.0 * i as f64 / rate as f64).sin();
+                [sample as f32; 2]
+            })
+            .collect()
+    }
+
+    fn measure(rate: u32, parts: &[(f64, f64)]) -> TrackLoudness {
+        let mut meter = LoudnessMeter::new(rate, STEREO);
+        for &(seconds, dbfs) in parts {
+            meter.push(&sine(rate, seconds, dbfs));
+        }
+        meter.finish()
+    }
+
+    fn assert_lufs(actual: Option<f64>, expected: f64) {
+        let actual = actual.expect("a loudness");
+        assert!((actual - expected).abs() < 0.1, "{actual} LUFS, expected {expected}");
+    }
+
+    #[test]
+    fn reference_tone_measures_minus_23_lufs() {
+        // EBU Tech 3341, case 1
+        for rate in [44100, 48000, 96000] {
+            let track = measure(rate, &[(20.0, -23.0)]);
+            assert_lufs(track.integrated(), -23.0);
+            assert!((track.peak - 10f32.powf(-23.0 / 20.0)).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn relative_gate_leaves_out_quiet_passages() {
+        // EBU Tech 3341, case 3
+        let track = measure(48000, &[(10.0, -36.0), (60.0, -23.0), (10.0, -36.0)]);
+        assert_lufs(track.integrated(), -23.0);
+    }
+
+    #[test]
+    fn silence_and_short_audio_have_no_loudness() {
+        assert_eq!(measure(48000, &[(5.0, -90.0)]).integrated(), None);
+        assert_eq!(measure(48000, &[(0.3, -10.0)]).integrated(), None);
+    }
+
+    #[test]
+    fn album_loudness_gates_the_tracks_together() {
+        let loud = measure(48000, &[(20.0, -20.0)]);
+        let quiet = measure(48000, &[(20.0, -26.0)]);
+        // Both are within 10 LU of the whole, so it is the mean of their energies
+        let expected = 10.0 * ((10f64.powf(-2.0) + 10f64.powf(-2.6)) / 2.0).log10();
+        assert_lufs(combined_loudness(&[loud.clone(), quiet]), expected);
+
+        let silent = measure(48000, &[(20.0, -90.0)]);
+        assert_lufs(combined_loudness(&[loud, silent]), -20.0);
+    }
+}
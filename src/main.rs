@@ -1,6 +1,6 @@
 use anyhow::Result;
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyModifiers},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
@@ -8,25 +8,86 @@ use ratatui::{
     backend::{Backend, CrosstermBackend},
     Terminal,
 };
+use clap::Parser;
 use std::{
     io,
+    path::PathBuf,
     sync::{Arc, Mutex},
     time::Duration,
 };
 
 mod audio;
+mod cache;
 mod cassette;
+mod cli;
+mod config;
+mod directory_selector;
+mod keymap;
 mod library;
+mod loudness;
 mod metadata;
+mod mixer;
+mod output_picker;
+mod playlist;
+mod queue;
+mod replaygain;
+mod resample;
+mod scanner;
+mod search;
+mod sink;
+mod tag_editor;
 mod ui;
 mod visualizer;
+mod watcher;
 
 use audio::AudioPlayer;
+use cache::LibraryCache;
+use cli::{Cli, Command};
+use config::Config;
+use keymap::Action;
 use library::MusicLibrary;
 use ui::{App, AppState};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+    let mut config = Config::load()?;
+    let open = match cli.command {
+        Some(Command::Play { file, no_tui: false }) => Some(file),
+        Some(command) => return cli::run(command, &mut config),
+        None => cli.path,
+    };
+
+    // Report a bad path before taking over the terminal
+    let open = open.as_deref().map(cli::resolve_path).transpose()?;
+    run_tui(config, open).await
+}
+
+async fn run_tui(config: Config, open: Option<PathBuf>) -> Result<()> {
+    // Initialize components
+    let audio_player = Arc::new(Mutex::new(AudioPlayer::new(config.audio.clone())?));
+    let cache = LibraryCache::load();
+    let mut music_library = MusicLibrary::load_cached(&cache, config.library.roots.clone());
+    music_library.set_sort(config.library.sort);
+    let music_library = Arc::new(Mutex::new(music_library));
+    let app_state = Arc::new(Mutex::new(AppState::new(config.theme)));
+
+    // Create app
+    let mut app = App::new(
+        config,
+        audio_player,
+        music_library,
+        Arc::new(Mutex::new(cache)),
+        app_state,
+    );
+
+    if let Some(path) = open {
+        app.open_path(path);
+    }
+
+    // Pick up anything that changed while the player was closed
+    app.start_scan();
+
     // Setup terminal
     enable_raw_mode()?;
     let mut stdout = io::stdout();
@@ -34,14 +95,6 @@ async fn main() -> Result<()> {
     let backend = CrosstermBackend::new(stdout);
     let mut terminal = Terminal::new(backend)?;
 
-    // Initialize components
-    let audio_player = Arc::new(Mutex::new(AudioPlayer::new()?));
-    let music_library = Arc::new(Mutex::new(MusicLibrary::new()));
-    let app_state = Arc::new(Mutex::new(AppState::new()));
-
-    // Create app
-    let mut app = App::new(audio_player, music_library, app_state);
-
     // Run app
     let res = run_app(&mut terminal, &mut app).await;
 
@@ -61,56 +114,90 @@ async fn main() -> Result<()> {
     Ok(())
 }
 
-async fn run_app<B: Backend>(
-    terminal: &mut Terminal<B>,
-    app: &mut App,
-) -> Result<()> {
+async fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> Result<()> {
     loop {
         terminal.draw(|f| app.render(f))?;
 
         if crossterm::event::poll(Duration::from_millis(100))? {
             if let Event::Key(key) = event::read()? {
-                match key.code {
-                    KeyCode::Char('q') => {
-                        if key.modifiers.contains(KeyModifiers::CONTROL) {
+                if app.search_open() {
+                    // Typing goes to the search box while it's open
+                    app.handle_search_key(key.code, key.modifiers);
+                } else if app.tag_editor_open() {
+                    app.handle_tag_editor_key(key.code, key.modifiers);
+                } else if app.naming_playlist() {
+                    app.handle_playlist_name_key(key.code, key.modifiers);
+                } else {
+                    let action = app.handle_key(key.code, key.modifiers);
+                    if app.help_open() {
+                        // Any key closes the help
+                        app.toggle_help();
+                    } else if app.track_details_open() {
+                        app.close_track_details();
+                    } else if app.directory_selector_open() {
+                        app.handle_directory_selector_key(key.code, action);
+                    } else if app.playlists_open() {
+                        app.handle_playlists_key(key.code, action);
+                    } else if app.output_picker_open() {
+                        app.handle_output_picker_key(key.code, action);
+                    } else if let Some(action) = action {
+                        if action == Action::Quit {
                             return Ok(());
                         }
+                        run_action(app, action).await?;
                     }
-                    KeyCode::Char(' ') => {
-                        app.toggle_playback().await?;
-                    }
-                    KeyCode::Up => {
-                        app.navigate_up();
-                    }
-                    KeyCode::Down => {
-                        app.navigate_down();
-                    }
-                    KeyCode::Left => {
-                        app.navigate_left();
-                    }
-                    KeyCode::Right => {
-                        app.navigate_right();
-                    }
-                    KeyCode::Enter => {
-                        app.select_item().await?;
-                    }
-                    KeyCode::Char('t') => {
-                        app.cycle_theme();
-                    }
-                    KeyCode::Char('r') => {
-                        app.toggle_rainbow_mode();
-                    }
-                    KeyCode::Char('s') => {
-                        app.toggle_shortcuts();
-                    }
-                    KeyCode::Char('d') => {
-                        app.toggle_directory_selector();
-                    }
-                    _ => {}
                 }
             }
         }
 
         app.update().await?;
     }
+}
+
+async fn run_action(app: &mut App, action: Action) -> Result<()> {
+    match action {
+        Action::Quit => {}
+        Action::PlayPause => app.toggle_playback().await?,
+        Action::Stop => app.stop(),
+        Action::Up => app.navigate_up(),
+        Action::Down => app.navigate_down(),
+        Action::Left => app.navigate_left(),
+        Action::Right => app.navigate_right(),
+        Action::Top => app.navigate_top(),
+        Action::Bottom => app.navigate_bottom(),
+        Action::Select => app.select_item().await?,
+        Action::Search => app.open_search(),
+        Action::TrackDetails => app.open_track_details(),
+        Action::EditTags => app.open_tag_editor(),
+        Action::ToggleMark => app.toggle_mark(),
+        Action::SeekBack => app.seek_relative(-5),
+        Action::SeekForward => app.seek_relative(5),
+        Action::SeekBackLong => app.seek_relative(-30),
+        Action::SeekForwardLong => app.seek_relative(30),
+        Action::JumpTo(tenth) => app.seek_to_percent(u32::from(tenth) * 10),
+        Action::ToggleFocus => app.toggle_focus(),
+        Action::CycleSort => app.cycle_sort(),
+        Action::Enqueue => app.enqueue_selected(),
+        Action::PlayNext => app.play_selected_next(),
+        Action::RemoveFromQueue => app.remove_from_queue(),
+        Action::MoveQueueEntryUp => app.move_queue_entry_up(),
+        Action::MoveQueueEntryDown => app.move_queue_entry_down(),
+        Action::ClearQueue => app.clear_queue(),
+        Action::NextTrack => app.next_track()?,
+        Action::PreviousTrack => app.previous_track()?,
+        Action::ToggleShuffle => app.toggle_shuffle(),
+        Action::CycleRepeat => app.cycle_repeat(),
+        Action::VolumeUp => app.change_volume(0.05),
+        Action::VolumeDown => app.change_volume(-0.05),
+        Action::Mute => app.toggle_mute(),
+        Action::CycleTheme => app.cycle_theme(),
+        Action::ToggleRainbow => app.toggle_rainbow_mode(),
+        Action::ToggleShortcuts => app.toggle_shortcuts(),
+        Action::Help => app.toggle_help(),
+        Action::DirectorySelector => app.toggle_directory_selector(),
+        Action::Playlists => app.toggle_playlists(),
+        Action::OutputDevices => app.toggle_output_picker(),
+        Action::CancelScan => app.cancel_scan(),
+    }
+    Ok(())
 }
\ No newline at end of file
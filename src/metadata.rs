@@ -1,7 +1,17 @@
 use anyhow::Result;
-use lofty::prelude::*;
+use lofty::{
+    config::ParseOptions,
+    file::{AudioFile, FileType, TaggedFile},
+    mp4::{Mp4Codec, Mp4File},
+    prelude::*,
+    tag::Tag,
+};
+use crate::replaygain::{self, ReplayGain};
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::{fs::File, path::Path};
+
+/// Album of tracks that have no album tag.
+pub const UNKNOWN_ALBUM: &str = "Unknown Album";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct TrackMetadata {
@@ -11,6 +21,96 @@ pub struct TrackMetadata {
     pub track_number: Option<u32>,
     pub duration: Option<u64>, // in milliseconds
     pub file_path: String,
+    /// The artist the album is filed under, e.g. "Various Artists" on a compilation.
+    #[serde(default)]
+    pub album_artist: Option<String>,
+    #[serde(default)]
+    pub disc_number: Option<u32>,
+    #[serde(default)]
+    pub year: Option<u32>,
+    /// When the library first saw the file, in seconds since the Unix epoch.
+    #[serde(default)]
+    pub date_added: Option<u64>,
+    #[serde(default)]
+    pub disc_total: Option<u32>,
+    /// The recording date as tagged, e.g. "1997" or "1997-05-21".
+    #[serde(default)]
+    pub date: Option<String>,
+    #[serde(default)]
+    pub genre: Option<String>,
+    #[serde(default)]
+    pub composer: Option<String>,
+    #[serde(default)]
+    pub comment: Option<String>,
+    #[serde(default)]
+    pub bpm: Option<u32>,
+    #[serde(default)]
+    pub bitrate: Option<u32>, // in kbps
+    #[serde(default)]
+    pub sample_rate: Option<u32>, // in Hz
+    #[serde(default)]
+    pub bit_depth: Option<u8>,
+    #[serde(default)]
+    pub channels: Option<u8>,
+    #[serde(default)]
+    pub codec: Option<String>,
+    #[serde(default)]
+    pub replay_gain: ReplayGain,
+}
+
+/// Reads a field from the primary tag, falling back to the file's other tags, e.g. an
+/// ID3v1 or APE tag left next to the ID3v2 one.
+fn read_tag<'a, T>(
+    tagged_file: &'a TaggedFile,
+    read: impl Fn(&'a Tag) -> Option<T>,
+) -> Option<T> {
+    tagged_file
+        .primary_tag()
+        .into_iter()
+        .chain(tagged_file.tags())
+        .find_map(read)
+}
+
+/// Reads a text field, skipping empty and whitespace-only values.
+fn read_text(tagged_file: &TaggedFile, key: ItemKey) -> Option<String> {
+    read_tag(tagged_file, |tag| {
+        tag.get_strings(&key)
+            .map(str::trim)
+            .find(|text| !text.is_empty())
+    })
+    .map(String::from)
+}
+
+/// Name of the codec, which for most formats follows from the container.
+fn codec_name(file_type: FileType, path: &Path) -> Option<String> {
+    let name = match file_type {
+        FileType::Aac => "AAC",
+        FileType::Aiff => "AIFF",
+        FileType::Ape => "Monkey's Audio",
+        FileType::Flac => "FLAC",
+        FileType::Mpeg => "MP3",
+        FileType::Mpc => "Musepack",
+        FileType::Opus => "Opus",
+        FileType::Vorbis => "Vorbis",
+        FileType::Speex => "Speex",
+        FileType::Wav => "WAV",
+        FileType::WavPack => "WavPack",
+        // An MP4 holds AAC or ALAC, which only its own properties tell apart
+        FileType::Mp4 => {
+            let mut file = File::open(path).ok()?;
+            let mp4 = Mp4File::read_from(&mut file, ParseOptions::new()).ok()?;
+            match mp4.properties().codec() {
+                Mp4Codec::AAC => "AAC",
+                Mp4Codec::ALAC => "ALAC",
+                Mp4Codec::MP3 => "MP3",
+                Mp4Codec::FLAC => "FLAC",
+                _ => return None,
+            }
+        }
+        FileType::Custom(name) => name,
+        _ => return None,
+    };
+    Some(name.to_string())
 }
 
 impl TrackMetadata {
@@ -18,54 +118,69 @@ impl TrackMetadata {
         let path = path.as_ref();
         let tagged_file = lofty::read_from_path(path)?;
 
-        let title = tagged_file
-            .primary_tag()
-            .and_then(|tag| tag.title())
-            .or_else(|| {
-                tagged_file
-                    .tag(lofty::id3::v2::Id3v2Tag::default().tag_type())
-                    .and_then(|tag| tag.title())
-            })
+        let title = read_tag(&tagged_file, |tag| tag.title())
+            .map(|title| title.to_string())
             .unwrap_or_else(|| {
                 path.file_stem()
                     .and_then(|s| s.to_str())
                     .unwrap_or("Unknown")
-                    .to_string().into()
-            })
-            .to_string();
-
-        let artist = tagged_file
-            .primary_tag()
-            .and_then(|tag| tag.artist())
-            .or_else(|| {
-                tagged_file
-                    .tag(lofty::id3::v2::Id3v2Tag::default().tag_type())
-                    .and_then(|tag| tag.artist())
-            })
-            .unwrap_or(std::borrow::Cow::Borrowed("Unknown Artist"))
-            .to_string();
-
-        let album = tagged_file
-            .primary_tag()
-            .and_then(|tag| tag.album())
-            .or_else(|| {
-                tagged_file
-                    .tag(lofty::id3::v2::Id3v2Tag::default().tag_type())
-                    .and_then(|tag| tag.album())
-            })
-            .unwrap_or(std::borrow::Cow::Borrowed("Unknown Album"))
-            .to_string();
-
-        let track_number = tagged_file
-            .primary_tag()
-            .and_then(|tag| tag.track())
-            .or_else(|| {
-                tagged_file
-                    .tag(lofty::id3::v2::Id3v2Tag::default().tag_type())
-                    .and_then(|tag| tag.track())
+                    .to_string()
             });
 
-        let duration = Some(tagged_file.properties().duration().as_millis() as u64);
+        let artist = read_tag(&tagged_file, |tag| tag.artist())
+            .map(|artist| artist.to_string())
+            .unwrap_or_else(|| "Unknown Artist".to_string());
+
+        let album = read_tag(&tagged_file, |tag| tag.album())
+            .map(|album| album.to_string())
+            .unwrap_or_else(|| UNKNOWN_ALBUM.to_string());
+
+        let album_artist = read_tag(&tagged_file, |tag| tag.get_string(&ItemKey::AlbumArtist))
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from);
+
+        let track_number = read_tag(&tagged_file, |tag| tag.track());
+        let disc_number = read_tag(&tagged_file, |tag| tag.disk());
+        let disc_total = read_tag(&tagged_file, |tag| tag.disk_total());
+
+        let date = read_text(&tagged_file, ItemKey::RecordingDate);
+        // Some taggers only write a full date, so take the year from its start
+        let year = read_tag(&tagged_file, |tag| tag.year()).or_else(|| {
+            date.as_deref()
+                .and_then(|date| date.get(..4))
+                .and_then(|year| year.parse().ok())
+        });
+
+        let genre = read_text(&tagged_file, ItemKey::Genre);
+        let composer = read_text(&tagged_file, ItemKey::Composer);
+        let comment = read_text(&tagged_file, ItemKey::Comment);
+        // BPM may be tagged with decimals, e.g. "127.5"
+        let bpm = read_text(&tagged_file, ItemKey::IntegerBpm)
+            .or_else(|| read_text(&tagged_file, ItemKey::Bpm))
+            .and_then(|bpm| bpm.parse::<f64>().ok())
+            .filter(|bpm| *bpm > 0.0)
+            .map(|bpm| bpm.round() as u32);
+
+        let gain = |key| read_text(&tagged_file, key).and_then(|text| ReplayGain::parse_gain(&text));
+        let peak = |key| read_text(&tagged_file, key).and_then(|text| ReplayGain::parse_peak(&text));
+        let replay_gain = ReplayGain {
+            track_gain: gain(ItemKey::ReplayGainTrackGain),
+            track_peak: peak(ItemKey::ReplayGainTrackPeak),
+            album_gain: gain(ItemKey::ReplayGainAlbumGain),
+            album_peak: peak(ItemKey::ReplayGainAlbumPeak),
+        };
+
+        let properties = tagged_file.properties();
+        let duration = Some(properties.duration().as_millis() as u64);
+        let bitrate = properties
+            .audio_bitrate()
+            .or(properties.overall_bitrate())
+            .filter(|bitrate| *bitrate > 0);
+        let sample_rate = properties.sample_rate();
+        let bit_depth = properties.bit_depth();
+        let channels = properties.channels();
+        let codec = codec_name(tagged_file.file_type(), path);
 
         Ok(Self {
             title,
@@ -74,6 +189,22 @@ impl TrackMetadata {
             track_number,
             duration,
             file_path: path.to_string_lossy().to_string(),
+            album_artist,
+            disc_number,
+            year,
+            date_added: None,
+            disc_total,
+            date,
+            genre,
+            composer,
+            comment,
+            bpm,
+            bitrate,
+            sample_rate,
+            bit_depth,
+            channels,
+            codec,
+            replay_gain,
         })
     }
 
@@ -92,4 +223,69 @@ impl TrackMetadata {
     pub fn display_album(&self) -> String {
         self.album.clone()
     }
+
+    /// The artist the track's album is grouped under.
+    pub fn album_artist(&self) -> &str {
+        self.album_artist.as_deref().unwrap_or(&self.artist)
+    }
+
+    /// Every known field as (label, value) pairs, for the details popup and `info`.
+    pub fn details(&self) -> Vec<(&'static str, String)> {
+        let mut details = vec![
+            ("Title", self.title.clone()),
+            ("Artist", self.artist.clone()),
+            ("Album", self.album.clone()),
+        ];
+        let mut add = |label, value: Option<String>| {
+            if let Some(value) = value {
+                details.push((label, value));
+            }
+        };
+
+        add("Album artist", self.album_artist.clone());
+        add("Track", self.track_number.map(|track| track.to_string()));
+        add(
+            "Disc",
+            self.disc_number.map(|disc| match self.disc_total {
+                Some(total) => format!("{disc} of {total}"),
+                None => disc.to_string(),
+            }),
+        );
+        add(
+            "Date",
+            self.date.clone().or(self.year.map(|year| year.to_string())),
+        );
+        add("Genre", self.genre.clone());
+        add("Composer", self.composer.clone());
+        add("BPM", self.bpm.map(|bpm| bpm.to_string()));
+        add("Comment", self.comment.clone());
+        add(
+            "Duration",
+            self.duration.map(|ms| {
+                let secs = ms / 1000;
+                format!("{:02}:{:02}", secs / 60, secs % 60)
+            }),
+        );
+        add("Codec", self.codec.clone());
+        add("Bitrate", self.bitrate.map(|kbps| format!("{kbps} kbps")));
+        add(
+            "Sample rate",
+            self.sample_rate
+                .map(|hz| format!("{} kHz", hz as f64 / 1000.0)),
+        );
+        add("Bit depth", self.bit_depth.map(|bits| format!("{bits} bit")));
+        add(
+            "Channels",
+            self.channels.map(|channels| match channels {
+                1 => "Mono".to_string(),
+                2 => "Stereo".to_string(),
+                n => n.to_string(),
+            }),
+        );
+        let gain = &self.replay_gain;
+        add("Track gain", replaygain::describe_gain(gain.track_gain, gain.track_peak));
+        add("Album gain", replaygain::describe_gain(gain.album_gain, gain.album_peak));
+        add("File", Some(self.file_path.clone()));
+        details
+    }
 }
\ No newline at end of file
@@ -0,0 +1,187 @@
+use symphonia::core::audio::Channels;
+
+// -3 dB, the usual weight for centre and surround channels in a stereo downmix
+const SURROUND_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// Maps interleaved frames from the decoder's channel layout to the output channel count.
+pub struct ChannelMixer {
+    source: Channels,
+    in_channels: usize,
+    out_channels: usize,
+    // matrix[out][in]
+    matrix: Vec<Vec<f32>>,
+}
+
+impl ChannelMixer {
+    pub fn new(source: Channels, out_channels: usize) -> Self {
+        let in_channels = source.count().max(1);
+        let mut matrix = vec![vec![0.0; in_channels]; out_channels];
+
+        if in_channels == out_channels {
+            for (i, row) in matrix.iter_mut().enumerate() {
+                row[i] = 1.0;
+            }
+        } else if in_channels == 1 {
+            // Mono goes to the front pair, or the only channel there is
+            for row in matrix.iter_mut().take(2) {
+                row[0] = 1.0;
+            }
+        } else if out_channels == 1 {
+            for (i, channel) in source.iter().enumerate() {
+                if !is_lfe(channel) {
+                    matrix[0][i] = 1.0;
+                }
+            }
+        } else if out_channels > 0 {
+            // Fold everything into the front pair, leaving any extra outputs silent
+            for (i, channel) in source.iter().enumerate() {
+                let (left, right) = stereo_weights(channel);
+                matrix[0][i] = left;
+                matrix[1][i] = right;
+            }
+        }
+
+        // Scale rows down so a full-scale signal on every input cannot clip
+        for row in &mut matrix {
+            let total: f32 = row.iter().sum();
+            if total > 1.0 {
+                for gain in row.iter_mut() {
+                    *gain /= total;
+                }
+            }
+        }
+
+        Self {
+            source,
+            in_channels,
+            out_channels,
+            matrix,
+        }
+    }
+
+    pub fn source(&self) -> Channels {
+        self.source
+    }
+
+    /// Remixes interleaved input frames into interleaved output frames.
+    pub fn mix(&self, input: &[f32]) -> Vec<f32> {
+        let frames = input.len() / self.in_channels;
+        let mut output = vec![0.0; frames * self.out_channels];
+
+        for (in_frame, out_frame) in input
+            .chunks_exact(self.in_channels)
+            .zip(output.chunks_exact_mut(self.out_channels))
+        {
+            for (out_sample, row) in out_frame.iter_mut().zip(&self.matrix) {
+                *out_sample = in_frame.iter().zip(row).map(|(s, g)| s * g).sum();
+            }
+        }
+
+        output
+    }
+}
+
+fn is_lfe(channel: Channels) -> bool {
+    channel == Channels::LFE1 || channel == Channels::LFE2
+}
+
+/// Left/right weights for one source channel in a stereo downmix.
+fn stereo_weights(channel: Channels) -> (f32, f32) {
+    let left = Channels::REAR_LEFT
+        | Channels::FRONT_LEFT_CENTRE
+        | Channels::SIDE_LEFT
+        | Channels::TOP_FRONT_LEFT
+        | Channels::TOP_REAR_LEFT
+        | Channels::REAR_LEFT_CENTRE
+        | Channels::FRONT_LEFT_WIDE
+        | Channels::FRONT_LEFT_HIGH;
+    let right = Channels::REAR_RIGHT
+        | Channels::FRONT_RIGHT_CENTRE
+        | Channels::SIDE_RIGHT
+        | Channels::TOP_FRONT_RIGHT
+        | Channels::TOP_REAR_RIGHT
+        | Channels::REAR_RIGHT_CENTRE
+        | Channels::FRONT_RIGHT_WIDE
+        | Channels::FRONT_RIGHT_HIGH;
+
+    if channel == Channels::FRONT_LEFT {
+        (1.0, 0.0)
+    } else if channel == Channels::FRONT_RIGHT {
+        (0.0, 1.0)
+    } else if is_lfe(channel) {
+        (0.0, 0.0)
+    } else if left.contains(channel) {
+        (SURROUND_GAIN, 0.0)
+    } else if right.contains(channel) {
+        (0.0, SURROUND_GAIN)
+    } else {
+        // Centre channels are shared between both sides
+        (SURROUND_GAIN, SURROUND_GAIN)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEREO: Channels = Channels::FRONT_LEFT.union(Channels::FRONT_RIGHT);
+
+    fn surround_5_1() -> Channels {
+        STEREO
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len(), "{actual:?} vs {expected:?}");
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((a - e).abs() < 1e-6, "{actual:?} vs {expected:?}");
+        }
+    }
+
+    #[test]
+    fn same_layout_passes_through() {
+        let mixer = ChannelMixer::new(STEREO, 2);
+        assert_close(&mixer.mix(&[0.1, -0.2, 0.3, -0.4]), &[0.1, -0.2, 0.3, -0.4]);
+    }
+
+    #[test]
+    fn mono_goes_to_both_front_channels() {
+        let mixer = ChannelMixer::new(Channels::FRONT_CENTRE, 6);
+        assert_close(&mixer.mix(&[0.5]), &[0.5, 0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn stereo_to_mono_averages_without_clipping() {
+        let mixer = ChannelMixer::new(STEREO, 1);
+        assert_close(&mixer.mix(&[1.0, 1.0, 0.5, -0.5]), &[1.0, 0.0]);
+    }
+
+    #[test]
+    fn surround_folds_into_stereo_without_the_lfe() {
+        let mixer = ChannelMixer::new(surround_5_1(), 2);
+        // Each side gets its front, the centre and its rear, scaled so they sum to one
+        let total = 1.0 + 2.0 * SURROUND_GAIN;
+        let frame = |fl, fr, c, lfe, rl, rr| [fl, fr, c, lfe, rl, rr];
+
+        assert_close(&mixer.mix(&frame(1.0, 0.0, 0.0, 0.0, 0.0, 0.0)), &[1.0 / total, 0.0]);
+        assert_close(
+            &mixer.mix(&frame(0.0, 0.0, 1.0, 0.0, 0.0, 0.0)),
+            &[SURROUND_GAIN / total, SURROUND_GAIN / total],
+        );
+        assert_close(&mixer.mix(&frame(0.0, 0.0, 0.0, 1.0, 0.0, 0.0)), &[0.0, 0.0]);
+        assert_close(
+            &mixer.mix(&frame(0.0, 0.0, 0.0, 0.0, 0.0, 1.0)),
+            &[0.0, SURROUND_GAIN / total],
+        );
+        assert_close(&mixer.mix(&frame(1.0, 1.0, 1.0, 1.0, 1.0, 1.0)), &[1.0, 1.0]);
+    }
+
+    #[test]
+    fn stereo_to_surround_leaves_the_extra_channels_silent() {
+        let mixer = ChannelMixer::new(STEREO, 6);
+        assert_close(&mixer.mix(&[0.25, -0.5]), &[0.25, -0.5, 0.0, 0.0, 0.0, 0.0]);
+    }
+}
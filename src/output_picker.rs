@@ -0,0 +1,46 @@
+use crate::sink::{self, DeviceId, OutputDevice};
+use ratatui::widgets::ListState;
+
+/// The output device popup.
+pub struct OutputPicker {
+    pub devices: Vec<OutputDevice>,
+    /// The device playing now, marked in the list.
+    pub active: Option<DeviceId>,
+    pub list_state: ListState,
+}
+
+impl OutputPicker {
+    /// Lists the devices of every host, starting at the one playing.
+    pub fn load(active: Option<DeviceId>) -> Self {
+        let devices = sink::list_output_devices();
+        let selected = devices
+            .iter()
+            .position(|device| Some(&device.id) == active.as_ref())
+            .or((!devices.is_empty()).then_some(0));
+
+        let mut list_state = ListState::default();
+        list_state.select(selected);
+        Self {
+            devices,
+            active,
+            list_state,
+        }
+    }
+
+    pub fn selected(&self) -> Option<&OutputDevice> {
+        self.list_state.selected().and_then(|i| self.devices.get(i))
+    }
+
+    pub fn move_up(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state.select(Some(i.saturating_sub(1)));
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state
+                .select(Some((i + 1).min(self.devices.len().saturating_sub(1))));
+        }
+    }
+}
\ No newline at end of file
@@ -0,0 +1,444 @@
+use crate::metadata::TrackMetadata;
+use anyhow::{bail, Result};
+use ratatui::widgets::ListState;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write as _,
+    fs,
+    path::{Component, Path, PathBuf},
+};
+use walkdir::WalkDir;
+
+pub const PLAYLIST_EXTENSIONS: [&str; 3] = ["m3u", "m3u8", "pls"];
+
+// New playlists are saved in this folder of the first music root
+const PLAYLIST_DIR: &str = "Playlists";
+
+// Playlists are looked for in the roots and one folder down, e.g. `Music/Playlists`,
+// but not in album folders, where they usually just repeat the album
+const SEARCH_DEPTH: usize = 2;
+
+/// One line of a playlist.
+#[derive(Debug, Clone)]
+pub struct PlaylistEntry {
+    /// Absolute, with relative entries resolved against the playlist's folder.
+    pub path: PathBuf,
+    /// From `#EXTINF` or `TitleN=`, or the library's tags when the file is known.
+    pub title: Option<String>,
+    pub missing: bool,
+}
+
+impl PlaylistEntry {
+    fn new(path: PathBuf, title: Option<String>) -> Self {
+        Self {
+            missing: !path.is_file(),
+            path,
+            title,
+        }
+    }
+
+    pub fn label(&self) -> String {
+        self.title.clone().unwrap_or_else(|| {
+            self.path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| self.path.display().to_string())
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub name: String,
+    pub path: PathBuf,
+    pub entries: Vec<PlaylistEntry>,
+}
+
+impl Playlist {
+    /// Reads an M3U, M3U8 or PLS file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = decode(&fs::read(path)?);
+        let base = path.parent().unwrap_or(Path::new("/"));
+        let entries = match extension(path).as_deref() {
+            Some("pls") => parse_pls(&text, base),
+            Some("m3u" | "m3u8") => parse_m3u(&text, base),
+            _ => bail!("{} is not a playlist", path.display()),
+        };
+
+        Ok(Self {
+            name: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            path: path.to_path_buf(),
+            entries,
+        })
+    }
+
+    /// A new playlist in the playlist folder of the first music root.
+    pub fn create(name: &str, roots: &[PathBuf], tracks: &[TrackMetadata]) -> Result<Self> {
+        let name = name.trim();
+        if name.is_empty() || name.contains(['/', '\\']) || name.starts_with('.') {
+            bail!("`{name}` can't be used as a playlist name");
+        }
+        let Some(root) = roots.first() else {
+            bail!("Add a music folder first; playlists are saved next to the music");
+        };
+        let path = root.join(PLAYLIST_DIR).join(format!("{name}.m3u8"));
+        if path.exists() {
+            bail!("{} already exists", path.display());
+        }
+
+        let entries = tracks
+            .iter()
+            .map(|track| {
+                PlaylistEntry::new(
+                    PathBuf::from(&track.file_path),
+                    Some(format!("{} - {}", track.artist, track.title)),
+                )
+            })
+            .collect();
+        let playlist = Self {
+            name: name.to_string(),
+            path,
+            entries,
+        };
+        playlist.save(tracks)?;
+        Ok(playlist)
+    }
+
+    /// Writes the playlist as extended M3U8, with paths relative to its folder so the
+    /// music can move along with it.
+    fn save(&self, tracks: &[TrackMetadata]) -> Result<()> {
+        let dir = self.path.parent().unwrap_or(Path::new("/"));
+        let mut text = String::from("#EXTM3U\n");
+        for track in tracks {
+            let seconds = track.duration.map_or(-1, |ms| (ms / 1000) as i64);
+            let path = relative_path(Path::new(&track.file_path), dir);
+            writeln!(text, "#EXTINF:{seconds},{} - {}", track.artist, track.title)?;
+            writeln!(text, "{}", path.display())?;
+        }
+
+        fs::create_dir_all(dir)?;
+        fs::write(&self.path, text)?;
+        Ok(())
+    }
+
+    pub fn missing_count(&self) -> usize {
+        self.entries.iter().filter(|entry| entry.missing).count()
+    }
+}
+
+pub fn is_playlist(path: &Path) -> bool {
+    extension(path).is_some_and(|ext| PLAYLIST_EXTENSIONS.contains(&ext.as_str()))
+}
+
+fn extension(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(str::to_lowercase)
+}
+
+/// Playlist files in and just below the music roots, sorted by name.
+pub fn find_playlists(roots: &[PathBuf]) -> Vec<PathBuf> {
+    let mut found: BTreeMap<String, PathBuf> = BTreeMap::new();
+    for root in roots {
+        for entry in WalkDir::new(root)
+            .max_depth(SEARCH_DEPTH)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file() && is_playlist(e.path()))
+        {
+            let key = format!(
+                "{}\0{}",
+                entry.file_name().to_string_lossy().to_lowercase(),
+                entry.path().display()
+            );
+            found.insert(key, entry.into_path());
+        }
+    }
+    found.into_values().collect()
+}
+
+/// Playlists are UTF-8 these days, but plain `.m3u` files are often Latin-1.
+fn decode(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_prefix(b"\xEF\xBB\xBF").unwrap_or(bytes);
+    match std::str::from_utf8(bytes) {
+        Ok(text) => text.to_string(),
+        Err(_) => bytes.iter().map(|&b| b as char).collect(),
+    }
+}
+
+fn parse_m3u(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    let mut entries = Vec::new();
+    let mut title = None;
+    for line in text.lines().map(str::trim) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            // `#EXTINF:<seconds>,<artist> - <title>`
+            title = info
+                .split_once(',')
+                .map(|(_, title)| title.trim().to_string())
+                .filter(|title| !title.is_empty());
+        } else if !line.is_empty() && !line.starts_with('#') {
+            entries.push(PlaylistEntry::new(resolve(base, line), title.take()));
+        }
+    }
+    entries
+}
+
+fn parse_pls(text: &str, base: &Path) -> Vec<PlaylistEntry> {
+    // `File3=...` and `Title3=...` belong together, in whatever order they come
+    let mut files: BTreeMap<u32, (Option<String>, Option<String>)> = BTreeMap::new();
+    for line in text.lines().map(str::trim) {
+        let Some((key, value)) = line.split_once('=') else {
+            continue;
+        };
+        let key = key.trim().to_lowercase();
+        let value = value.trim().to_string();
+        if let Some(n) = key.strip_prefix("file").and_then(|n| n.parse().ok()) {
+            files.entry(n).or_default().0 = Some(value);
+        } else if let Some(n) = key.strip_prefix("title").and_then(|n| n.parse().ok()) {
+            files.entry(n).or_default().1 = Some(value).filter(|title| !title.is_empty());
+        }
+    }
+
+    files
+        .into_values()
+        .filter_map(|(file, title)| Some(PlaylistEntry::new(resolve(base, &file?), title)))
+        .collect()
+}
+
+/// Turns a playlist line into an absolute path, resolving relative ones against `base`.
+fn resolve(base: &Path, location: &str) -> PathBuf {
+    // File URLs escape spaces and other characters, plain paths are taken as they are
+    let location = match location.strip_prefix("file://") {
+        // `file://localhost/music/a.flac` names the same file as `file:///music/a.flac`
+        Some(url) => percent_decode(
+            url.strip_prefix("localhost")
+                .filter(|path| path.starts_with('/'))
+                .unwrap_or(url),
+        ),
+        None => location.to_string(),
+    };
+    // Playlists made on Windows use backslashes
+    let location = if cfg!(windows) {
+        location.to_string()
+    } else {
+        location.replace('\\', "/")
+    };
+
+    let path = Path::new(&location);
+    let path = if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base.join(path)
+    };
+    normalize(&path)
+}
+
+/// Decodes `%XX` escapes, e.g. "a%20b" to "a b". Anything that isn't a valid escape is
+/// kept as it is.
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = (bytes[i] == b'%')
+            .then(|| text.get(i + 1..i + 3))
+            .flatten()
+            .filter(|hex| hex.bytes().all(|b| b.is_ascii_hexdigit()))
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(byte) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            None => {
+                decoded.push(bytes[i]);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Drops `.` and `..` without touching the filesystem, so the path matches the
+/// library's even for files that are gone.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+    normalized
+}
+
+/// `path` relative to `dir`, going up with `..` where needed. Paths that only share
+/// the filesystem root stay absolute.
+fn relative_path(path: &Path, dir: &Path) -> PathBuf {
+    let path_parts: Vec<Component> = path.components().collect();
+    let dir_parts: Vec<Component> = dir.components().collect();
+    let common = path_parts
+        .iter()
+        .zip(&dir_parts)
+        .take_while(|(a, b)| a == b)
+        .count();
+    if common <= 1 {
+        return path.to_path_buf();
+    }
+
+    let mut relative = PathBuf::new();
+    for _ in common..dir_parts.len() {
+        relative.push("..");
+    }
+    relative.extend(&path_parts[common..]);
+    relative
+}
+
+/// Name being typed for a new playlist, and the tracks it will hold.
+pub struct NewPlaylist {
+    pub name: String,
+    pub tracks: Vec<TrackMetadata>,
+}
+
+/// The playlists popup.
+pub struct PlaylistBrowser {
+    pub playlists: Vec<Playlist>,
+    pub list_state: ListState,
+    pub naming: Option<NewPlaylist>,
+    // Shown at the bottom of the popup, e.g. why saving failed
+    pub message: Option<String>,
+}
+
+impl PlaylistBrowser {
+    /// Loads the playlists in the music roots, titling entries from the library's tags.
+    pub fn load(roots: &[PathBuf], library_tracks: &[TrackMetadata]) -> Self {
+        let by_path: HashMap<&str, &TrackMetadata> = library_tracks
+            .iter()
+            .map(|track| (track.file_path.as_str(), track))
+            .collect();
+
+        let mut failed = 0;
+        let mut playlists = Vec::new();
+        for path in find_playlists(roots) {
+            match Playlist::load(&path) {
+                Ok(mut playlist) => {
+                    for entry in &mut playlist.entries {
+                        if let Some(track) = entry.path.to_str().and_then(|p| by_path.get(p)) {
+                            entry.title = Some(format!("{} - {}", track.artist, track.title));
+                        }
+                    }
+                    playlists.push(playlist);
+                }
+                Err(_) => failed += 1,
+            }
+        }
+
+        let mut list_state = ListState::default();
+        list_state.select((!playlists.is_empty()).then_some(0));
+        Self {
+            playlists,
+            list_state,
+            naming: None,
+            message: (failed > 0).then(|| format!("Couldn't read {failed} playlist file(s)")),
+        }
+    }
+
+    pub fn selected(&self) -> Option<&Playlist> {
+        self.list_state.selected().and_then(|i| self.playlists.get(i))
+    }
+
+    pub fn select_path(&mut self, path: &Path) {
+        if let Some(index) = self.playlists.iter().position(|p| p.path == path) {
+            self.list_state.select(Some(index));
+        }
+    }
+
+    pub fn move_up(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state.select(Some(i.saturating_sub(1)));
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state
+                .select(Some((i + 1).min(self.playlists.len().saturating_sub(1))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn paths(entries: &[PlaylistEntry]) -> Vec<&Path> {
+        entries.iter().map(|entry| entry.path.as_path()).collect()
+    }
+
+    #[test]
+    fn m3u_resolves_relative_entries_and_reads_titles() {
+        let text = "#EXTM3U\n\
+                    #EXTINF:215,Artist - Song\n\
+                    ../Album/01 Song.flac\n\
+                    \n\
+                    # a comment\n\
+                    /other/02.mp3\n";
+        let entries = parse_m3u(text, Path::new("/music/Playlists"));
+        assert_eq!(
+            paths(&entries),
+            [Path::new("/music/Album/01 Song.flac"), Path::new("/other/02.mp3")]
+        );
+        assert_eq!(entries[0].title.as_deref(), Some("Artist - Song"));
+        assert_eq!(entries[1].title, None);
+    }
+
+    #[test]
+    fn m3u_decodes_file_urls() {
+        let text = "file:///music/A%20B/01.flac\nfile://localhost/music/C%23/02.flac\n";
+        let entries = parse_m3u(text, Path::new("/playlists"));
+        assert_eq!(
+            paths(&entries),
+            [Path::new("/music/A B/01.flac"), Path::new("/music/C#/02.flac")]
+        );
+    }
+
+    #[test]
+    fn pls_pairs_files_and_titles_by_number() {
+        let text = "[playlist]\n\
+                    Title2=Second\n\
+                    File2=b.ogg\n\
+                    File1=a.ogg\n\
+                    Title1=\n\
+                    NumberOfEntries=2\n";
+        let entries = parse_pls(text, Path::new("/music"));
+        assert_eq!(paths(&entries), [Path::new("/music/a.ogg"), Path::new("/music/b.ogg")]);
+        assert_eq!(entries[0].title, None);
+        assert_eq!(entries[1].title.as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn percent_decode_keeps_invalid_escapes() {
+        assert_eq!(percent_decode("a%20b"), "a b");
+        assert_eq!(percent_decode("caf%C3%A9"), "café");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%2"), "%zz%2");
+    }
+
+    #[test]
+    fn relative_path_goes_up_to_the_common_folder() {
+        assert_eq!(
+            relative_path(Path::new("/music/Album/01.flac"), Path::new("/music/Playlists")),
+            Path::new("../Album/01.flac")
+        );
+        assert_eq!(
+            relative_path(Path::new("/other/01.flac"), Path::new("/music/Playlists")),
+            Path::new("/other/01.flac")
+        );
+    }
+}
@@ -0,0 +1,411 @@
+use crate::metadata::TrackMetadata;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum RepeatMode {
+    #[default]
+    Off,
+    One,
+    Album,
+    All,
+}
+
+impl RepeatMode {
+    pub fn cycle(self) -> Self {
+        match self {
+            RepeatMode::Off => RepeatMode::One,
+            RepeatMode::One => RepeatMode::Album,
+            RepeatMode::Album => RepeatMode::All,
+            RepeatMode::All => RepeatMode::Off,
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            RepeatMode::Off => "Off",
+            RepeatMode::One => "Track",
+            RepeatMode::Album => "Album",
+            RepeatMode::All => "All",
+        }
+    }
+}
+
+/// The queue as it was before shuffling, restored when shuffle is switched off.
+#[derive(Debug, Clone)]
+struct ShuffleState {
+    seed: u64,
+    saved_tracks: Vec<TrackMetadata>,
+}
+
+/// Tracks lined up for playback, independent of where the library is being browsed.
+#[derive(Debug, Clone, Default)]
+pub struct PlayQueue {
+    tracks: Vec<TrackMetadata>,
+    // Entry being played. `None` means playback starts from the first entry.
+    current: Option<usize>,
+    now_playing: Option<TrackMetadata>,
+    repeat: RepeatMode,
+    shuffle: Option<ShuffleState>,
+}
+
+impl PlayQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn enqueue(&mut self, track: TrackMetadata) {
+        self.tracks.push(track);
+    }
+
+    pub fn enqueue_all(&mut self, tracks: impl IntoIterator<Item = TrackMetadata>) {
+        self.tracks.extend(tracks);
+    }
+
+    /// Inserts `track` right after the one playing now.
+    pub fn play_next(&mut self, track: TrackMetadata) {
+        let index = self.current.map_or(0, |current| current + 1);
+        self.tracks.insert(index, track);
+    }
+
+    /// Removes an entry. Removing the playing entry leaves it playing, and the
+    /// entry that took its place becomes the next one.
+    pub fn remove(&mut self, index: usize) {
+        if index >= self.tracks.len() {
+            return;
+        }
+        self.tracks.remove(index);
+
+        if let Some(current) = self.current {
+            if index <= current {
+                self.current = current.checked_sub(1);
+            }
+        }
+    }
+
+    /// Swaps an entry with its neighbour. Returns the entry's new index.
+    pub fn move_up(&mut self, index: usize) -> usize {
+        if index == 0 || index >= self.tracks.len() {
+            return index;
+        }
+        self.swap(index, index - 1);
+        index - 1
+    }
+
+    pub fn move_down(&mut self, index: usize) -> usize {
+        if index + 1 >= self.tracks.len() {
+            return index;
+        }
+        self.swap(index, index + 1);
+        index + 1
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.tracks.swap(a, b);
+        if self.current == Some(a) {
+            self.current = Some(b);
+        } else if self.current == Some(b) {
+            self.current = Some(a);
+        }
+    }
+
+    /// Swaps in re-read tags for entries of the same files, e.g. after editing them.
+    pub fn update_tracks(&mut self, tracks: &[TrackMetadata]) {
+        let saved = self
+            .shuffle
+            .iter_mut()
+            .flat_map(|state| state.saved_tracks.iter_mut());
+        let entries = self
+            .tracks
+            .iter_mut()
+            .chain(self.now_playing.iter_mut())
+            .chain(saved);
+        for entry in entries {
+            if let Some(track) = tracks.iter().find(|track| track.file_path == entry.file_path) {
+                *entry = track.clone();
+            }
+        }
+    }
+
+    /// Empties the queue. Whatever is playing keeps playing.
+    pub fn clear(&mut self) {
+        self.tracks.clear();
+        self.current = None;
+    }
+
+    /// Moves the cursor to `index` and returns the track to play.
+    pub fn play_index(&mut self, index: usize) -> Option<&TrackMetadata> {
+        let track = self.tracks.get(index)?;
+        self.current = Some(index);
+        self.now_playing = Some(track.clone());
+        self.now_playing.as_ref()
+    }
+
+    /// Moves on to the entry that should play next when a track finishes.
+    pub fn advance(&mut self) -> Option<&TrackMetadata> {
+        let index = self.next_index(true)?;
+        self.play_index(index)
+    }
+
+    /// Skips to the next entry on request. Unlike `advance`, this leaves the track
+    /// even when repeating a single track.
+    pub fn skip_forward(&mut self) -> Option<&TrackMetadata> {
+        let index = self.next_index(false)?;
+        self.play_index(index)
+    }
+
+    /// Goes back to the entry that played before the current one.
+    pub fn previous(&mut self) -> Option<&TrackMetadata> {
+        let index = match self.current? {
+            0 if self.repeat == RepeatMode::All => self.tracks.len().checked_sub(1)?,
+            current => current.checked_sub(1)?,
+        };
+        self.play_index(index)
+    }
+
+    /// The entry `advance` would move to, so it can be opened ahead of time.
+    pub fn peek_next(&self) -> Option<&TrackMetadata> {
+        self.tracks.get(self.next_index(true)?)
+    }
+
+    fn next_index(&self, repeat_track: bool) -> Option<usize> {
+        let Some(current) = self.current else {
+            return (!self.tracks.is_empty()).then_some(0);
+        };
+        let following = current + 1;
+
+        match self.repeat {
+            RepeatMode::One if repeat_track => Some(current),
+            RepeatMode::Album if !self.same_album(current, following) => {
+                Some(self.album_start(current))
+            }
+            RepeatMode::All if following >= self.tracks.len() => Some(0),
+            _ => (following < self.tracks.len()).then_some(following),
+        }
+    }
+
+    /// Whether two entries are on the same album, grouped as in the library so the
+    /// tracks of a compilation stay together.
+    fn same_album(&self, a: usize, b: usize) -> bool {
+        match (self.tracks.get(a), self.tracks.get(b)) {
+            (Some(a), Some(b)) => a.album == b.album && a.album_artist() == b.album_artist(),
+            _ => false,
+        }
+    }
+
+    /// First entry of the run of consecutive entries from the same album as `index`.
+    fn album_start(&self, index: usize) -> usize {
+        let mut start = index;
+        while start > 0 && self.same_album(start - 1, index) {
+            start -= 1;
+        }
+        start
+    }
+
+    pub fn repeat(&self) -> RepeatMode {
+        self.repeat
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.repeat = self.repeat.cycle();
+    }
+
+    pub fn is_shuffled(&self) -> bool {
+        self.shuffle.is_some()
+    }
+
+    /// Replaces the queue with a shuffled order of `library_tracks`. Each track appears
+    /// once, so stepping back replays exactly what came before. The track playing now
+    /// stays at the front.
+    pub fn enable_shuffle(&mut self, library_tracks: &[TrackMetadata], seed: u64) {
+        if self.shuffle.is_some() {
+            return;
+        }
+
+        let playing_path = self.now_playing.as_ref().map(|t| t.file_path.clone());
+        let mut tracks: Vec<TrackMetadata> = library_tracks
+            .iter()
+            .filter(|t| Some(&t.file_path) != playing_path.as_ref())
+            .cloned()
+            .collect();
+        shuffle_tracks(&mut tracks, seed);
+
+        let saved_tracks = std::mem::take(&mut self.tracks);
+        self.current = None;
+        if let Some(playing) = &self.now_playing {
+            tracks.insert(0, playing.clone());
+            self.current = Some(0);
+        }
+        self.tracks = tracks;
+        self.shuffle = Some(ShuffleState { seed, saved_tracks });
+    }
+
+    /// Puts back the queue from before shuffling, keeping the cursor on the playing track.
+    pub fn disable_shuffle(&mut self) {
+        let Some(state) = self.shuffle.take() else {
+            return;
+        };
+
+        self.tracks = state.saved_tracks;
+        self.current = self.now_playing.as_ref().and_then(|playing| {
+            self.tracks
+                .iter()
+                .position(|t| t.file_path == playing.file_path)
+        });
+    }
+
+    pub fn shuffle_seed(&self) -> Option<u64> {
+        self.shuffle.as_ref().map(|state| state.seed)
+    }
+
+    pub fn now_playing(&self) -> Option<&TrackMetadata> {
+        self.now_playing.as_ref()
+    }
+
+    pub fn current_index(&self) -> Option<usize> {
+        self.current
+    }
+
+    pub fn tracks(&self) -> &[TrackMetadata] {
+        &self.tracks
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+}
+
+/// A seed that differs from run to run.
+pub fn random_seed() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0x9E37_79B9_7F4A_7C15)
+}
+
+/// Fisher-Yates shuffle driven by SplitMix64, so the same seed gives the same order.
+fn shuffle_tracks(tracks: &mut [TrackMetadata], seed: u64) {
+    let mut state = seed;
+    let mut next_random = || {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    };
+
+    for i in (1..tracks.len()).rev() {
+        let j = (next_random() % (i as u64 + 1)) as usize;
+        tracks.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(path: &str, album: &str) -> TrackMetadata {
+        serde_json::from_value(serde_json::json!({
+            "title": path,
+            "artist": "Artist",
+            "album": album,
+            "file_path": path,
+        }))
+        .unwrap()
+    }
+
+    /// Two albums: a1 and a2 on A, b1 on B.
+    fn queue() -> PlayQueue {
+        let mut queue = PlayQueue::new();
+        queue.enqueue_all([track("a1", "A"), track("a2", "A"), track("b1", "B")]);
+        queue
+    }
+
+    fn path(track: Option<&TrackMetadata>) -> Option<&str> {
+        track.map(|track| track.file_path.as_str())
+    }
+
+    #[test]
+    fn plays_through_once_without_repeat() {
+        let mut queue = queue();
+        assert_eq!(path(queue.advance()), Some("a1"));
+        assert_eq!(path(queue.advance()), Some("a2"));
+        assert_eq!(path(queue.advance()), Some("b1"));
+        assert_eq!(path(queue.peek_next()), None);
+        assert_eq!(path(queue.advance()), None);
+        assert_eq!(path(queue.now_playing()), Some("b1"));
+    }
+
+    #[test]
+    fn repeat_track_only_holds_when_advancing() {
+        let mut queue = queue();
+        queue.cycle_repeat();
+        assert_eq!(queue.repeat(), RepeatMode::One);
+        queue.advance();
+        assert_eq!(path(queue.advance()), Some("a1"));
+        assert_eq!(path(queue.skip_forward()), Some("a2"));
+    }
+
+    #[test]
+    fn repeat_album_goes_back_to_the_album_start() {
+        let mut queue = queue();
+        queue.repeat = RepeatMode::Album;
+        queue.play_index(1);
+        assert_eq!(path(queue.advance()), Some("a1"));
+        queue.play_index(2);
+        assert_eq!(path(queue.advance()), Some("b1"));
+    }
+
+    #[test]
+    fn repeat_all_wraps_around() {
+        let mut queue = queue();
+        queue.repeat = RepeatMode::All;
+        queue.play_index(2);
+        assert_eq!(path(queue.advance()), Some("a1"));
+        assert_eq!(path(queue.previous()), Some("b1"));
+    }
+
+    #[test]
+    fn shuffle_plays_every_track_once_in_a_repeatable_order() {
+        let library: Vec<_> = (0..20).map(|i| track(&format!("t{i}"), "A")).collect();
+        let mut queue = PlayQueue::new();
+        queue.enqueue(library[7].clone());
+        queue.advance();
+
+        queue.enable_shuffle(&library, 42);
+        let order: Vec<_> = queue.tracks().iter().map(|t| t.file_path.clone()).collect();
+        assert_eq!(order[0], "t7");
+        assert_eq!(queue.current_index(), Some(0));
+        let mut sorted = order.clone();
+        sorted.sort();
+        sorted.dedup();
+        assert_eq!(sorted.len(), library.len());
+
+        let mut again = PlayQueue::new();
+        again.enqueue(library[7].clone());
+        again.advance();
+        again.enable_shuffle(&library, 42);
+        let again: Vec<_> = again.tracks().iter().map(|t| t.file_path.clone()).collect();
+        assert_eq!(order, again);
+    }
+
+    #[test]
+    fn disabling_shuffle_restores_the_queue_at_the_playing_track() {
+        let mut queue = queue();
+        queue.advance();
+        let library = [track("a1", "A"), track("a2", "A"), track("b1", "B")];
+        queue.enable_shuffle(&library, 7);
+        while path(queue.now_playing()) != Some("a2") {
+            queue.skip_forward();
+        }
+
+        queue.disable_shuffle();
+        assert!(!queue.is_shuffled());
+        assert_eq!(queue.len(), 3);
+        assert_eq!(queue.current_index(), Some(1));
+        assert_eq!(path(queue.peek_next()), Some("b1"));
+    }
+}
@@ -0,0 +1,234 @@
+use crate::{
+    loudness::{self, TrackLoudness},
+    metadata::{TrackMetadata, UNKNOWN_ALBUM},
+};
+use anyhow::Result;
+use lofty::{config::WriteOptions, prelude::*, tag::Tag};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+/// Loudness that ReplayGain 2.0 brings every track to, in LUFS.
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+/// Which of a track's gain tags playback follows.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    /// Every track at the same loudness.
+    Track,
+    /// Whole albums at the same loudness, keeping the differences between their tracks.
+    Album,
+}
+
+/// The ReplayGain tags of a track. Gains are in dB, peaks relative to full scale.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+impl ReplayGain {
+    /// Reads a gain tag such as "-6.52 dB" or "+1.2".
+    pub fn parse_gain(text: &str) -> Option<f32> {
+        let text = text.trim();
+        let number = text
+            .strip_suffix("dB")
+            .or_else(|| text.strip_suffix("db"))
+            .unwrap_or(text);
+        number.trim().parse().ok().filter(|gain: &f32| gain.is_finite())
+    }
+
+    /// Reads a peak tag such as "0.988553".
+    pub fn parse_peak(text: &str) -> Option<f32> {
+        text.trim()
+            .parse()
+            .ok()
+            .filter(|peak: &f32| peak.is_finite() && *peak >= 0.0)
+    }
+
+    /// The linear gain to play the track at in `mode`, with `preamp_db` added on top.
+    /// Album mode uses the track's own values when there are no album tags, and the
+    /// other way round. The gain never takes the peak above full scale, so a positive
+    /// gain or preamp can't clip; without a peak tag the track is assumed to reach it.
+    /// Untagged tracks play unchanged.
+    pub fn factor(&self, mode: ReplayGainMode, preamp_db: f32) -> f32 {
+        let track = self.track_gain.map(|gain| (gain, self.track_peak));
+        let album = self.album_gain.map(|gain| (gain, self.album_peak));
+        let chosen = match mode {
+            ReplayGainMode::Off => None,
+            ReplayGainMode::Track => track.or(album),
+            ReplayGainMode::Album => album.or(track),
+        };
+        let Some((gain_db, peak)) = chosen else {
+            return 1.0;
+        };
+
+        let factor = 10f32.powf((gain_db + preamp_db) / 20.0);
+        match peak.unwrap_or(1.0) {
+            peak if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// E.g. "-6.52 dB, peak 0.989".
+pub fn describe_gain(gain: Option<f32>, peak: Option<f32>) -> Option<String> {
+    let gain = gain?;
+    Some(match peak {
+        Some(peak) => format!("{gain:+.2} dB, peak {peak:.3}"),
+        None => format!("{gain:+.2} dB"),
+    })
+}
+
+/// Gain that brings `loudness` to the reference level.
+fn gain_for(loudness: f64) -> f32 {
+    (REFERENCE_LUFS - loudness) as f32
+}
+
+/// What the analyzer found for one track.
+pub struct Analysis {
+    pub path: String,
+    /// Integrated loudness in LUFS; `None` for silence, which gets no tags.
+    pub loudness: Option<f64>,
+    pub gain: ReplayGain,
+}
+
+/// Result of an analysis: the tracks measured, and the files that couldn't be decoded.
+pub struct AnalysisReport {
+    pub tracks: Vec<Analysis>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// Whether the track is missing gain tags the analyzer would write.
+fn lacks_tags(track: &TrackMetadata) -> bool {
+    let gain = &track.replay_gain;
+    gain.track_gain.is_none() || (track.album != UNKNOWN_ALBUM && gain.album_gain.is_none())
+}
+
+/// Measures the tracks that lack gain tags, or all of them with `force`, after EBU R128.
+/// Tracks that share an album are measured together for the album gain, so one
+/// untagged track means its whole album is measured again. `progress` is called as
+/// each file is done.
+pub fn analyze(
+    tracks: &[TrackMetadata],
+    force: bool,
+    progress: impl Fn(&str) + Sync,
+) -> AnalysisReport {
+    // Tracks without an album tag are each on their own
+    let mut albums: BTreeMap<(&str, &str), Vec<&TrackMetadata>> = BTreeMap::new();
+    let mut singles = Vec::new();
+    for track in tracks {
+        if track.album == UNKNOWN_ALBUM {
+            singles.push(vec![track]);
+        } else {
+            albums
+                .entry((track.album_artist(), &track.album))
+                .or_default()
+                .push(track);
+        }
+    }
+
+    let groups: Vec<(bool, Vec<&TrackMetadata>)> = albums
+        .into_values()
+        .map(|group| (true, group))
+        .chain(singles.into_iter().map(|group| (false, group)))
+        .filter(|(_, group)| force || group.iter().any(|track| lacks_tags(track)))
+        .collect();
+
+    let results: Vec<_> = groups
+        .par_iter()
+        .flat_map_iter(|(is_album, group)| {
+            let measured: Vec<_> = group
+                .iter()
+                .map(|track| {
+                    let result = loudness::measure_file(Path::new(&track.file_path));
+                    progress(&track.file_path);
+                    result
+                })
+                .collect();
+
+            let measured_ok: Vec<TrackLoudness> =
+                measured.iter().filter_map(|m| m.as_ref().ok()).cloned().collect();
+            let album = (*is_album)
+                .then(|| loudness::combined_loudness(&measured_ok))
+                .flatten()
+                .map(|loudness| {
+                    let peak = measured_ok.iter().map(|m| m.peak).fold(0.0, f32::max);
+                    (gain_for(loudness), peak)
+                });
+
+            group
+                .iter()
+                .zip(measured)
+                .map(move |(track, measured)| {
+                    let path = track.file_path.clone();
+                    let measured = measured.map_err(|err| (path.clone(), err))?;
+                    let loudness = measured.integrated();
+                    let gain = match loudness {
+                        Some(loudness) => ReplayGain {
+                            track_gain: Some(gain_for(loudness)),
+                            track_peak: Some(measured.peak),
+                            album_gain: album.map(|(gain, _)| gain),
+                            album_peak: album.map(|(_, peak)| peak),
+                        },
+                        None => ReplayGain::default(),
+                    };
+                    Ok(Analysis {
+                        path,
+                        loudness,
+                        gain,
+                    })
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect();
+
+    let mut report = AnalysisReport {
+        tracks: Vec::new(),
+        failed: Vec::new(),
+    };
+    for result in results {
+        match result {
+            Ok(analysis) => report.tracks.push(analysis),
+            Err(failed) => report.failed.push(failed),
+        }
+    }
+    report
+}
+
+/// Writes the gain tags to every tag in the file, like the tag editor does, replacing
+/// older ones. Tags without an album gain lose any stale album values.
+pub fn write_tags(path: &str, gain: &ReplayGain) -> Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let values = [
+        (ItemKey::ReplayGainTrackGain, gain.track_gain.map(|g| format!("{g:.2} dB"))),
+        (ItemKey::ReplayGainTrackPeak, gain.track_peak.map(|p| format!("{p:.6}"))),
+        (ItemKey::ReplayGainAlbumGain, gain.album_gain.map(|g| format!("{g:.2} dB"))),
+        (ItemKey::ReplayGainAlbumPeak, gain.album_peak.map(|p| format!("{p:.6}"))),
+    ];
+    let tag_types: Vec<_> = tagged_file.tags().iter().map(|tag| tag.tag_type()).collect();
+    for tag_type in tag_types {
+        if let Some(tag) = tagged_file.tag_mut(tag_type) {
+            for (key, value) in &values {
+                match value {
+                    Some(value) => {
+                        tag.insert_text(key.clone(), value.clone());
+                    }
+                    None => tag.remove_key(key),
+                }
+            }
+        }
+    }
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
\ No newline at end of file
@@ -0,0 +1,295 @@
+use anyhow::Result;
+use rubato::{
+    calculate_cutoff, FastFixedIn, PolynomialDegree, ResampleResult, Resampler, SincFixedIn,
+    SincInterpolationParameters, SincInterpolationType, WindowFunction,
+};
+use serde::{Deserialize, Serialize};
+
+// Frames handed to the resampler at a time; decoded packets are split or joined to fit
+const CHUNK_FRAMES: usize = 1024;
+
+/// Trade-off between CPU use and accuracy when converting sample rates.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResampleQuality {
+    /// Cubic polynomial interpolation. Cheap, with some aliasing in the top octave.
+    Fast,
+    /// Short sinc filter.
+    Balanced,
+    /// Long sinc filter with a cutoff close to the output's Nyquist frequency.
+    #[default]
+    High,
+}
+
+impl ResampleQuality {
+    fn filter(self, ratio: f64, channels: usize) -> Result<Filter> {
+        let sinc = |sinc_len, interpolation, oversampling_factor| {
+            let window = WindowFunction::BlackmanHarris2;
+            SincInterpolationParameters {
+                sinc_len,
+                f_cutoff: calculate_cutoff(sinc_len, window),
+                interpolation,
+                oversampling_factor,
+                window,
+            }
+        };
+
+        Ok(match self {
+            ResampleQuality::Fast => Filter::Polynomial(FastFixedIn::new(
+                ratio,
+                1.0,
+                PolynomialDegree::Cubic,
+                CHUNK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::Balanced => Filter::Sinc(SincFixedIn::new(
+                ratio,
+                1.0,
+                sinc(64, SincInterpolationType::Linear, 128),
+                CHUNK_FRAMES,
+                channels,
+            )?),
+            ResampleQuality::High => Filter::Sinc(SincFixedIn::new(
+                ratio,
+                1.0,
+                sinc(256, SincInterpolationType::Linear, 256),
+                CHUNK_FRAMES,
+                channels,
+            )?),
+        })
+    }
+}
+
+/// The two kinds of rubato resampler the presets use. `Resampler` isn't object safe,
+/// so this stands in for a trait object.
+enum Filter {
+    Polynomial(FastFixedIn<f32>),
+    Sinc(SincFixedIn<f32>),
+}
+
+impl Filter {
+    fn process(&mut self, input: &[Vec<f32>]) -> ResampleResult<Vec<Vec<f32>>> {
+        match self {
+            Filter::Polynomial(filter) => filter.process(input, None),
+            Filter::Sinc(filter) => filter.process(input, None),
+        }
+    }
+
+    /// Processes `input`, padded with silence to a whole chunk.
+    fn process_partial(&mut self, input: Option<&[Vec<f32>]>) -> ResampleResult<Vec<Vec<f32>>> {
+        match self {
+            Filter::Polynomial(filter) => filter.process_partial(input, None),
+            Filter::Sinc(filter) => filter.process_partial(input, None),
+        }
+    }
+
+    fn input_frames_next(&self) -> usize {
+        match self {
+            Filter::Polynomial(filter) => filter.input_frames_next(),
+            Filter::Sinc(filter) => filter.input_frames_next(),
+        }
+    }
+
+    /// Output frames that lag behind the input. rubato reports half the filter length
+    /// for the sinc resamplers too, but their output already lines up with the input.
+    fn output_delay(&self) -> usize {
+        match self {
+            Filter::Polynomial(filter) => filter.output_delay(),
+            Filter::Sinc(_) => 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            Filter::Polynomial(filter) => filter.reset(),
+            Filter::Sinc(filter) => filter.reset(),
+        }
+    }
+}
+
+/// Converts a stream of interleaved packets of any size from one sample rate to
+/// another. Input is buffered until a whole chunk is there, and the filter's delay is
+/// trimmed so output frame `n` lines up with input frame `n / ratio`.
+pub struct StreamResampler {
+    resampler: Filter,
+    ratio: f64,
+    channels: usize,
+    // Deinterleaved input that doesn't fill a chunk yet
+    pending: Vec<Vec<f32>>,
+    // Output frames still to drop at the start of the stream
+    delay: usize,
+    // Frames in and out since the last reset, to size the tail
+    frames_in: u64,
+    frames_out: u64,
+}
+
+impl StreamResampler {
+    pub fn new(
+        source_rate: u32,
+        output_rate: u32,
+        channels: usize,
+        quality: ResampleQuality,
+    ) -> Result<Self> {
+        let ratio = output_rate as f64 / source_rate as f64;
+        let resampler = quality.filter(ratio, channels)?;
+        Ok(Self {
+            delay: resampler.output_delay(),
+            resampler,
+            ratio,
+            channels,
+            pending: vec![Vec::with_capacity(CHUNK_FRAMES); channels],
+            frames_in: 0,
+            frames_out: 0,
+        })
+    }
+
+    /// Resamples `samples`, returning as much output as whole chunks allow.
+    pub fn process(&mut self, samples: &[f32]) -> Result<Vec<f32>> {
+        for frame in samples.chunks_exact(self.channels) {
+            for (channel, &sample) in self.pending.iter_mut().zip(frame) {
+                channel.push(sample);
+            }
+        }
+        self.frames_in += (samples.len() / self.channels) as u64;
+
+        let mut output = Vec::new();
+        while self.pending[0].len() >= self.resampler.input_frames_next() {
+            let needed = self.resampler.input_frames_next();
+            let chunk: Vec<Vec<f32>> = self
+                .pending
+                .iter_mut()
+                .map(|channel| channel.drain(..needed).collect())
+                .collect();
+            let resampled = self.resampler.process(&chunk)?;
+            self.append(&resampled, &mut output, u64::MAX);
+        }
+        Ok(output)
+    }
+
+    /// Pushes the buffered input and the filter's delay line out, at the end of a
+    /// track. Returns exactly the output still owed for the input seen so far.
+    pub fn flush(&mut self) -> Result<Vec<f32>> {
+        let expected = (self.frames_in as f64 * self.ratio).round() as u64;
+        let mut output = Vec::new();
+        while self.frames_out < expected {
+            // The first pass takes the leftover input, the rest just push out silence
+            let input = (!self.pending[0].is_empty()).then_some(self.pending.as_slice());
+            let resampled = self.resampler.process_partial(input)?;
+            for channel in &mut self.pending {
+                channel.clear();
+            }
+            self.append(&resampled, &mut output, expected);
+        }
+        self.reset();
+        Ok(output)
+    }
+
+    /// Forgets all buffered audio, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.resampler.reset();
+        for channel in &mut self.pending {
+            channel.clear();
+        }
+        self.delay = self.resampler.output_delay();
+        self.frames_in = 0;
+        self.frames_out = 0;
+    }
+
+    /// Interleaves `resampled` onto `output`, dropping the delay and stopping at
+    /// `limit` frames in total.
+    fn append(&mut self, resampled: &[Vec<f32>], output: &mut Vec<f32>, limit: u64) {
+        let frames = resampled.first().map_or(0, Vec::len);
+        let skip = self.delay.min(frames);
+        self.delay -= skip;
+
+        let available = limit.saturating_sub(self.frames_out);
+        let take = ((frames - skip) as u64).min(available) as usize;
+        for i in skip..skip + take {
+            output.extend(resampled.iter().map(|channel| channel[i]));
+        }
+        self.frames_out += take as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const QUALITIES: [ResampleQuality; 3] = [
+        ResampleQuality::Fast,
+        ResampleQuality::Balanced,
+        ResampleQuality::High,
+    ];
+
+    /// Feeds `input` in packets of `packet_frames` and flushes at the end.
+    fn run(resampler: &mut StreamResampler, input: &[f32], packet_frames: usize) -> Vec<f32> {
+        let mut output = Vec::new();
+        for packet in input.chunks(packet_frames * resampler.channels) {
+            output.extend(resampler.process(packet).unwrap());
+        }
+        output.extend(resampler.flush().unwrap());
+        output
+    }
+
+    /// A stereo stream that is silent apart from one click on the left at `at`.
+    fn click(frames: usize, at: usize) -> Vec<f32> {
+        let mut samples = vec![0.0; frames * 2];
+        samples[at * 2] = 1.0;
+        samples
+    }
+
+    fn loudest_frame(samples: &[f32], channel: usize) -> usize {
+        samples
+            .iter()
+            .skip(channel)
+            .step_by(2)
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.abs().total_cmp(&b.abs()))
+            .map(|(i, _)| i)
+            .unwrap()
+    }
+
+    #[test]
+    fn flush_returns_exactly_the_owed_length() {
+        let cases = [(44100, 48000, 22050), (48000, 44100, 777), (96000, 44100, 5)];
+        for quality in QUALITIES {
+            for (from, to, frames) in cases {
+                let mut resampler = StreamResampler::new(from, to, 2, quality).unwrap();
+                let output = run(&mut resampler, &vec![0.25; frames * 2], 333);
+                let expected = (frames as f64 * to as f64 / from as f64).round() as usize;
+                assert_eq!(output.len(), expected * 2, "{quality:?} {from} -> {to}");
+            }
+        }
+    }
+
+    #[test]
+    fn output_lines_up_with_the_input() {
+        for quality in QUALITIES {
+            let mut resampler = StreamResampler::new(44100, 48000, 2, quality).unwrap();
+            let output = run(&mut resampler, &click(8000, 3000), 1000);
+            let expected = (3000.0 * 48000.0 / 44100.0) as usize;
+            let found = loudest_frame(&output, 0);
+            assert!(found.abs_diff(expected) <= 1, "{quality:?}: {found} vs {expected}");
+            assert!(output.iter().skip(1).step_by(2).all(|s| s.abs() < 1e-3));
+        }
+    }
+
+    #[test]
+    fn packet_size_does_not_change_the_output() {
+        let input: Vec<f32> = (0..20000).map(|i| ((i as f32) * 0.01).sin()).collect();
+        let mut resampler = StreamResampler::new(44100, 48000, 2, ResampleQuality::High).unwrap();
+        let whole = run(&mut resampler, &input, 10000);
+        let split = run(&mut resampler, &input, 37);
+        assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn reset_starts_a_fresh_stream() {
+        let mut resampler = StreamResampler::new(44100, 48000, 2, ResampleQuality::Fast).unwrap();
+        resampler.process(&click(5000, 10)).unwrap();
+        resampler.reset();
+        let output = run(&mut resampler, &click(4000, 2000), 512);
+        assert_eq!(output.len(), (4000.0f64 * 48000.0 / 44100.0).round() as usize * 2);
+        assert!(loudest_frame(&output, 0).abs_diff(2176) <= 1);
+    }
+}
@@ -0,0 +1,228 @@
+use crate::{
+    cache::{FileStamp, SharedCache},
+    metadata::TrackMetadata,
+};
+use rayon::prelude::*;
+use std::{
+    collections::HashSet,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc,
+    },
+    thread,
+};
+use walkdir::WalkDir;
+
+/// Whether `path` has one of `extensions` (lowercase, without the dot).
+pub fn is_supported(path: &Path, extensions: &[String]) -> bool {
+    path.extension()
+        .and_then(|s| s.to_str())
+        .is_some_and(|extension| extensions.contains(&extension.to_lowercase()))
+}
+
+// Files handed to the parser pool at a time; cancellation is checked between batches
+const BATCH_SIZE: usize = 256;
+
+/// Counters updated by the scan thread while it runs.
+#[derive(Debug, Default)]
+struct ScanProgress {
+    seen: AtomicUsize,
+    cached: AtomicUsize,
+    parsed: AtomicUsize,
+    failed: AtomicUsize,
+    walking: AtomicBool,
+}
+
+/// A snapshot of the scan progress.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ScanCounts {
+    /// Music files found so far.
+    pub seen: usize,
+    /// Files whose tags came from the cache.
+    pub cached: usize,
+    pub parsed: usize,
+    pub failed: usize,
+    /// Still walking the directories, so `seen` keeps growing.
+    pub walking: bool,
+}
+
+impl ScanCounts {
+    pub fn done(&self) -> usize {
+        self.cached + self.parsed + self.failed
+    }
+
+    pub fn ratio(&self) -> f64 {
+        if self.seen == 0 {
+            0.0
+        } else {
+            (self.done() as f64 / self.seen as f64).min(1.0)
+        }
+    }
+}
+
+pub enum ScanUpdate {
+    /// Tracks found or re-read since the last update.
+    Tracks(Vec<TrackMetadata>),
+    /// The scan completed. `found` holds every track path under the roots, so anything
+    /// else in the library was deleted.
+    Finished { found: HashSet<String> },
+    Cancelled,
+    Failed(String),
+}
+
+/// A library scan running on a background thread. Dropping it cancels the scan.
+pub struct LibraryScan {
+    updates: Receiver<ScanUpdate>,
+    progress: Arc<ScanProgress>,
+    cancel: Arc<AtomicBool>,
+}
+
+impl LibraryScan {
+    pub fn start(roots: Vec<PathBuf>, extensions: Vec<String>, cache: SharedCache) -> Self {
+        let (tx, updates) = mpsc::channel();
+        let progress = Arc::new(ScanProgress::default());
+        let cancel = Arc::new(AtomicBool::new(false));
+        progress.walking.store(true, Ordering::Relaxed);
+
+        let thread_progress = Arc::clone(&progress);
+        let thread_cancel = Arc::clone(&cancel);
+        thread::spawn(move || {
+            run_scan(roots, &extensions, &cache, &thread_progress, &thread_cancel, &tx)
+        });
+
+        Self {
+            updates,
+            progress,
+            cancel,
+        }
+    }
+
+    pub fn progress(&self) -> ScanCounts {
+        ScanCounts {
+            seen: self.progress.seen.load(Ordering::Relaxed),
+            cached: self.progress.cached.load(Ordering::Relaxed),
+            parsed: self.progress.parsed.load(Ordering::Relaxed),
+            failed: self.progress.failed.load(Ordering::Relaxed),
+            walking: self.progress.walking.load(Ordering::Relaxed),
+        }
+    }
+
+    pub fn cancel(&self) {
+        self.cancel.store(true, Ordering::Relaxed);
+    }
+
+    /// Everything the scan thread has sent since the last call.
+    pub fn poll_updates(&self) -> Vec<ScanUpdate> {
+        self.updates.try_iter().collect()
+    }
+}
+
+impl Drop for LibraryScan {
+    fn drop(&mut self) {
+        self.cancel();
+    }
+}
+
+fn run_scan(
+    roots: Vec<PathBuf>,
+    extensions: &[String],
+    cache: &SharedCache,
+    progress: &ScanProgress,
+    cancel: &AtomicBool,
+    tx: &Sender<ScanUpdate>,
+) {
+    let mut found: HashSet<String> = HashSet::new();
+    let mut to_parse: Vec<(PathBuf, FileStamp)> = Vec::new();
+    let mut batch: Vec<TrackMetadata> = Vec::new();
+
+    // Walk first, handing out cached tracks straight away
+    for entry in roots
+        .iter()
+        .flat_map(WalkDir::new)
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+    {
+        if cancel.load(Ordering::Relaxed) {
+            let _ = tx.send(ScanUpdate::Cancelled);
+            return;
+        }
+
+        let file_path = entry.path();
+        if !is_supported(file_path, extensions) {
+            continue;
+        }
+        let Ok(file_metadata) = entry.metadata() else {
+            continue;
+        };
+
+        let key = file_path.to_string_lossy().to_string();
+        let stamp = FileStamp::from_metadata(&file_metadata);
+        progress.seen.fetch_add(1, Ordering::Relaxed);
+
+        // Locked per file, so the watcher and the tag editor aren't held up by the walk
+        let cached = cache.lock().unwrap().get(&key, stamp).cloned();
+        if let Some(metadata) = cached {
+            batch.push(metadata);
+            progress.cached.fetch_add(1, Ordering::Relaxed);
+            if batch.len() >= BATCH_SIZE {
+                let _ = tx.send(ScanUpdate::Tracks(std::mem::take(&mut batch)));
+            }
+        } else {
+            to_parse.push((file_path.to_path_buf(), stamp));
+        }
+        found.insert(key);
+    }
+    if !batch.is_empty() {
+        let _ = tx.send(ScanUpdate::Tracks(std::mem::take(&mut batch)));
+    }
+    progress.walking.store(false, Ordering::Relaxed);
+
+    // Then read the tags of new and changed files in parallel
+    let mut cancelled = false;
+    for chunk in to_parse.chunks(BATCH_SIZE) {
+        if cancel.load(Ordering::Relaxed) {
+            cancelled = true;
+            break;
+        }
+
+        let results: Vec<_> = chunk
+            .par_iter()
+            .map(|(path, stamp)| (path, *stamp, TrackMetadata::from_file(path)))
+            .collect();
+
+        let mut cache = cache.lock().unwrap();
+        for (path, stamp, result) in results {
+            match result {
+                Ok(metadata) => {
+                    batch.push(cache.insert(stamp, metadata).clone());
+                    progress.parsed.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(_) => {
+                    // A file that stopped parsing must not linger in the library
+                    found.remove(path.to_string_lossy().as_ref());
+                    progress.failed.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        drop(cache);
+        let _ = tx.send(ScanUpdate::Tracks(std::mem::take(&mut batch)));
+    }
+
+    let mut cache = cache.lock().unwrap();
+    if cancelled {
+        // Keep what was parsed, but don't prune anything on a partial scan
+        let _ = cache.save();
+        let _ = tx.send(ScanUpdate::Cancelled);
+        return;
+    }
+
+    // Forget files that were deleted, no longer parse, or belong to a removed root
+    cache.retain(|file| found.contains(file));
+    let update = match cache.save() {
+        Ok(()) => ScanUpdate::Finished { found },
+        Err(err) => ScanUpdate::Failed(err.to_string()),
+    };
+    let _ = tx.send(update);
+}
\ No newline at end of file
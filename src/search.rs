@@ -0,0 +1,285 @@
+use crate::metadata::TrackMetadata;
+use ratatui::widgets::ListState;
+use std::cmp::Reverse;
+
+// More than fits on screen; the rest is found by typing more
+const MAX_RESULTS: usize = 200;
+
+const SCORE_MATCH: i64 = 16;
+const BONUS_WORD_START: i64 = 10;
+const BONUS_CONSECUTIVE: i64 = 8;
+const BONUS_FIRST_CHAR: i64 = 6;
+const PENALTY_GAP_START: i64 = 3;
+const PENALTY_GAP: i64 = 1;
+
+/// Matches the characters of `needle` in order anywhere in `haystack`, ignoring case.
+/// Returns a score (higher is better) and the char positions in `haystack` that matched.
+pub fn fuzzy_match(needle: &str, haystack: &str) -> Option<(i64, Vec<usize>)> {
+    let needle: Vec<char> = needle.chars().map(fold_case).collect();
+    let haystack: Vec<char> = haystack.chars().collect();
+    if needle.is_empty() {
+        return Some((0, Vec::new()));
+    }
+
+    // Find where the first greedy match ends...
+    let mut end = None;
+    let mut next = 0;
+    for (i, c) in haystack.iter().enumerate() {
+        if fold_case(*c) == needle[next] {
+            next += 1;
+            if next == needle.len() {
+                end = Some(i);
+                break;
+            }
+        }
+    }
+    let end = end?;
+
+    // ...then walk back from there to find the tightest window, so "ab" in "a-x-ab"
+    // matches the "ab" at the end instead of the first "a"
+    let mut start = end;
+    let mut remaining = needle.len();
+    for i in (0..=end).rev() {
+        if fold_case(haystack[i]) == needle[remaining - 1] {
+            remaining -= 1;
+            if remaining == 0 {
+                start = i;
+                break;
+            }
+        }
+    }
+
+    let mut positions = Vec::with_capacity(needle.len());
+    let mut next = 0;
+    for (i, c) in haystack.iter().enumerate().take(end + 1).skip(start) {
+        if next < needle.len() && fold_case(*c) == needle[next] {
+            positions.push(i);
+            next += 1;
+        }
+    }
+
+    Some((score(&haystack, &positions), positions))
+}
+
+/// Lowercases `c` and drops the accent of common Latin letters, so "bjork" finds "Björk".
+fn fold_case(c: char) -> char {
+    match c.to_lowercase().next().unwrap_or(c) {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' => 'a',
+        'ç' | 'ć' | 'č' => 'c',
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => 'e',
+        'ì' | 'í' | 'î' | 'ï' | 'ī' => 'i',
+        'ñ' | 'ń' | 'ň' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => 'o',
+        'ś' | 'š' | 'ş' => 's',
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'ź' | 'ż' | 'ž' => 'z',
+        'ł' => 'l',
+        other => other,
+    }
+}
+
+fn score(haystack: &[char], positions: &[usize]) -> i64 {
+    let mut score = 0;
+    let mut previous: Option<usize> = None;
+    for &i in positions {
+        score += SCORE_MATCH;
+        if i == 0 {
+            score += BONUS_FIRST_CHAR;
+        }
+        let word_start = i == 0 || !haystack[i - 1].is_alphanumeric();
+        if word_start {
+            score += BONUS_WORD_START;
+        }
+        match previous {
+            Some(p) if p + 1 == i => score += BONUS_CONSECUTIVE,
+            Some(p) => score -= PENALTY_GAP_START + PENALTY_GAP * (i - p - 2) as i64,
+            None => {}
+        }
+        previous = Some(i);
+    }
+    score
+}
+
+/// A track matching the search, with the matched characters of each field.
+pub struct SearchHit {
+    pub track: TrackMetadata,
+    pub title_matches: Vec<usize>,
+    pub artist_matches: Vec<usize>,
+    pub album_matches: Vec<usize>,
+}
+
+/// The matched characters of each field, before the track itself is copied.
+#[derive(Default)]
+struct FieldMatches {
+    title: Vec<usize>,
+    artist: Vec<usize>,
+    album: Vec<usize>,
+}
+
+/// Matches every word of `query` against the title, artist or album of `track`.
+fn match_track(words: &[&str], track: &TrackMetadata) -> Option<(i64, FieldMatches)> {
+    let mut score = 0;
+    let mut found = FieldMatches::default();
+
+    for word in words {
+        // A word counts for whichever field it matches best
+        let fields = [
+            (&track.title, &mut found.title),
+            (&track.artist, &mut found.artist),
+            (&track.album, &mut found.album),
+        ];
+        let (word_score, positions, matches) = fields
+            .into_iter()
+            .filter_map(|(text, matches)| {
+                fuzzy_match(word, text).map(|(score, positions)| (score, positions, matches))
+            })
+            .max_by_key(|(score, _, _)| *score)?;
+        score += word_score;
+        matches.extend(positions);
+    }
+    Some((score, found))
+}
+
+/// The `/` search popup: the query being typed and the ranked results.
+pub struct Search {
+    pub query: String,
+    pub hits: Vec<SearchHit>,
+    pub list_state: ListState,
+    // Shown at the bottom of the popup, e.g. what was just enqueued
+    pub message: Option<String>,
+}
+
+impl Search {
+    pub fn new() -> Self {
+        Self {
+            query: String::new(),
+            hits: Vec::new(),
+            list_state: ListState::default(),
+            message: None,
+        }
+    }
+
+    /// Reruns the query over `tracks`, best matches first.
+    pub fn update(&mut self, tracks: &[TrackMetadata]) {
+        let words: Vec<&str> = self.query.split_whitespace().collect();
+        let mut matched: Vec<(usize, i64, FieldMatches)> = if words.is_empty() {
+            Vec::new()
+        } else {
+            tracks
+                .iter()
+                .enumerate()
+                .filter_map(|(i, track)| {
+                    match_track(&words, track).map(|(score, found)| (i, score, found))
+                })
+                .collect()
+        };
+
+        // Shorter titles first on a tie, as more of them matched
+        matched.sort_by(|(a, a_score, _), (b, b_score, _)| {
+            let (a, b) = (&tracks[*a], &tracks[*b]);
+            (Reverse(a_score), a.title.len(), &a.file_path).cmp(&(
+                Reverse(b_score),
+                b.title.len(),
+                &b.file_path,
+            ))
+        });
+        matched.truncate(MAX_RESULTS);
+
+        // Only the tracks that are shown are copied
+        self.hits = matched
+            .into_iter()
+            .map(|(i, _, found)| SearchHit {
+                track: tracks[i].clone(),
+                title_matches: found.title,
+                artist_matches: found.artist,
+                album_matches: found.album,
+            })
+            .collect();
+        self.list_state
+            .select((!self.hits.is_empty()).then_some(0));
+    }
+
+    pub fn selected(&self) -> Option<&SearchHit> {
+        self.list_state.selected().and_then(|i| self.hits.get(i))
+    }
+
+    pub fn move_up(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state.select(Some(i.saturating_sub(1)));
+        }
+    }
+
+    pub fn move_down(&mut self) {
+        if let Some(i) = self.list_state.selected() {
+            self.list_state
+                .select(Some((i + 1).min(self.hits.len().saturating_sub(1))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn track(title: &str, artist: &str, path: &str) -> TrackMetadata {
+        serde_json::from_value(serde_json::json!({
+            "title": title,
+            "artist": artist,
+            "album": "Album",
+            "file_path": path,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn matches_in_order_ignoring_case_and_accents() {
+        assert_eq!(fuzzy_match("bjork", "Björk").map(|(_, p)| p), Some(vec![0, 1, 2, 3, 4]));
+        assert_eq!(fuzzy_match("HLO", "hello").map(|(_, p)| p), Some(vec![0, 2, 4]));
+        assert!(fuzzy_match("olh", "hello").is_none());
+        assert_eq!(fuzzy_match("", "anything"), Some((0, Vec::new())));
+    }
+
+    #[test]
+    fn picks_the_tightest_window() {
+        let (_, positions) = fuzzy_match("ab", "a-x-ab").unwrap();
+        assert_eq!(positions, [4, 5]);
+    }
+
+    #[test]
+    fn prefers_word_starts_and_runs() {
+        let score = |haystack| fuzzy_match("ts", haystack).unwrap().0;
+        assert!(score("The Sun") > score("artists"));
+        assert!(score("tsunami") > score("t-x-s"));
+    }
+
+    #[test]
+    fn every_word_must_match_some_field() {
+        let tracks = [
+            track("Yellow", "Coldplay", "a"),
+            track("Yellow Submarine", "The Beatles", "b"),
+            track("Help", "The Beatles", "c"),
+        ];
+        let mut search = Search::new();
+        search.query = "yellow beat".to_string();
+        search.update(&tracks);
+        let found: Vec<_> = search.hits.iter().map(|hit| hit.track.file_path.as_str()).collect();
+        assert_eq!(found, ["b"]);
+        assert_eq!(search.hits[0].title_matches.len(), 6);
+        assert_eq!(search.hits[0].artist_matches.len(), 4);
+    }
+
+    #[test]
+    fn results_are_ranked_and_capped() {
+        let mut tracks: Vec<_> = (0..MAX_RESULTS + 50)
+            .map(|i| track(&format!("song number {i}"), "Artist", &format!("{i:04}")))
+            .collect();
+        tracks.push(track("Song", "Artist", "best"));
+        let mut search = Search::new();
+        search.query = "song".to_string();
+        search.update(&tracks);
+        assert_eq!(search.hits.len(), MAX_RESULTS);
+        assert_eq!(search.hits[0].track.file_path, "best");
+        assert_eq!(search.selected().map(|hit| hit.track.title.as_str()), Some("Song"));
+    }
+}
@@ -0,0 +1,914 @@
+use anyhow::Result;
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    Device, FromSample, SampleFormat, SampleRate, SizedSample, Stream, StreamConfig,
+    SupportedStreamConfigRange,
+};
+use ringbuf::{
+    traits::{Consumer, Observer, Producer},
+    HeapCons, HeapProd,
+};
+use std::{
+    fmt,
+    fs::File,
+    io::BufWriter,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        mpsc, Arc,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SinkConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+    /// The output carries the source's samples untouched: no resampling, remixing or
+    /// volume.
+    pub bit_perfect: bool,
+}
+
+impl SinkConfig {
+    /// Float output at `sample_rate`, for the outputs that accept anything.
+    pub fn float(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels,
+            sample_format: SampleFormat::F32,
+            bit_perfect: false,
+        }
+    }
+
+    /// E.g. "44.1 kHz, 2 ch, i16, bit-perfect".
+    pub fn describe(&self) -> String {
+        let mut text = format!(
+            "{} kHz, {} ch, {}",
+            self.sample_rate as f64 / 1000.0,
+            self.channels,
+            self.sample_format
+        );
+        if self.bit_perfect {
+            text.push_str(", bit-perfect");
+        }
+        text
+    }
+}
+
+/// Names an output device between runs. cpal has no stable ids, so the host and the
+/// device's name stand in for one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceId {
+    /// E.g. "ALSA" or "WASAPI".
+    pub host: String,
+    pub name: String,
+}
+
+impl DeviceId {
+    pub fn on_default_host(name: String) -> Self {
+        Self {
+            host: cpal::default_host().id().name().to_string(),
+            name,
+        }
+    }
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.host, self.name)
+    }
+}
+
+/// An output device and the formats it supports, for the device picker.
+pub struct OutputDevice {
+    pub id: DeviceId,
+    /// The host's default output.
+    pub is_default: bool,
+    pub configs: Vec<SupportedStreamConfigRange>,
+}
+
+/// Every output device of every host cpal can reach on this system. Hosts that fail
+/// to start are skipped, and a device that can't report its formats is listed without.
+pub fn list_output_devices() -> Vec<OutputDevice> {
+    let mut devices = Vec::new();
+    for host_id in cpal::available_hosts() {
+        let Ok(host) = cpal::host_from_id(host_id) else {
+            continue;
+        };
+        let Ok(outputs) = host.output_devices() else {
+            continue;
+        };
+        let default_name = host.default_output_device().and_then(|device| device.name().ok());
+
+        for device in outputs {
+            let Ok(name) = device.name() else {
+                continue;
+            };
+            let configs = device
+                .supported_output_configs()
+                .map(|configs| configs.collect())
+                .unwrap_or_default();
+            devices.push(OutputDevice {
+                is_default: default_name.as_deref() == Some(name.as_str()),
+                id: DeviceId {
+                    host: host_id.name().to_string(),
+                    name,
+                },
+                configs,
+            });
+        }
+    }
+    devices
+}
+
+/// E.g. "2 ch, 44.1-192 kHz, i32".
+pub fn describe_range(range: &SupportedStreamConfigRange) -> String {
+    let khz = |rate: SampleRate| rate.0 as f64 / 1000.0;
+    let (min, max) = (range.min_sample_rate(), range.max_sample_rate());
+    let rates = if min == max {
+        format!("{}", khz(min))
+    } else {
+        format!("{}-{}", khz(min), khz(max))
+    };
+    format!("{} ch, {rates} kHz, {}", range.channels(), range.sample_format())
+}
+
+/// Looks a device up again by host and name, e.g. to check it's still plugged in.
+fn find_device(id: &DeviceId) -> Result<Device> {
+    let host_id = cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name() == id.host)
+        .ok_or_else(|| anyhow::anyhow!("Audio host {} is not available", id.host))?;
+    cpal::host_from_id(host_id)?
+        .output_devices()?
+        .find(|device| device.name().is_ok_and(|name| name == id.name))
+        .ok_or_else(|| anyhow::anyhow!("Output device {id} not found"))
+}
+
+/// What the player would like the output to run at for a track.
+#[derive(Debug, Clone, Copy)]
+pub struct FormatRequest {
+    /// The source's own rate, used whenever the output supports it.
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Bits per sample of lossless sources; `None` for lossy ones.
+    pub bits_per_sample: Option<u32>,
+    /// Rate to resample to when the source's own rate isn't supported.
+    pub fallback_rate: u32,
+    pub bit_perfect: bool,
+}
+
+/// Picks the config closest to `request` from what an output supports.
+///
+/// Bit-perfect output needs the source's rate and channel count and a sample format
+/// that holds its samples exactly. Otherwise the source's rate is preferred, then the
+/// fallback rate, then `default`; within a rate, the source's channel count, then
+/// stereo, and float samples.
+pub fn choose_config(
+    supported: &[SupportedStreamConfigRange],
+    default: Option<SinkConfig>,
+    request: &FormatRequest,
+) -> Option<SinkConfig> {
+    let supports_rate = |range: &SupportedStreamConfigRange, rate: u32| {
+        (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&rate)
+    };
+    let config = |range: &SupportedStreamConfigRange, sample_rate: u32, bit_perfect: bool| {
+        SinkConfig {
+            sample_rate,
+            channels: range.channels(),
+            sample_format: range.sample_format(),
+            bit_perfect,
+        }
+    };
+
+    if request.bit_perfect {
+        let exact = supported
+            .iter()
+            .filter(|range| {
+                supports_rate(range, request.sample_rate)
+                    && range.channels() == request.channels
+            })
+            .filter_map(|range| {
+                exact_format_rank(range.sample_format(), request.bits_per_sample)
+                    .map(|rank| (rank, range))
+            })
+            .min_by_key(|(rank, _)| *rank);
+        if let Some((_, range)) = exact {
+            return Some(config(range, request.sample_rate, true));
+        }
+    }
+
+    // Anything but the source's own layout or stereo is left to the mixer
+    let channel_rank = |channels: u16| match channels {
+        c if c == request.channels => 0,
+        2 => 1,
+        _ => 2,
+    };
+    for rate in [request.sample_rate, request.fallback_rate] {
+        let best = supported
+            .iter()
+            .filter(|range| supports_rate(range, rate))
+            .min_by_key(|range| {
+                (
+                    channel_rank(range.channels()),
+                    format_rank(range.sample_format()),
+                )
+            });
+        if let Some(range) = best {
+            return Some(config(range, rate, false));
+        }
+    }
+    default
+}
+
+/// Order of preference for sample formats, since everything is mixed as f32.
+fn format_rank(format: SampleFormat) -> u8 {
+    match format {
+        SampleFormat::F32 => 0,
+        SampleFormat::I32 | SampleFormat::F64 => 1,
+        SampleFormat::I16 => 2,
+        _ => 3,
+    }
+}
+
+/// For bit-perfect output: whether `format` holds the source's samples exactly after
+/// passing through f32, preferring the narrowest format that does.
+fn exact_format_rank(format: SampleFormat, bits_per_sample: Option<u32>) -> Option<u8> {
+    // f32 has a 24-bit mantissa; lossy sources decode straight to float
+    let bits = bits_per_sample.unwrap_or(24);
+    match format {
+        SampleFormat::I16 if bits <= 16 => Some(0),
+        SampleFormat::I32 if bits <= 24 => Some(1),
+        SampleFormat::F32 if bits <= 24 => Some(2),
+        SampleFormat::F64 if bits <= 24 => Some(3),
+        _ => None,
+    }
+}
+
+// Time constant of the volume ramp, long enough to avoid zipper noise on changes
+const VOLUME_SMOOTHING_SECS: f32 = 0.01;
+// About -120 dB, well below anything audible
+const GAIN_SNAP: f32 = 1e-6;
+
+/// State shared between the player and whichever sink is draining the playback buffer.
+pub struct OutputControl {
+    pub is_playing: AtomicBool,
+    flush_requested: AtomicBool,
+    frames_played: AtomicU64,
+    // Set by the output stream when it reports an error, e.g. because the device is gone
+    stream_error: AtomicBool,
+    // f32 bits, so the output callback can read it without locking
+    volume: AtomicU32,
+    muted: AtomicBool,
+}
+
+impl OutputControl {
+    pub fn new(volume: f32) -> Self {
+        Self {
+            is_playing: AtomicBool::new(false),
+            flush_requested: AtomicBool::new(false),
+            frames_played: AtomicU64::new(0),
+            stream_error: AtomicBool::new(false),
+            volume: AtomicU32::new(volume.clamp(0.0, 1.0).to_bits()),
+            muted: AtomicBool::new(false),
+        }
+    }
+
+    /// Asks the sink to drop everything buffered and waits until it has done so.
+    pub fn flush(&self) {
+        self.flush_requested.store(true, Ordering::Release);
+
+        // Don't hang forever if the sink has stopped pulling
+        let deadline = Instant::now() + Duration::from_millis(500);
+        while self.flush_requested.load(Ordering::Acquire) && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Total frames handed to the output since the player started.
+    pub fn frames_played(&self) -> u64 {
+        self.frames_played.load(Ordering::Acquire)
+    }
+
+    /// Whether the output stream reported an error since the last call.
+    pub fn take_stream_error(&self) -> bool {
+        self.stream_error.swap(false, Ordering::AcqRel)
+    }
+
+    pub fn volume(&self) -> f32 {
+        f32::from_bits(self.volume.load(Ordering::Relaxed))
+    }
+
+    pub fn set_volume(&self, volume: f32) {
+        self.volume
+            .store(volume.clamp(0.0, 1.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.load(Ordering::Relaxed)
+    }
+
+    pub fn set_muted(&self, muted: bool) {
+        self.muted.store(muted, Ordering::Relaxed);
+    }
+
+    fn target_gain(&self) -> f32 {
+        if self.is_muted() {
+            0.0
+        } else {
+            self.volume()
+        }
+    }
+}
+
+/// The consuming end of the playback buffer, handed to a sink when it starts.
+pub struct OutputSource {
+    consumer: HeapCons<f32>,
+    visualizer_tap: HeapProd<f32>,
+    control: Arc<OutputControl>,
+    channels: usize,
+    bit_perfect: bool,
+    gain: f32,
+    // Per-frame step of the one-pole volume ramp
+    gain_smoothing: f32,
+}
+
+impl OutputSource {
+    pub fn new(
+        consumer: HeapCons<f32>,
+        visualizer_tap: HeapProd<f32>,
+        control: Arc<OutputControl>,
+        config: SinkConfig,
+    ) -> Self {
+        let gain_smoothing =
+            1.0 - (-1.0 / (VOLUME_SMOOTHING_SECS * config.sample_rate as f32)).exp();
+        let mut source = Self {
+            consumer,
+            visualizer_tap,
+            gain: 0.0,
+            control,
+            channels: config.channels as usize,
+            bit_perfect: config.bit_perfect,
+            gain_smoothing,
+        };
+        source.gain = source.target_gain();
+        source
+    }
+
+    /// Bit-perfect output ignores the volume, though muting still works.
+    fn target_gain(&self) -> f32 {
+        if self.bit_perfect && !self.control.is_muted() {
+            1.0
+        } else {
+            self.control.target_gain()
+        }
+    }
+
+    /// Fills `out` completely, padding with silence while paused or on underrun.
+    /// Returns how many buffered samples were used.
+    pub fn fill(&mut self, out: &mut [f32]) -> usize {
+        let read = self.read(out);
+        out[read..].fill(0.0);
+        read
+    }
+
+    /// Reads only the samples that are actually buffered, for sinks that are not clocked.
+    pub fn read(&mut self, out: &mut [f32]) -> usize {
+        if self.control.flush_requested.load(Ordering::Acquire) {
+            self.consumer.clear();
+            self.control.flush_requested.store(false, Ordering::Release);
+        }
+
+        // Keep the buffered audio while paused
+        if !self.control.is_playing.load(Ordering::Relaxed) {
+            return 0;
+        }
+
+        // Only whole frames, so an underrun cannot swap the channels
+        let available = self.consumer.occupied_len().min(out.len());
+        let read = self
+            .consumer
+            .pop_slice(&mut out[..available - available % self.channels]);
+        let target_gain = self.target_gain();
+        for frame in out[..read].chunks_mut(self.channels) {
+            // The visualizer sees the signal before volume, so it keeps moving when muted
+            let _ = self
+                .visualizer_tap
+                .try_push(frame.iter().sum::<f32>() / self.channels as f32);
+
+            self.gain += (target_gain - self.gain) * self.gain_smoothing;
+            // Land on the target exactly, or the ramp would stall a rounding error short
+            if (target_gain - self.gain).abs() < GAIN_SNAP {
+                self.gain = target_gain;
+            }
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+        self.control
+            .frames_played
+            .fetch_add((read / self.channels) as u64, Ordering::AcqRel);
+        read
+    }
+}
+
+/// Where the player sends its interleaved f32 output.
+pub trait AudioSink: Send {
+    fn name(&self) -> String;
+
+    /// The config of the running stream, or the one it will start with.
+    fn config(&self) -> SinkConfig;
+
+    /// The config this output would run at for `request`.
+    fn negotiate(&self, request: &FormatRequest) -> SinkConfig;
+
+    /// Starts pulling samples from `source` at `config`, replacing any stream that is
+    /// already running. The sink stops when dropped.
+    fn start(&mut self, config: SinkConfig, source: OutputSource) -> Result<()>;
+
+    /// The sound device behind this output, if there is one.
+    fn device(&self) -> Option<DeviceId> {
+        None
+    }
+
+    /// Whether the output can still play, checked after its stream reports an error.
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+/// Plays through a cpal output device.
+pub struct CpalSink {
+    device: Device,
+    id: DeviceId,
+    supported: Vec<SupportedStreamConfigRange>,
+    default_config: Option<SinkConfig>,
+    config: SinkConfig,
+    stream: Option<StreamGuard>,
+}
+
+impl CpalSink {
+    /// Opens `device`, or the default one for `None`, initially set up for stereo at
+    /// `sample_rate`.
+    pub fn new(device: Option<&DeviceId>, sample_rate: u32) -> Result<Self> {
+        let (device, id) = match device {
+            Some(id) => (find_device(id)?, id.clone()),
+            None => {
+                let device = cpal::default_host()
+                    .default_output_device()
+                    .ok_or_else(|| anyhow::anyhow!("No output device available"))?;
+                let name = device.name().unwrap_or_else(|_| "Unknown device".to_string());
+                (device, DeviceId::on_default_host(name))
+            }
+        };
+
+        let supported: Vec<_> = device.supported_output_configs()?.collect();
+        let default_config = device.default_output_config().ok().map(|config| SinkConfig {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+            sample_format: config.sample_format(),
+            bit_perfect: false,
+        });
+        let request = FormatRequest {
+            sample_rate,
+            channels: 2,
+            bits_per_sample: None,
+            fallback_rate: sample_rate,
+            bit_perfect: false,
+        };
+        let config = choose_config(&supported, default_config, &request)
+            .ok_or_else(|| anyhow::anyhow!("No supported configs"))?;
+
+        Ok(Self {
+            device,
+            id,
+            supported,
+            default_config,
+            config,
+            stream: None,
+        })
+    }
+}
+
+impl AudioSink for CpalSink {
+    fn name(&self) -> String {
+        self.id.name.clone()
+    }
+
+    fn config(&self) -> SinkConfig {
+        self.config
+    }
+
+    fn negotiate(&self, request: &FormatRequest) -> SinkConfig {
+        choose_config(&self.supported, self.default_config, request).unwrap_or(self.config)
+    }
+
+    fn start(&mut self, config: SinkConfig, source: OutputSource) -> Result<()> {
+        // Close the old stream first; some devices only allow one at a time
+        self.stream = None;
+        let stream_config = StreamConfig {
+            channels: config.channels,
+            sample_rate: SampleRate(config.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        };
+        self.stream = Some(spawn_output_thread(
+            self.device.clone(),
+            stream_config,
+            config.sample_format,
+            source,
+        )?);
+        self.config = config;
+        Ok(())
+    }
+
+    fn device(&self) -> Option<DeviceId> {
+        Some(self.id.clone())
+    }
+
+    fn is_available(&self) -> bool {
+        find_device(&self.id).is_ok()
+    }
+}
+
+/// Keeps an output stream alive. Dropping it closes the stream and waits until it's gone.
+struct StreamGuard {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.stop = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Builds and starts the output stream on its own thread, since cpal streams are not `Send`.
+fn spawn_output_thread(
+    device: Device,
+    config: StreamConfig,
+    sample_format: SampleFormat,
+    source: OutputSource,
+) -> Result<StreamGuard> {
+    let (ready_tx, ready_rx) = mpsc::channel::<Result<()>>();
+    let (guard_tx, guard_rx) = mpsc::channel::<()>();
+
+    let thread = thread::spawn(move || {
+        let stream = match sample_format {
+            SampleFormat::F32 => build_output_stream::<f32>(&device, &config, source),
+            SampleFormat::F64 => build_output_stream::<f64>(&device, &config, source),
+            SampleFormat::I8 => build_output_stream::<i8>(&device, &config, source),
+            SampleFormat::I16 => build_output_stream::<i16>(&device, &config, source),
+            SampleFormat::I32 => build_output_stream::<i32>(&device, &config, source),
+            SampleFormat::U8 => build_output_stream::<u8>(&device, &config, source),
+            SampleFormat::U16 => build_output_stream::<u16>(&device, &config, source),
+            SampleFormat::U32 => build_output_stream::<u32>(&device, &config, source),
+            other => Err(anyhow::anyhow!("Unsupported sample format: {other}")),
+        };
+
+        let stream = match stream.and_then(|stream| Ok(stream.play().map(|_| stream)?)) {
+            Ok(stream) => stream,
+            Err(err) => {
+                let _ = ready_tx.send(Err(err));
+                return;
+            }
+        };
+        let _ = ready_tx.send(Ok(()));
+
+        // Blocks until the guard is dropped
+        let _ = guard_rx.recv();
+        drop(stream);
+    });
+
+    ready_rx
+        .recv()
+        .map_err(|_| anyhow::anyhow!("Output thread exited unexpectedly"))??;
+
+    Ok(StreamGuard {
+        stop: Some(guard_tx),
+        thread: Some(thread),
+    })
+}
+
+fn build_output_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut source: OutputSource,
+) -> Result<Stream>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let mut scratch: Vec<f32> = Vec::new();
+    let control = Arc::clone(&source.control);
+
+    let stream = device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            if scratch.len() < data.len() {
+                scratch.resize(data.len(), 0.0);
+            }
+            let scratch = &mut scratch[..data.len()];
+            source.fill(scratch);
+
+            for (out, &sample) in data.iter_mut().zip(scratch.iter()) {
+                *out = T::from_sample(sample);
+            }
+        },
+        // Stream errors have nowhere to go while the TUI owns the terminal; the player
+        // only checks whether the device is still there
+        move |_err| control.stream_error.store(true, Ordering::Release),
+        None,
+    )?;
+
+    Ok(stream)
+}
+
+/// Writes everything that gets played to a 32-bit float WAV file.
+pub struct WavSink {
+    config: SinkConfig,
+    path: String,
+    writer: Option<hound::WavWriter<BufWriter<File>>>,
+    worker: Option<SinkWorker>,
+    // Set when a write fails, e.g. on a full disk
+    failed: Arc<AtomicBool>,
+}
+
+impl WavSink {
+    pub fn new<P: AsRef<Path>>(path: P, config: SinkConfig) -> Result<Self> {
+        let path = path.as_ref();
+        let spec = hound::WavSpec {
+            channels: config.channels,
+            sample_rate: config.sample_rate,
+            bits_per_sample: 32,
+            sample_format: hound::SampleFormat::Float,
+        };
+        let writer = hound::WavWriter::create(path, spec)?;
+
+        Ok(Self {
+            config,
+            path: path.to_string_lossy().to_string(),
+            writer: Some(writer),
+            worker: None,
+            failed: Arc::new(AtomicBool::new(false)),
+        })
+    }
+}
+
+impl AudioSink for WavSink {
+    fn name(&self) -> String {
+        format!("WAV file ({})", self.path)
+    }
+
+    fn config(&self) -> SinkConfig {
+        self.config
+    }
+
+    fn negotiate(&self, _request: &FormatRequest) -> SinkConfig {
+        // The file's format is fixed once it's created
+        self.config
+    }
+
+    fn start(&mut self, _config: SinkConfig, mut source: OutputSource) -> Result<()> {
+        let mut writer = self
+            .writer
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("WAV sink already started"))?;
+        let frames = self.config.sample_rate as usize / 10;
+        let mut buffer = vec![0.0; frames * self.config.channels as usize];
+        let failed = self.failed.clone();
+
+        self.worker = Some(SinkWorker::spawn(move |stop| {
+            // Not clocked: write decoded audio as soon as it is available
+            'write: while !stop.load(Ordering::Relaxed) {
+                let read = source.read(&mut buffer);
+                if read == 0 {
+                    thread::sleep(Duration::from_millis(10));
+                    continue;
+                }
+                for &sample in &buffer[..read] {
+                    if writer.write_sample(sample).is_err() {
+                        // Reported like a lost device, so the engine moves to another output
+                        failed.store(true, Ordering::Release);
+                        source.control.stream_error.store(true, Ordering::Release);
+                        break 'write;
+                    }
+                }
+            }
+            // Fixes up the header, so whatever was written still plays
+            if writer.finalize().is_err() {
+                failed.store(true, Ordering::Release);
+            }
+        }));
+        Ok(())
+    }
+
+    fn is_available(&self) -> bool {
+        !self.failed.load(Ordering::Acquire)
+    }
+}
+
+/// Discards audio in real time, for machines without sound hardware.
+pub struct NullSink {
+    config: SinkConfig,
+    worker: Option<SinkWorker>,
+}
+
+impl NullSink {
+    pub fn new(config: SinkConfig) -> Self {
+        Self {
+            config,
+            worker: None,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn name(&self) -> String {
+        "Null output".to_string()
+    }
+
+    fn config(&self) -> SinkConfig {
+        self.config
+    }
+
+    fn negotiate(&self, request: &FormatRequest) -> SinkConfig {
+        // Behaves like a device that supports anything
+        SinkConfig {
+            bit_perfect: request.bit_perfect,
+            ..SinkConfig::float(request.sample_rate, request.channels)
+        }
+    }
+
+    fn start(&mut self, config: SinkConfig, mut source: OutputSource) -> Result<()> {
+        self.worker = None;
+        self.config = config;
+        let channels = self.config.channels as usize;
+        let sample_rate = self.config.sample_rate as f64;
+        let mut buffer = Vec::new();
+
+        self.worker = Some(SinkWorker::spawn(move |stop| {
+            let started = Instant::now();
+            let mut frames_consumed: u64 = 0;
+
+            // Consume exactly as many frames as a real device would have played by now
+            while !stop.load(Ordering::Relaxed) {
+                thread::sleep(Duration::from_millis(10));
+                let due = (started.elapsed().as_secs_f64() * sample_rate) as u64;
+                let frames = (due - frames_consumed) as usize;
+                buffer.resize(frames * channels, 0.0);
+                source.fill(&mut buffer);
+                frames_consumed = due;
+            }
+        }));
+        Ok(())
+    }
+}
+
+/// Background thread for the sinks that are not driven by an audio callback.
+struct SinkWorker {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl SinkWorker {
+    fn spawn<F>(run: F) -> Self
+    where
+        F: FnOnce(Arc<AtomicBool>) + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        Self {
+            stop,
+            handle: Some(thread::spawn(move || run(thread_stop))),
+        }
+    }
+}
+
+impl Drop for SinkWorker {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SupportedBufferSize;
+
+    fn range(
+        channels: u16,
+        min: u32,
+        max: u32,
+        format: SampleFormat,
+    ) -> SupportedStreamConfigRange {
+        SupportedStreamConfigRange::new(
+            channels,
+            SampleRate(min),
+            SampleRate(max),
+            SupportedBufferSize::Unknown,
+            format,
+        )
+    }
+
+    fn request(sample_rate: u32, channels: u16) -> FormatRequest {
+        FormatRequest {
+            sample_rate,
+            channels,
+            bits_per_sample: Some(16),
+            fallback_rate: 48000,
+            bit_perfect: false,
+        }
+    }
+
+    fn chosen(supported: &[SupportedStreamConfigRange], request: &FormatRequest) -> SinkConfig {
+        choose_config(supported, None, request).expect("a config")
+    }
+
+    #[test]
+    fn prefers_the_source_rate_then_the_fallback() {
+        let supported = [
+            range(2, 44100, 48000, SampleFormat::I16),
+            range(2, 44100, 192000, SampleFormat::F32),
+        ];
+        let config = chosen(&supported, &request(96000, 2));
+        assert_eq!((config.sample_rate, config.sample_format), (96000, SampleFormat::F32));
+
+        let config = chosen(&supported, &request(22050, 2));
+        assert_eq!(config.sample_rate, 48000);
+        assert!(!config.bit_perfect);
+    }
+
+    #[test]
+    fn falls_back_to_the_default_config() {
+        let supported = [range(2, 48000, 48000, SampleFormat::F32)];
+        let default = SinkConfig::float(44100, 2);
+        let request = FormatRequest {
+            fallback_rate: 32000,
+            ..request(96000, 2)
+        };
+        assert_eq!(choose_config(&supported, Some(default), &request), Some(default));
+        assert_eq!(choose_config(&[], None, &request), None);
+    }
+
+    #[test]
+    fn channel_count_outranks_sample_format() {
+        let supported = [
+            range(2, 48000, 48000, SampleFormat::F32),
+            range(6, 48000, 48000, SampleFormat::I16),
+            range(8, 48000, 48000, SampleFormat::F32),
+        ];
+        assert_eq!(chosen(&supported, &request(48000, 6)).channels, 6);
+        // Anything else is mixed down to stereo rather than spread over eight outputs
+        assert_eq!(chosen(&supported, &request(48000, 1)).channels, 2);
+    }
+
+    #[test]
+    fn prefers_float_samples() {
+        let supported = [
+            range(2, 48000, 48000, SampleFormat::I16),
+            range(2, 48000, 48000, SampleFormat::I32),
+            range(2, 48000, 48000, SampleFormat::F32),
+        ];
+        assert_eq!(chosen(&supported, &request(48000, 2)).sample_format, SampleFormat::F32);
+        let without_float = chosen(&supported[..2], &request(48000, 2));
+        assert_eq!(without_float.sample_format, SampleFormat::I32);
+    }
+
+    #[test]
+    fn bit_perfect_picks_the_narrowest_exact_format() {
+        let supported = [
+            range(2, 44100, 96000, SampleFormat::F32),
+            range(2, 44100, 96000, SampleFormat::I32),
+            range(2, 44100, 96000, SampleFormat::I16),
+        ];
+        let bit_perfect = |bits| FormatRequest {
+            bits_per_sample: bits,
+            bit_perfect: true,
+            ..request(44100, 2)
+        };
+
+        let config = chosen(&supported, &bit_perfect(Some(16)));
+        assert_eq!(config.sample_format, SampleFormat::I16);
+        assert!(config.bit_perfect);
+        assert_eq!(chosen(&supported, &bit_perfect(Some(24))).sample_format, SampleFormat::I32);
+
+        // 32-bit integer samples don't survive f32, so the output isn't bit-perfect
+        let config = chosen(&supported, &bit_perfect(Some(32)));
+        assert_eq!(config.sample_format, SampleFormat::F32);
+        assert!(!config.bit_perfect);
+
+        // Nor is it when the device can't run at the source's rate
+        let too_fast = FormatRequest {
+            sample_rate: 192000,
+            ..bit_perfect(Some(16))
+        };
+        assert!(!chosen(&supported, &too_fast).bit_perfect);
+    }
+}
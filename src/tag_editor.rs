@@ -0,0 +1,349 @@
+use crate::{
+    cache::{FileStamp, SharedCache},
+    metadata::TrackMetadata,
+};
+use anyhow::{anyhow, Result};
+use lofty::{config::WriteOptions, prelude::*, tag::Tag};
+use std::fs;
+
+/// A tag the editor can change.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TagField {
+    Title,
+    Artist,
+    Album,
+    Track,
+    Disc,
+    Year,
+    Genre,
+}
+
+impl TagField {
+    pub const ALL: [TagField; 7] = [
+        TagField::Title,
+        TagField::Artist,
+        TagField::Album,
+        TagField::Track,
+        TagField::Disc,
+        TagField::Year,
+        TagField::Genre,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            TagField::Title => "Title",
+            TagField::Artist => "Artist",
+            TagField::Album => "Album",
+            TagField::Track => "Track",
+            TagField::Disc => "Disc",
+            TagField::Year => "Year",
+            TagField::Genre => "Genre",
+        }
+    }
+
+    pub fn is_number(self) -> bool {
+        matches!(self, TagField::Track | TagField::Disc | TagField::Year)
+    }
+
+    fn value(self, track: &TrackMetadata) -> Option<String> {
+        match self {
+            TagField::Title => Some(track.title.clone()),
+            TagField::Artist => Some(track.artist.clone()),
+            TagField::Album => Some(track.album.clone()),
+            TagField::Track => track.track_number.map(|n| n.to_string()),
+            TagField::Disc => track.disc_number.map(|n| n.to_string()),
+            TagField::Year => track.year.map(|n| n.to_string()),
+            TagField::Genre => track.genre.clone(),
+        }
+    }
+
+    /// Sets the field on `tag`, or removes it if `value` is empty.
+    fn apply(self, tag: &mut Tag, value: &str) -> Result<()> {
+        if value.is_empty() {
+            match self {
+                TagField::Title => tag.remove_title(),
+                TagField::Artist => tag.remove_artist(),
+                TagField::Album => tag.remove_album(),
+                TagField::Track => tag.remove_track(),
+                TagField::Disc => tag.remove_disk(),
+                TagField::Year => tag.remove_year(),
+                TagField::Genre => tag.remove_genre(),
+            }
+            return Ok(());
+        }
+
+        let number = || -> Result<u32> {
+            value
+                .parse()
+                .map_err(|_| anyhow!("{} is not a valid number", self.label()))
+        };
+        match self {
+            TagField::Title => tag.set_title(value.to_string()),
+            TagField::Artist => tag.set_artist(value.to_string()),
+            TagField::Album => tag.set_album(value.to_string()),
+            TagField::Track => tag.set_track(number()?),
+            TagField::Disc => tag.set_disk(number()?),
+            TagField::Year => tag.set_year(number()?),
+            TagField::Genre => tag.set_genre(value.to_string()),
+        }
+        Ok(())
+    }
+}
+
+/// One text box of the editor.
+pub struct FieldEdit {
+    pub field: TagField,
+    pub value: String,
+    /// The tracks had different values, so the box starts empty.
+    pub mixed: bool,
+    /// Only edited fields are written, the rest are left as they are in each file.
+    pub edited: bool,
+}
+
+/// Result of writing the edits: the tracks as read back, and the files that failed.
+pub struct SavedTags {
+    pub tracks: Vec<TrackMetadata>,
+    pub failed: Vec<(String, anyhow::Error)>,
+}
+
+/// The tag editor popup, for one track or several at once.
+pub struct TagEditor {
+    pub tracks: Vec<TrackMetadata>,
+    pub fields: Vec<FieldEdit>,
+    pub selected: usize,
+    // Why the last save was refused
+    pub error: Option<String>,
+    /// Set by Ctrl+U on a field the tracks disagree on; a second Ctrl+U confirms.
+    pub confirm_clear: bool,
+}
+
+impl TagEditor {
+    pub fn new(tracks: Vec<TrackMetadata>) -> Self {
+        let fields = TagField::ALL
+            .into_iter()
+            .map(|field| {
+                let mut values = tracks.iter().map(|track| field.value(track));
+                let first = values.next().flatten();
+                let mixed = values.any(|value| value != first);
+                FieldEdit {
+                    field,
+                    value: if mixed { String::new() } else { first.unwrap_or_default() },
+                    mixed,
+                    edited: false,
+                }
+            })
+            .collect();
+
+        Self {
+            tracks,
+            fields,
+            selected: 0,
+            error: None,
+            confirm_clear: false,
+        }
+    }
+
+    pub fn move_up(&mut self) {
+        self.selected = self.selected.checked_sub(1).unwrap_or(self.fields.len() - 1);
+    }
+
+    pub fn move_down(&mut self) {
+        self.selected = (self.selected + 1) % self.fields.len();
+    }
+
+    pub fn push(&mut self, c: char) {
+        let edit = &mut self.fields[self.selected];
+        if edit.field.is_number() && !c.is_ascii_digit() {
+            return;
+        }
+        edit.value.push(c);
+        edit.edited = true;
+    }
+
+    /// Deletes the last character. A mixed field erased back to empty is left as it is
+    /// in each file, rather than removed from all of them.
+    pub fn pop(&mut self) {
+        let edit = &mut self.fields[self.selected];
+        if edit.value.pop().is_some() {
+            edit.edited = !edit.mixed || !edit.value.is_empty();
+        }
+    }
+
+    /// Empties the selected field, which removes it from the files when saved. On a mixed
+    /// field that would wipe every track's own value, so it first asks to confirm.
+    pub fn clear(&mut self) {
+        let edit = &mut self.fields[self.selected];
+        if edit.mixed && !self.confirm_clear {
+            edit.value.clear();
+            edit.edited = false;
+            self.confirm_clear = true;
+            return;
+        }
+        self.confirm_clear = false;
+        edit.value.clear();
+        edit.mixed = false;
+        edit.edited = true;
+    }
+
+    pub fn has_edits(&self) -> bool {
+        self.fields.iter().any(|edit| edit.edited)
+    }
+
+    /// Writes the edited fields to every file and reads the tags back, updating the
+    /// library cache so the next start sees them too. Nothing is written if a number
+    /// field doesn't parse.
+    pub fn save(&self, cache: &SharedCache) -> Result<SavedTags> {
+        for edit in &self.fields {
+            if edit.edited && edit.field.is_number() && !edit.value.is_empty() {
+                edit.value
+                    .parse::<u32>()
+                    .map_err(|_| anyhow!("{} is not a valid number", edit.field.label()))?;
+            }
+        }
+
+        let edits: Vec<(TagField, &str)> = self
+            .fields
+            .iter()
+            .filter(|edit| edit.edited)
+            .map(|edit| (edit.field, edit.value.trim()))
+            .collect();
+
+        let mut saved = SavedTags {
+            tracks: Vec::new(),
+            failed: Vec::new(),
+        };
+        for track in &self.tracks {
+            let path = &track.file_path;
+            let result = write_tags(path, &edits).and_then(|()| {
+                let stamp = FileStamp::from_metadata(&fs::metadata(path)?);
+                let metadata = TrackMetadata::from_file(path)?;
+                Ok(cache.lock().unwrap().insert(stamp, metadata).clone())
+            });
+            match result {
+                Ok(metadata) => saved.tracks.push(metadata),
+                Err(err) => saved.failed.push((path.clone(), err)),
+            }
+        }
+        if !saved.tracks.is_empty() {
+            let _ = cache.lock().unwrap().save();
+        }
+        Ok(saved)
+    }
+}
+
+/// Applies `edits` to every tag in the file, so a stale ID3v1 or APE tag next to the
+/// main one doesn't keep showing the old values.
+fn write_tags(path: &str, edits: &[(TagField, &str)]) -> Result<()> {
+    let mut tagged_file = lofty::read_from_path(path)?;
+    if tagged_file.primary_tag().is_none() {
+        let tag_type = tagged_file.primary_tag_type();
+        tagged_file.insert_tag(Tag::new(tag_type));
+    }
+
+    let tag_types: Vec<_> = tagged_file.tags().iter().map(|tag| tag.tag_type()).collect();
+    for tag_type in tag_types {
+        if let Some(tag) = tagged_file.tag_mut(tag_type) {
+            for (field, value) in edits {
+                field.apply(tag, value)?;
+            }
+        }
+    }
+    tagged_file.save_to_path(path, WriteOptions::default())?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::LibraryCache;
+    use std::sync::{Arc, Mutex};
+
+    fn track(path: &str, album: &str, year: Option<u32>) -> TrackMetadata {
+        serde_json::from_value(serde_json::json!({
+            "title": path,
+            "artist": "Artist",
+            "album": album,
+            "file_path": path,
+            "year": year,
+        }))
+        .unwrap()
+    }
+
+    fn field(editor: &TagEditor, field: TagField) -> &FieldEdit {
+        editor.fields.iter().find(|edit| edit.field == field).unwrap()
+    }
+
+    fn select(editor: &mut TagEditor, field: TagField) {
+        editor.selected = editor.fields.iter().position(|edit| edit.field == field).unwrap();
+    }
+
+    #[test]
+    fn shared_values_are_filled_in_and_differing_ones_are_mixed() {
+        let editor = TagEditor::new(vec![
+            track("a.flac", "Album", Some(1997)),
+            track("b.flac", "Album", None),
+        ]);
+
+        let album = field(&editor, TagField::Album);
+        assert_eq!(album.value, "Album");
+        assert!(!album.mixed);
+        let title = field(&editor, TagField::Title);
+        assert_eq!(title.value, "");
+        assert!(title.mixed);
+        // Missing on one track counts as differing
+        assert!(field(&editor, TagField::Year).mixed);
+        // Missing on both doesn't
+        assert!(!field(&editor, TagField::Genre).mixed);
+        assert!(!editor.has_edits());
+    }
+
+    #[test]
+    fn save_refuses_a_number_that_does_not_parse() {
+        let mut editor = TagEditor::new(vec![track("missing.flac", "Album", None)]);
+        select(&mut editor, TagField::Year);
+        for c in "99999999999".chars() {
+            editor.push(c);
+        }
+
+        let cache = Arc::new(Mutex::new(LibraryCache::load()));
+        let err = editor.save(&cache).err().unwrap();
+        assert_eq!(err.to_string(), "Year is not a valid number");
+    }
+
+    #[test]
+    fn backspace_on_a_mixed_field_leaves_it_unedited() {
+        let mut editor = TagEditor::new(vec![
+            track("a.flac", "A", None),
+            track("b.flac", "B", None),
+        ]);
+        select(&mut editor, TagField::Album);
+
+        editor.pop();
+        assert!(!editor.has_edits());
+
+        editor.push('x');
+        editor.pop();
+        assert!(!editor.has_edits());
+
+        editor.push('x');
+        assert!(field(&editor, TagField::Album).edited);
+    }
+
+    #[test]
+    fn clearing_a_mixed_field_needs_confirming() {
+        let mut editor = TagEditor::new(vec![
+            track("a.flac", "A", None),
+            track("b.flac", "B", None),
+        ]);
+        select(&mut editor, TagField::Album);
+
+        editor.clear();
+        assert!(editor.confirm_clear);
+        assert!(!editor.has_edits());
+
+        editor.clear();
+        let album = field(&editor, TagField::Album);
+        assert!(album.edited);
+        assert_eq!(album.value, "");
+    }
+}
@@ -1,7 +1,22 @@
 use anyhow::Result;
-use crate::audio::AudioPlayer;
+use crate::audio::{AudioPlayer, PlayerEvent, PlayerState};
+use crate::cache::SharedCache;
 use crate::cassette::CassetteWidget;
+use crate::config::Config;
+use crate::directory_selector::{DirectorySelector, SelectorPane};
+use crate::keymap::{self, Action, Keymap, ACTIONS};
 use crate::library::MusicLibrary;
+use crate::metadata::TrackMetadata;
+use crate::output_picker::OutputPicker;
+use crate::playlist::{self, NewPlaylist, Playlist, PlaylistBrowser};
+use crate::queue::{self, PlayQueue};
+use crate::scanner::{LibraryScan, ScanUpdate};
+use crate::search::Search;
+use crate::sink;
+use crate::tag_editor::{SavedTags, TagEditor};
+use crate::watcher::LibraryWatcher;
+use crossterm::event::{KeyCode, KeyModifiers};
+use serde::Deserialize;
 use ratatui::{
     layout::{
         Alignment, Constraint, Direction, Layout, Rect,
@@ -9,14 +24,20 @@ use ratatui::{
     style::{Color, Modifier, Style},
     text::{Line, Span},
     widgets::{
-        Block, Borders, List, ListItem, ListState, Paragraph, Wrap,
+        Block, Borders, Clear, Gauge, List, ListItem, ListState, Paragraph, Wrap,
     },
     Frame,
 };
-use std::sync::{Arc, Mutex};
+use std::{
+    collections::{HashMap, HashSet},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use crate::visualizer::Visualizer;
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
 pub enum Theme {
     Dark,
     Light,
@@ -34,13 +55,30 @@ pub enum Theme {
 }
 
 impl Theme {
+    /// The name used for the theme in the config file.
+    pub fn name(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+            Theme::Synthwave => "synthwave",
+            Theme::Ocean => "ocean",
+            Theme::Forest => "forest",
+            Theme::Cyberpunk => "cyberpunk",
+            Theme::Neon => "neon",
+            Theme::Retro => "retro",
+            Theme::Sunset => "sunset",
+            Theme::Matrix => "matrix",
+            Theme::Arctic => "arctic",
+            Theme::Fire => "fire",
+            Theme::System => "system",
+        }
+    }
+
     pub fn colors(&self) -> ThemeColors {
         match self {
             Theme::Dark => ThemeColors {
                 background: Color::Black,
-                foreground: Color::White,
                 primary: Color::Blue,
-                secondary: Color::Cyan,
                 accent: Color::Yellow,
                 border: Color::Gray,
                 text: Color::White,
@@ -48,9 +86,7 @@ impl Theme {
             },
             Theme::Light => ThemeColors {
                 background: Color::White,
-                foreground: Color::Black,
                 primary: Color::Blue,
-                secondary: Color::Cyan,
                 accent: Color::Yellow,
                 border: Color::Gray,
                 text: Color::Black,
@@ -58,9 +94,7 @@ impl Theme {
             },
             Theme::Synthwave => ThemeColors {
                 background: Color::Rgb(20, 20, 40),
-                foreground: Color::Rgb(255, 100, 255),
                 primary: Color::Rgb(255, 100, 255),
-                secondary: Color::Rgb(100, 255, 255),
                 accent: Color::Rgb(255, 255, 100),
                 border: Color::Rgb(100, 100, 200),
                 text: Color::Rgb(255, 255, 255),
@@ -68,9 +102,7 @@ impl Theme {
             },
             Theme::Ocean => ThemeColors {
                 background: Color::Rgb(0, 20, 40),
-                foreground: Color::Rgb(100, 200, 255),
                 primary: Color::Rgb(0, 150, 255),
-                secondary: Color::Rgb(100, 255, 255),
                 accent: Color::Rgb(255, 255, 100),
                 border: Color::Rgb(50, 100, 150),
                 text: Color::Rgb(200, 220, 255),
@@ -78,9 +110,7 @@ impl Theme {
             },
             Theme::Forest => ThemeColors {
                 background: Color::Rgb(20, 40, 20),
-                foreground: Color::Rgb(100, 255, 100),
                 primary: Color::Rgb(0, 200, 0),
-                secondary: Color::Rgb(100, 255, 100),
                 accent: Color::Rgb(255, 255, 100),
                 border: Color::Rgb(100, 150, 100),
                 text: Color::Rgb(200, 255, 200),
@@ -88,9 +118,7 @@ impl Theme {
             },
             Theme::Cyberpunk => ThemeColors {
                 background: Color::Rgb(10, 5, 20),
-                foreground: Color::Rgb(255, 0, 255),
                 primary: Color::Rgb(255, 0, 255),
-                secondary: Color::Rgb(0, 255, 255),
                 accent: Color::Rgb(255, 255, 0),
                 border: Color::Rgb(100, 0, 200),
                 text: Color::Rgb(255, 200, 255),
@@ -98,9 +126,7 @@ impl Theme {
             },
             Theme::Neon => ThemeColors {
                 background: Color::Rgb(0, 0, 0),
-                foreground: Color::Rgb(0, 255, 255),
                 primary: Color::Rgb(0, 255, 255),
-                secondary: Color::Rgb(255, 0, 255),
                 accent: Color::Rgb(255, 255, 0),
                 border: Color::Rgb(50, 50, 50),
                 text: Color::Rgb(200, 255, 255),
@@ -108,9 +134,7 @@ impl Theme {
             },
             Theme::Retro => ThemeColors {
                 background: Color::Rgb(40, 20, 10),
-                foreground: Color::Rgb(255, 200, 100),
                 primary: Color::Rgb(255, 150, 0),
-                secondary: Color::Rgb(255, 200, 100),
                 accent: Color::Rgb(255, 100, 0),
                 border: Color::Rgb(150, 100, 50),
                 text: Color::Rgb(255, 220, 180),
@@ -118,9 +142,7 @@ impl Theme {
             },
             Theme::Sunset => ThemeColors {
                 background: Color::Rgb(30, 15, 40),
-                foreground: Color::Rgb(255, 100, 50),
                 primary: Color::Rgb(255, 150, 0),
-                secondary: Color::Rgb(255, 100, 150),
                 accent: Color::Rgb(255, 200, 0),
                 border: Color::Rgb(150, 75, 100),
                 text: Color::Rgb(255, 180, 200),
@@ -128,9 +150,7 @@ impl Theme {
             },
             Theme::Matrix => ThemeColors {
                 background: Color::Rgb(0, 0, 0),
-                foreground: Color::Rgb(0, 255, 0),
                 primary: Color::Rgb(0, 255, 0),
-                secondary: Color::Rgb(0, 200, 0),
                 accent: Color::Rgb(0, 255, 100),
                 border: Color::Rgb(0, 100, 0),
                 text: Color::Rgb(0, 255, 0),
@@ -138,9 +158,7 @@ impl Theme {
             },
             Theme::Arctic => ThemeColors {
                 background: Color::Rgb(5, 15, 30),
-                foreground: Color::Rgb(150, 200, 255),
                 primary: Color::Rgb(100, 150, 255),
-                secondary: Color::Rgb(150, 200, 255),
                 accent: Color::Rgb(200, 220, 255),
                 border: Color::Rgb(50, 100, 150),
                 text: Color::Rgb(200, 220, 255),
@@ -148,9 +166,7 @@ impl Theme {
             },
             Theme::Fire => ThemeColors {
                 background: Color::Rgb(20, 5, 0),
-                foreground: Color::Rgb(255, 100, 0),
                 primary: Color::Rgb(255, 150, 0),
-                secondary: Color::Rgb(255, 100, 0),
                 accent: Color::Rgb(255, 200, 0),
                 border: Color::Rgb(150, 50, 0),
                 text: Color::Rgb(255, 180, 150),
@@ -158,9 +174,7 @@ impl Theme {
             },
             Theme::System => ThemeColors {
                 background: Color::Rgb(0, 15, 20), // Dark teal background
-                foreground: Color::Rgb(0, 255, 100), // Bright green
                 primary: Color::Rgb(0, 255, 100), // Bright green titles
-                secondary: Color::Rgb(255, 100, 255), // Bright pink labels
                 accent: Color::Rgb(255, 255, 0), // Bright yellow highlights
                 border: Color::Rgb(0, 255, 100), // Bright green borders
                 text: Color::Rgb(200, 255, 200), // Light green text
@@ -173,9 +187,7 @@ impl Theme {
 #[derive(Debug, Clone)]
 pub struct ThemeColors {
     pub background: Color,
-    pub foreground: Color,
     pub primary: Color,
-    pub secondary: Color,
     pub accent: Color,
     pub border: Color,
     pub text: Color,
@@ -186,21 +198,19 @@ pub struct ThemeColors {
 pub struct AppState {
     pub current_theme: Theme,
     pub rainbow_mode: bool,
-    pub show_albums: bool,
-    pub show_tracks: bool,
     pub show_shortcuts: bool,
     pub show_directory_selector: bool,
+    pub show_help: bool,
 }
 
 impl AppState {
-    pub fn new() -> Self {
+    pub fn new(theme: Theme) -> Self {
         Self {
-            current_theme: Theme::System,
+            current_theme: theme,
             rainbow_mode: false,
-            show_albums: true,
-            show_tracks: false,
             show_shortcuts: true,
             show_directory_selector: false,
+            show_help: false,
         }
     }
 
@@ -233,34 +243,88 @@ impl AppState {
     pub fn toggle_directory_selector(&mut self) {
         self.show_directory_selector = !self.show_directory_selector;
     }
+
+    pub fn toggle_help(&mut self) {
+        self.show_help = !self.show_help;
+    }
+}
+
+/// Which column the navigation keys act on, left to right.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Focus {
+    Artists,
+    Albums,
+    Tracks,
+    Queue,
 }
 
 pub struct App {
+    config: Config,
+    keymap: Keymap,
     audio_player: Arc<Mutex<AudioPlayer>>,
     music_library: Arc<Mutex<MusicLibrary>>,
+    // Written by the scanner, the watcher and the tag editor
+    cache: SharedCache,
     app_state: Arc<Mutex<AppState>>,
+    play_queue: PlayQueue,
+    focus: Focus,
+    // Where TAB goes back to from the queue
+    library_focus: Focus,
     visualizer: Visualizer,
     cassette: CassetteWidget,
+    directory_selector: Option<DirectorySelector>,
+    search: Option<Search>,
+    // The track shown in the details popup
+    track_details: Option<TrackMetadata>,
+    tag_editor: Option<TagEditor>,
+    playlists: Option<PlaylistBrowser>,
+    output_picker: Option<OutputPicker>,
+    // Paths of the tracks marked for editing together
+    marked: HashSet<String>,
+    scan: Option<LibraryScan>,
+    watcher: Option<LibraryWatcher>,
+    // Shown in place of the shortcuts bar for a few seconds
+    status_message: Option<(String, Instant)>,
+    artist_list_state: ListState,
     album_list_state: ListState,
     track_list_state: ListState,
-    music_directory: Option<String>,
+    queue_list_state: ListState,
 }
 
 impl App {
     pub fn new(
+        config: Config,
         audio_player: Arc<Mutex<AudioPlayer>>,
         music_library: Arc<Mutex<MusicLibrary>>,
+        cache: SharedCache,
         app_state: Arc<Mutex<AppState>>,
     ) -> Self {
         Self {
+            keymap: config.keymap(),
+            config,
             audio_player,
             music_library,
+            cache,
             app_state,
+            play_queue: PlayQueue::new(),
+            focus: Focus::Albums,
+            library_focus: Focus::Albums,
             visualizer: Visualizer::new(),
             cassette: CassetteWidget::new(),
+            directory_selector: None,
+            search: None,
+            track_details: None,
+            tag_editor: None,
+            playlists: None,
+            output_picker: None,
+            marked: HashSet::new(),
+            scan: None,
+            watcher: None,
+            status_message: None,
+            artist_list_state: ListState::default(),
             album_list_state: ListState::default(),
             track_list_state: ListState::default(),
-            music_directory: None,
+            queue_list_state: ListState::default(),
         }
     }
 
@@ -281,6 +345,48 @@ impl App {
         self.cassette.set_playing(is_playing);
         self.cassette.update();
 
+        // Follow the player through the queue
+        let events = {
+            let player = self.audio_player.lock().unwrap();
+            player.poll_events()
+        };
+        for event in events {
+            match event {
+                PlayerEvent::TrackChanged(path) => {
+                    let expected = self.play_queue.peek_next().map(|track| &track.file_path);
+                    let current = self.play_queue.now_playing().map(|track| &track.file_path);
+                    if expected == Some(&path) {
+                        self.play_queue.advance();
+                        self.queue_following_track();
+                    } else if current == Some(&path) {
+                        // Started by hand just as the hand-off came in
+                        self.queue_following_track();
+                    } else {
+                        // The engine moved on to an entry queued before the queue was
+                        // edited; play what the queue has next, so the two agree again
+                        let track = self.play_queue.advance().cloned();
+                        if track.is_none() {
+                            self.audio_player.lock().unwrap().stop();
+                        }
+                        self.play_track(track)?;
+                    }
+                }
+                PlayerEvent::StateChanged(PlayerState::Error(message)) => {
+                    self.status_message = Some((format!("Can't play: {message}"), Instant::now()));
+                }
+                PlayerEvent::StateChanged(_) => {}
+                PlayerEvent::OutputChanged(name) => {
+                    self.status_message = Some((format!("Output: {name}"), Instant::now()));
+                }
+                PlayerEvent::OutputError(message) => {
+                    self.status_message = Some((message, Instant::now()));
+                }
+            }
+        }
+
+        self.poll_scan();
+        self.poll_watcher();
+
         Ok(())
     }
 
@@ -310,7 +416,35 @@ impl App {
         self.render_top_section(f, chunks[0], &colors, rainbow_mode);
         self.render_visualizer(f, chunks[1], &colors, rainbow_mode);
         self.render_lists(f, chunks[2], &colors, rainbow_mode);
-        self.render_shortcuts_bar(f, chunks[3], &colors);
+        if self.scan.is_some() {
+            self.render_scan_progress(f, chunks[3], &colors);
+        } else if self.status_message.is_some() {
+            self.render_status_message(f, chunks[3], &colors);
+        } else {
+            self.render_shortcuts_bar(f, chunks[3], &colors);
+        }
+
+        if self.directory_selector.is_some() {
+            self.render_directory_selector(f, &colors);
+        }
+        if self.playlists.is_some() {
+            self.render_playlists(f, &colors);
+        }
+        if self.output_picker.is_some() {
+            self.render_output_picker(f, &colors);
+        }
+        if self.search.is_some() {
+            self.render_search(f, &colors);
+        }
+        if self.track_details.is_some() {
+            self.render_track_details(f, &colors);
+        }
+        if self.tag_editor.is_some() {
+            self.render_tag_editor(f, &colors);
+        }
+        if self.app_state.lock().unwrap().show_help {
+            self.render_help(f, &colors);
+        }
     }
 
     fn render_top_section(
@@ -345,15 +479,17 @@ impl App {
         colors: &ThemeColors,
         _rainbow_mode: bool,
     ) {
-        let library = self.music_library.lock().unwrap();
-        let current_track = library.get_current_track();
         let audio_player = self.audio_player.lock().unwrap();
         let position = audio_player.get_position();
         let duration = audio_player.get_duration();
+        let output_name = audio_player.output_name();
+        let volume = audio_player.get_volume();
+        let muted = audio_player.is_muted();
+        let bit_perfect = audio_player.is_bit_perfect();
+        let state = audio_player.state();
         drop(audio_player);
         
-        let track_info = current_track.cloned();
-        drop(library);
+        let track_info = self.play_queue.now_playing().cloned();
 
         let mut lines = Vec::new();
 
@@ -387,12 +523,45 @@ impl App {
             Span::styled(" / ", Style::default().fg(colors.border)),
             Span::styled(&duration_str, Style::default().fg(colors.text)),
         ]));
+        // Volume meter
+        let meter_width = 20;
+        let filled = (volume * meter_width as f32).round() as usize;
+        let meter = format!("{}{}", "█".repeat(filled), "░".repeat(meter_width - filled));
+        let (volume_color, volume_text) = if muted {
+            (colors.border, " MUTED".to_string())
+        } else if bit_perfect {
+            // The output ignores the volume while it carries the file untouched
+            (colors.border, " BIT-PERFECT".to_string())
+        } else {
+            (colors.highlight, format!(" {:>3.0}%", volume * 100.0))
+        };
+        lines.push(Line::from(vec![
+            Span::styled("Volume: ", Style::default().fg(colors.primary)),
+            Span::styled(meter, Style::default().fg(volume_color)),
+            Span::styled(volume_text, Style::default().fg(colors.text)),
+        ]));
+
+        let shuffle = match self.play_queue.shuffle_seed() {
+            Some(seed) => format!("On #{:08x}", seed as u32),
+            None => "Off".to_string(),
+        };
+        lines.push(Line::from(vec![
+            Span::styled("Shuffle: ", Style::default().fg(colors.primary)),
+            Span::styled(shuffle, Style::default().fg(colors.text)),
+            Span::styled(" | ", Style::default().fg(colors.border)),
+            Span::styled("Repeat: ", Style::default().fg(colors.primary)),
+            Span::styled(self.play_queue.repeat().label(), Style::default().fg(colors.text)),
+        ]));
+        lines.push(Line::from(vec![
+            Span::styled("Output: ", Style::default().fg(colors.primary)),
+            Span::styled(output_name, Style::default().fg(colors.text)),
+        ]));
 
         let paragraph = Paragraph::new(lines)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("NOW PLAYING")
+                    .title(format!("NOW PLAYING - {}", state.label()))
                     .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
                     .border_style(Style::default().fg(colors.border)),
             )
@@ -472,11 +641,59 @@ impl App {
     ) {
         let chunks = Layout::default()
             .direction(Direction::Horizontal)
-            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .constraints([
+                Constraint::Percentage(20),
+                Constraint::Percentage(27),
+                Constraint::Percentage(28),
+                Constraint::Percentage(25),
+            ])
             .split(area);
 
-        self.render_album_list(f, chunks[0], colors, rainbow_mode);
-        self.render_track_list(f, chunks[1], colors, rainbow_mode);
+        self.render_artist_list(f, chunks[0], colors);
+        self.render_album_list(f, chunks[1], colors, rainbow_mode);
+        self.render_track_list(f, chunks[2], colors, rainbow_mode);
+        self.render_queue(f, chunks[3], colors);
+    }
+
+    fn border_color(&self, column: Focus, colors: &ThemeColors) -> Color {
+        if self.focus == column {
+            colors.highlight
+        } else {
+            colors.border
+        }
+    }
+
+    fn render_artist_list(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
+        let library = self.music_library.lock().unwrap();
+        let artists: Vec<ListItem> = library
+            .artists
+            .iter()
+            .enumerate()
+            .map(|(i, artist)| {
+                let style = if i == library.current_artist_index {
+                    Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default().fg(colors.text)
+                };
+                ListItem::new(Line::from(vec![
+                    Span::styled("> ", style),
+                    Span::styled(artist.name.clone(), style),
+                ]))
+            })
+            .collect();
+
+        let list = List::new(artists).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(format!("ARTISTS ({})", library.artists.len()))
+                .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                .border_style(Style::default().fg(self.border_color(Focus::Artists, colors))),
+        );
+
+        self.artist_list_state
+            .select((!library.artists.is_empty()).then_some(library.current_artist_index));
+        drop(library);
+        f.render_stateful_widget(list, area, &mut self.artist_list_state);
     }
 
     fn render_album_list(
@@ -487,22 +704,31 @@ impl App {
         _rainbow_mode: bool,
     ) {
         let library = self.music_library.lock().unwrap();
+        // Only the selected artist's albums, so the index is relative to their first one
+        let first = library.get_current_artist().map_or(0, |artist| artist.albums.start);
+        let selected = library.current_album_index.saturating_sub(first);
         let albums: Vec<ListItem> = library
-            .albums
+            .artist_albums()
             .iter()
             .enumerate()
             .map(|(i, album)| {
-                let style = if i == library.current_album_index {
+                let style = if i == selected {
                     Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD)
                 } else {
                     Style::default().fg(colors.text)
                 };
 
-                let display_name = album.display_name();
-                ListItem::new(Line::from(vec![
+                let mut spans = vec![
                     Span::styled("> ", style),
-                    Span::styled(display_name, style),
-                ]))
+                    Span::styled(album.name.clone(), style),
+                ];
+                if let Some(year) = album.year() {
+                    spans.push(Span::styled(
+                        format!(" ({year})"),
+                        Style::default().fg(colors.border),
+                    ));
+                }
+                ListItem::new(Line::from(spans))
             })
             .collect();
 
@@ -510,14 +736,15 @@ impl App {
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("ALBUMS")
+                    .title(format!("ALBUMS by {}", library.sort.label()))
                     .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
+                    .border_style(Style::default().fg(self.border_color(Focus::Albums, colors))),
             );
 
-        self.album_list_state.select(Some(library.current_album_index));
-        f.render_stateful_widget(list, area, &mut self.album_list_state);
+        self.album_list_state
+            .select((!library.albums.is_empty()).then_some(selected));
         drop(library);
+        f.render_stateful_widget(list, area, &mut self.album_list_state);
     }
 
     fn render_track_list(
@@ -531,6 +758,7 @@ impl App {
         let tracks: Vec<ListItem> = library
             .get_current_album()
             .map(|album| {
+                let multi_disc = album.is_multi_disc();
                 album
                     .tracks
                     .iter()
@@ -542,9 +770,19 @@ impl App {
                             Style::default().fg(colors.text)
                         };
 
-                        let display_title = track.display_title();
+                        let display_title = match track.disc_number {
+                            Some(disc) if multi_disc => {
+                                format!("{disc}-{}", track.display_title())
+                            }
+                            _ => track.display_title(),
+                        };
+                        let marker = if self.marked.contains(&track.file_path) {
+                            Span::styled("* ", Style::default().fg(colors.accent))
+                        } else {
+                            Span::styled("> ", style)
+                        };
                         ListItem::new(Line::from(vec![
-                            Span::styled("> ", style),
+                            marker,
                             Span::styled(display_title, style),
                         ]))
                     })
@@ -552,42 +790,93 @@ impl App {
             })
             .unwrap_or_default();
 
+        let title = match self.marked.len() {
+            0 => "TRACKS".to_string(),
+            marked => format!("TRACKS ({marked} marked)"),
+        };
         let list = List::new(tracks)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("TRACKS")
+                    .title(title)
                     .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
+                    .border_style(Style::default().fg(self.border_color(Focus::Tracks, colors))),
             );
 
         if let Some(_album) = library.get_current_album() {
             self.track_list_state.select(Some(library.current_track_index));
         }
-        f.render_stateful_widget(list, area, &mut self.track_list_state);
         drop(library);
+        f.render_stateful_widget(list, area, &mut self.track_list_state);
+    }
+
+    fn render_queue(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
+        let current = self.play_queue.current_index();
+        let tracks: Vec<ListItem> = self
+            .play_queue
+            .tracks()
+            .iter()
+            .enumerate()
+            .map(|(i, track)| {
+                let (marker, style) = if Some(i) == current {
+                    ("▶ ", Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                } else {
+                    ("  ", Style::default().fg(colors.text))
+                };
+
+                ListItem::new(Line::from(vec![
+                    Span::styled(marker, style),
+                    Span::styled(format!("{} - {}", track.artist, track.title), style),
+                ]))
+            })
+            .collect();
+
+        let border_color = self.border_color(Focus::Queue, colors);
+        let list = List::new(tracks)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("QUEUE ({})", self.play_queue.len()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(border_color)),
+            )
+            .highlight_style(Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD))
+            .highlight_symbol("> ");
+
+        if self.focus != Focus::Queue {
+            self.queue_list_state.select(None);
+        } else if self.queue_list_state.selected().is_none() && !self.play_queue.is_empty() {
+            self.queue_list_state.select(Some(current.unwrap_or(0)));
+        }
+        f.render_stateful_widget(list, area, &mut self.queue_list_state);
     }
 
-    fn render_shortcuts(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        // Barra de atalhos no estilo do sistema de monitoramento
-        let shortcuts = vec![
-            ("SPACE", "Play/Pause"),
-            ("↑↓", "Albums"),
-            ("←→", "Tracks"),
-            ("ENTER", "Select"),
-            ("T", "Theme"),
-            ("R", "Rainbow"),
-            ("S", "Shortcuts"),
-            ("D", "Directory"),
-            ("CTRL+Q", "Quit"),
-        ];
+    fn render_shortcuts_bar(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
+        // Garantir que a área seja válida
+        if area.height < 1 {
+            return;
+        }
 
         let mut spans = Vec::new();
-        for (i, (key, action)) in shortcuts.iter().enumerate() {
+        // Show the start of a key sequence until it's finished
+        if !self.keymap.pending().is_empty() {
+            spans.push(Span::styled(
+                format!("{} … | ", keymap::format_sequence(self.keymap.pending())),
+                Style::default().fg(colors.accent).add_modifier(Modifier::BOLD),
+            ));
+        }
+
+        let shortcuts = SHORTCUT_BAR.iter().filter_map(|(actions, label)| {
+            let keys: Vec<String> = actions
+                .iter()
+                .filter_map(|action| self.keymap.label(*action))
+                .collect();
+            (!keys.is_empty()).then(|| (join_key_labels(&keys), *label))
+        });
+        for (i, (key, action)) in shortcuts.enumerate() {
             if i > 0 {
                 spans.push(Span::styled(" | ", Style::default().fg(colors.border)));
             }
-            // Chave em destaque (como [Q] no sistema)
             spans.push(Span::styled(
                 format!("[{}]", key),
                 Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD),
@@ -599,339 +888,1053 @@ impl App {
         }
 
         let shortcuts_text = Line::from(spans);
-        
         let shortcuts_paragraph = Paragraph::new(shortcuts_text)
+            .style(Style::default().fg(colors.text))
+            .alignment(Alignment::Center);
+
+        // Footer no canto direito
+        let footer_text = "made by gui기กีギ";
+        let footer = Paragraph::new(footer_text)
+            .style(Style::default().fg(colors.border))
+            .alignment(Alignment::Right);
+
+        let layout = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(footer_text.len() as u16 + 2)])
+            .split(area);
+
+        f.render_widget(shortcuts_paragraph, layout[0]);
+        f.render_widget(footer, layout[1]);
+    }
+
+    fn render_scan_progress(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
+        let Some(scan) = &self.scan else {
+            return;
+        };
+        let counts = scan.progress();
+        let cancel = self
+            .keymap
+            .label(Action::CancelScan)
+            .map(|key| format!(" | {key} Cancel"))
+            .unwrap_or_default();
+
+        let label = if counts.walking {
+            format!(
+                "Looking for music... {} files found ({} cached){cancel}",
+                counts.seen, counts.cached
+            )
+        } else {
+            format!(
+                "Reading tags {}/{} | parsed {} | cached {} | failed {}{cancel}",
+                counts.done(),
+                counts.seen,
+                counts.parsed,
+                counts.cached,
+                counts.failed
+            )
+        };
+
+        let gauge = Gauge::default()
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("KEYBOARD SHORTCUTS")
+                    .title("SCANNING LIBRARY")
                     .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
                     .border_style(Style::default().fg(colors.border)),
             )
-            .alignment(Alignment::Center);
+            .gauge_style(Style::default().fg(colors.highlight).bg(colors.background))
+            .ratio(counts.ratio())
+            .label(Span::styled(label, Style::default().fg(colors.text)));
 
-        f.render_widget(shortcuts_paragraph, area);
+        f.render_widget(gauge, area);
+    }
+
+    fn render_status_message(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
+        let Some((message, _)) = &self.status_message else {
+            return;
+        };
+        let paragraph = Paragraph::new(message.as_str())
+            .style(Style::default().fg(colors.text))
+            .alignment(Alignment::Center);
+        f.render_widget(paragraph, area);
     }
 
-    fn render_system_info(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let system_info = vec![
-            "User: eugui@PC-AIIALABS",
-            "OS: Windows",
-            "Distro: Windows 11 Home",
-            "Kernel: 26100",
-            "Arch: x86_64",
-            "Shell: PowerShell",
-            "Processes: 299",
-        ];
+    fn render_search(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+
+        let area = centered_rect(70, 70, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Block::default().style(Style::default().bg(colors.background)),
+            area,
+        );
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(3), Constraint::Min(3), Constraint::Length(3)])
+            .split(area);
+
+        let input = Paragraph::new(Line::from(vec![
+            Span::styled("/ ", Style::default().fg(colors.accent).add_modifier(Modifier::BOLD)),
+            Span::styled(format!("{}█", search.query), Style::default().fg(colors.text)),
+        ]))
+        .block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("SEARCH")
+                .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                .border_style(Style::default().fg(colors.highlight)),
+        );
+        f.render_widget(input, rows[0]);
 
-        let items: Vec<ListItem> = system_info
+        let text = Style::default().fg(colors.text);
+        let dim = Style::default().fg(colors.border);
+        let matched = Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD);
+        let items: Vec<ListItem> = search
+            .hits
             .iter()
-            .map(|info| {
-                let spans = vec![
-                    Span::styled("User: ", Style::default().fg(colors.secondary)),
-                    Span::styled("eugui@PC-AIIALABS", Style::default().fg(colors.text)),
-                ];
+            .map(|hit| {
+                let mut spans = highlight(&hit.track.title, &hit.title_matches, text, matched);
+                spans.push(Span::styled(" - ", dim));
+                spans.extend(highlight(&hit.track.artist, &hit.artist_matches, text, matched));
+                spans.push(Span::styled(" - ", dim));
+                spans.extend(highlight(&hit.track.album, &hit.album_matches, dim, matched));
                 ListItem::new(Line::from(spans))
             })
             .collect();
-
-        let list = List::new(items)
+        let results = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("RESULTS ({})", search.hits.len()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(colors.border)),
+            )
+            .highlight_style(Style::default().add_modifier(Modifier::REVERSED))
+            .highlight_symbol("> ");
+        f.render_stateful_widget(results, rows[1], &mut search.list_state);
+
+        let hint = search.message.clone().unwrap_or_else(|| {
+            "Type to search title, artist, album | ↑↓ Move | ENTER Go to | CTRL+A Enqueue | ESC Close"
+                .to_string()
+        });
+        let footer = Paragraph::new(hint)
+            .style(Style::default().fg(colors.text))
+            .alignment(Alignment::Center)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("System Information")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
                     .border_style(Style::default().fg(colors.border)),
             );
+        f.render_widget(footer, rows[2]);
+    }
+
+    fn render_track_details(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let Some(track) = &self.track_details else {
+            return;
+        };
 
-        f.render_widget(list, area);
+        let area = centered_rect(60, 70, f.size());
+        f.render_widget(Clear, area);
+        let rows: Vec<Line> = track
+            .details()
+            .into_iter()
+            .map(|(label, value)| {
+                Line::from(vec![
+                    Span::styled(
+                        format!("{label:>12}  "),
+                        Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD),
+                    ),
+                    Span::styled(value, Style::default().fg(colors.text)),
+                ])
+            })
+            .collect();
+        let details = Paragraph::new(rows).wrap(Wrap { trim: false }).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("TRACK DETAILS - press any key to close")
+                .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                .border_style(Style::default().fg(colors.border))
+                .style(Style::default().bg(colors.background)),
+        );
+        f.render_widget(details, area);
     }
 
-    fn render_hardware_info(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let hardware_info = vec![
-            "CPU: 12th Gen Intel(R) Core(TM) i5-1235",
-            "Cores: 12 (10 physical)",
-            "Frequency: 1.30 GHz",
-            "Memory: 15.7 GB",
-            "Uptime: 22h 6m",
-            "Display: 1920x1080 @ 60Hz",
-            "Audio: Intel Smart Sound",
-        ];
+    fn render_tag_editor(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let Some(editor) = &self.tag_editor else {
+            return;
+        };
+
+        let area = centered_rect(60, 50, f.size());
+        f.render_widget(Clear, area);
+        let title = match editor.tracks.as_slice() {
+            [track] => format!("EDIT TAGS - {}", track.title),
+            tracks => format!("EDIT TAGS - {} tracks", tracks.len()),
+        };
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(title)
+            .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+            .border_style(Style::default().fg(colors.border))
+            .style(Style::default().bg(colors.background));
+        let inner = block.inner(area);
+        f.render_widget(block, area);
+
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(1), Constraint::Length(1)])
+            .split(inner);
 
-        let items: Vec<ListItem> = hardware_info
+        let fields: Vec<Line> = editor
+            .fields
             .iter()
-            .map(|info| {
-                let parts: Vec<&str> = info.split(": ").collect();
-                if parts.len() == 2 {
-                    let spans = vec![
-                        Span::styled(format!("{}: ", parts[0]), Style::default().fg(colors.secondary)),
-                        Span::styled(parts[1], Style::default().fg(colors.text)),
-                    ];
-                    ListItem::new(Line::from(spans))
+            .enumerate()
+            .map(|(i, edit)| {
+                let selected = i == editor.selected;
+                let label_style = if selected {
+                    Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD)
+                } else {
+                    Style::default().fg(colors.text)
+                };
+                let value = if edit.mixed && !edit.edited {
+                    Span::styled("<multiple values>", Style::default().fg(colors.border))
                 } else {
-                    ListItem::new(Line::from(Span::styled(*info, Style::default().fg(colors.text))))
+                    Span::styled(edit.value.clone(), Style::default().fg(colors.text))
+                };
+                let mut line = vec![
+                    Span::styled(format!("{:>8}  ", edit.field.label()), label_style),
+                    value,
+                ];
+                if selected {
+                    line.push(Span::styled("_", Style::default().fg(colors.highlight)));
                 }
+                Line::from(line)
             })
             .collect();
+        f.render_widget(Paragraph::new(fields), rows[0]);
+
+        let footer = match &editor.error {
+            Some(error) => Span::styled(error.clone(), Style::default().fg(colors.accent)),
+            None if editor.confirm_clear => Span::styled(
+                format!(
+                    "CTRL+U again to remove {} from all {} tracks",
+                    editor.fields[editor.selected].field.label(),
+                    editor.tracks.len()
+                ),
+                Style::default().fg(colors.accent),
+            ),
+            None => Span::styled(
+                "ENTER save | ESC cancel | TAB next field | CTRL+U clear field",
+                Style::default().fg(colors.border),
+            ),
+        };
+        f.render_widget(Paragraph::new(footer).alignment(Alignment::Center), rows[1]);
+    }
 
-        let list = List::new(items)
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("Hardware Information")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
-            );
+    /// Every action with all of its keys, in two columns.
+    fn render_help(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let area = centered_rect(80, 80, f.size());
+        f.render_widget(Clear, area);
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title("KEYS - press any key to close")
+            .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+            .border_style(Style::default().fg(colors.border))
+            .style(Style::default().bg(colors.background));
+        let inner = block.inner(area);
+        f.render_widget(block, area);
+
+        let rows: Vec<Line> = ACTIONS
+            .iter()
+            .map(|info| {
+                let keys: Vec<String> = self
+                    .keymap
+                    .keys_for(info.action)
+                    .map(|keys| keymap::format_sequence(keys))
+                    .collect();
+                let keys = if keys.is_empty() {
+                    "-".to_string()
+                } else {
+                    keys.join(", ")
+                };
+                Line::from(vec![
+                    Span::styled(
+                        format!("{keys:>16}  "),
+                        Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD),
+                    ),
+                    Span::styled(info.description, Style::default().fg(colors.text)),
+                ])
+            })
+            .collect();
 
-        f.render_widget(list, area);
+        let columns = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+            .split(inner);
+        let half = rows.len().div_ceil(2);
+        let (left, right) = rows.split_at(half);
+        f.render_widget(Paragraph::new(left.to_vec()), columns[0]);
+        f.render_widget(Paragraph::new(right.to_vec()), columns[1]);
     }
 
-    fn render_cpu_usage(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let cpu_usage = 21.2;
-        let usage_text = format!("CPU Usage: {:.1}%", cpu_usage);
-        
-        // Barra de progresso
-        let bar_width = (area.width.saturating_sub(4)) as usize;
-        let filled_width = ((cpu_usage / 100.0) * bar_width as f64) as usize;
-        
-        let mut bar_chars = vec!['█'; filled_width];
-        bar_chars.resize(bar_width, '░');
-        let bar_text = bar_chars.iter().collect::<String>();
-
-        let content = vec![
-            Line::from(vec![
-                Span::styled("CPU Usage: ", Style::default().fg(colors.primary).add_modifier(Modifier::BOLD)),
-                Span::styled(format!("{:.1}%", cpu_usage), Style::default().fg(colors.text)),
-            ]),
-            Line::from(Span::styled(bar_text, Style::default().fg(colors.highlight))),
-        ];
-
-        let paragraph = Paragraph::new(content)
-            .block(
-                Block::default()
-                    .borders(Borders::ALL)
-                    .title("CPU Usage")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
-            );
+    fn render_directory_selector(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let roots = self.music_library.lock().unwrap().music_roots.clone();
+        let Some(selector) = self.directory_selector.as_mut() else {
+            return;
+        };
 
-        f.render_widget(paragraph, area);
-    }
+        let area = centered_rect(70, 70, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Block::default().style(Style::default().bg(colors.background)),
+            area,
+        );
 
-    fn render_disk_usage(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let disk_usage = 20.1; // 188/934GB
-        let usage_text = format!("Disk Usage: {:.1}%", disk_usage);
-        
-        // Barra de progresso
-        let bar_width = (area.width.saturating_sub(4)) as usize;
-        let filled_width = ((disk_usage / 100.0) * bar_width as f64) as usize;
-        
-        let mut bar_chars = vec!['█'; filled_width];
-        bar_chars.resize(bar_width, '░');
-        let bar_text = bar_chars.iter().collect::<String>();
-
-        let content = vec![
-            Line::from(vec![
-                Span::styled("OS [SSD] ", Style::default().fg(colors.text)),
-            ]),
-            Line::from(Span::styled(bar_text, Style::default().fg(colors.highlight))),
-            Line::from(vec![
-                Span::styled("188/934GB", Style::default().fg(colors.text)),
-            ]),
-        ];
-
-        let paragraph = Paragraph::new(content)
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(area);
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+            .split(rows[0]);
+
+        let pane_border = |pane: SelectorPane| {
+            if selector.pane == pane {
+                colors.highlight
+            } else {
+                colors.border
+            }
+        };
+        let browser_border = pane_border(SelectorPane::Browser);
+        let roots_border = pane_border(SelectorPane::Roots);
+
+        // Filesystem browser, with directories that are already roots marked
+        let entries: Vec<ListItem> = selector
+            .entries()
+            .iter()
+            .map(|entry| {
+                let is_root = entry.label != ".." && roots.contains(&entry.path);
+                let marker = if is_root { "★ " } else { "  " };
+                ListItem::new(Line::from(vec![
+                    Span::styled(marker, Style::default().fg(colors.accent)),
+                    Span::styled(format!("{}/", entry.label), Style::default().fg(colors.text)),
+                ]))
+            })
+            .collect();
+        let browser = List::new(entries)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("Disk Usage")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
-            );
-
-        f.render_widget(paragraph, area);
-    }
+                    .title(format!("BROWSE {}", selector.current_dir().display()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(browser_border)),
+            )
+            .highlight_style(Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD))
+            .highlight_symbol("> ");
+        f.render_stateful_widget(browser, panes[0], &mut selector.browser_state);
 
-    fn render_memory_info(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let ram_usage = 74.8;
-        let swap_usage = 70.1;
-        
-        // Barras de progresso
-        let bar_width = (area.width.saturating_sub(4)) as usize;
-        
-        let ram_filled = ((ram_usage / 100.0) * bar_width as f64) as usize;
-        let swap_filled = ((swap_usage / 100.0) * bar_width as f64) as usize;
-        
-        let mut ram_bar = vec!['█'; ram_filled];
-        ram_bar.resize(bar_width, '░');
-        let ram_bar_text = ram_bar.iter().collect::<String>();
-        
-        let mut swap_bar = vec!['█'; swap_filled];
-        swap_bar.resize(bar_width, '░');
-        let swap_bar_text = swap_bar.iter().collect::<String>();
-
-        let content = vec![
-            Line::from(vec![
-                Span::styled("Memory: ", Style::default().fg(colors.primary).add_modifier(Modifier::BOLD)),
-                Span::styled(format!("{:.1}%", ram_usage), Style::default().fg(colors.text)),
-            ]),
-            Line::from(vec![
-                Span::styled("RAM: ", Style::default().fg(colors.secondary)),
-                Span::styled("11.7 GB used • 4.0 GB free • 74.8%", Style::default().fg(colors.text)),
-            ]),
-            Line::from(Span::styled(ram_bar_text, Style::default().fg(colors.highlight))),
-            Line::from(vec![
-                Span::styled("Swap: ", Style::default().fg(colors.secondary)),
-                Span::styled("11.6 GB used • 5.0 GB free • 70.1%", Style::default().fg(colors.text)),
-            ]),
-            Line::from(Span::styled(swap_bar_text, Style::default().fg(colors.highlight))),
-        ];
-
-        let paragraph = Paragraph::new(content)
+        let root_items: Vec<ListItem> = roots
+            .iter()
+            .map(|root| {
+                ListItem::new(Span::styled(
+                    root.display().to_string(),
+                    Style::default().fg(colors.text),
+                ))
+            })
+            .collect();
+        let roots_list = List::new(root_items)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("Memory")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
-                    .border_style(Style::default().fg(colors.border)),
-            );
-
-        f.render_widget(paragraph, area);
-    }
+                    .title(format!("MUSIC ROOTS ({})", roots.len()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(roots_border)),
+            )
+            .highlight_style(Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD))
+            .highlight_symbol("> ");
+        if selector.pane != SelectorPane::Roots {
+            selector.roots_state.select(None);
+        } else {
+            selector.clamp_roots(roots.len());
+        }
+        f.render_stateful_widget(roots_list, panes[1], &mut selector.roots_state);
 
-    fn render_network_info(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        let content = vec![
-            Line::from(vec![
-                Span::styled("↓ Download: ", Style::default().fg(colors.highlight)),
-                Span::styled("15.0 KB/s", Style::default().fg(colors.text)),
-            ]),
-            Line::from(vec![
-                Span::styled("↑ Upload: ", Style::default().fg(Color::Red)),
-                Span::styled("657.2 B/s", Style::default().fg(colors.text)),
-            ]),
-            Line::from(vec![
-                Span::styled("Total RX: ", Style::default().fg(colors.text)),
-                Span::styled("0.00 GB", Style::default().fg(colors.text)),
-            ]),
-            Line::from(vec![
-                Span::styled("Total TX: ", Style::default().fg(colors.text)),
-                Span::styled("0.00 GB", Style::default().fg(colors.text)),
-            ]),
-        ];
-
-        let paragraph = Paragraph::new(content)
+        let hint = selector.message.clone().unwrap_or_else(|| {
+            "ENTER/→ Open | ←/BACKSPACE Up | A Add Root | TAB Roots | X Remove | S Scan | ESC Close"
+                .to_string()
+        });
+        let footer = Paragraph::new(hint)
+            .style(Style::default().fg(colors.text))
+            .alignment(Alignment::Center)
             .block(
                 Block::default()
                     .borders(Borders::ALL)
-                    .title("Network")
-                    .title_style(Style::default().fg(colors.primary).add_modifier(Modifier::BOLD))
                     .border_style(Style::default().fg(colors.border)),
             );
+        f.render_widget(footer, rows[1]);
+    }
 
-        f.render_widget(paragraph, area);
-    }
-
-    fn render_shortcuts_bar(&mut self, f: &mut Frame, area: Rect, colors: &ThemeColors) {
-        // Garantir que a área seja válida
-        if area.height < 1 {
+    fn render_playlists(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let Some(browser) = self.playlists.as_mut() else {
             return;
-        }
-
-        let shortcuts = vec![
-            ("SPACE", "Play/Pause"),
-            ("↑↓", "Albums"),
-            ("←→", "Tracks"),
-            ("ENTER", "Select"),
-            ("T", "Theme"),
-            ("R", "Rainbow"),
-            ("S", "Shortcuts"),
-            ("D", "Directory"),
-            ("CTRL+Q", "Quit"),
-        ];
+        };
 
-        let mut spans = Vec::new();
-        for (i, (key, action)) in shortcuts.iter().enumerate() {
-            if i > 0 {
-                spans.push(Span::styled(" | ", Style::default().fg(colors.border)));
-            }
-            spans.push(Span::styled(
-                format!("[{}]", key),
-                Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD),
-            ));
-            spans.push(Span::styled(
-                format!(" {}", action),
-                Style::default().fg(colors.text),
-            ));
-        }
+        let area = centered_rect(80, 70, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Block::default().style(Style::default().bg(colors.background)),
+            area,
+        );
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
+            .split(area);
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(40), Constraint::Percentage(60)])
+            .split(rows[0]);
 
-        let shortcuts_text = Line::from(spans);
-        let shortcuts_paragraph = Paragraph::new(shortcuts_text)
+        let items: Vec<ListItem> = browser
+            .playlists
+            .iter()
+            .map(|playlist| {
+                let mut line = vec![Span::styled(
+                    format!("{} ({})", playlist.name, playlist.entries.len()),
+                    Style::default().fg(colors.text),
+                )];
+                let missing = playlist.missing_count();
+                if missing > 0 {
+                    line.push(Span::styled(
+                        format!(" {missing} missing"),
+                        Style::default().fg(colors.accent),
+                    ));
+                }
+                ListItem::new(Line::from(line))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("PLAYLISTS ({})", browser.playlists.len()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(colors.highlight)),
+            )
+            .highlight_style(Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD))
+            .highlight_symbol("> ");
+        f.render_stateful_widget(list, panes[0], &mut browser.list_state);
+
+        // Missing files stay in the list so the playlist can be fixed
+        let (title, entries) = match browser.selected() {
+            Some(playlist) => (
+                playlist.path.display().to_string(),
+                playlist
+                    .entries
+                    .iter()
+                    .map(|entry| {
+                        if entry.missing {
+                            ListItem::new(Span::styled(
+                                format!("✗ {} (missing: {})", entry.label(), entry.path.display()),
+                                Style::default().fg(colors.accent),
+                            ))
+                        } else {
+                            ListItem::new(Span::styled(
+                                format!("  {}", entry.label()),
+                                Style::default().fg(colors.text),
+                            ))
+                        }
+                    })
+                    .collect(),
+            ),
+            None => ("NO PLAYLISTS".to_string(), Vec::new()),
+        };
+        let entries = List::new(entries).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title(title)
+                .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                .border_style(Style::default().fg(colors.border)),
+        );
+        f.render_widget(entries, panes[1]);
+
+        let hint = match &browser.naming {
+            Some(new) => format!(
+                "New playlist of {} tracks: {}_   (ENTER Save | ESC Cancel)",
+                new.tracks.len(),
+                new.name
+            ),
+            None => browser.message.clone().unwrap_or_else(|| {
+                "ENTER Play | A Add to Queue | S Save Queue | L Save Selection | ESC Close"
+                    .to_string()
+            }),
+        };
+        let footer = Paragraph::new(hint)
             .style(Style::default().fg(colors.text))
-            .alignment(Alignment::Center);
+            .alignment(Alignment::Center)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(colors.border)),
+            );
+        f.render_widget(footer, rows[1]);
+    }
 
-        // Footer no canto direito
-        let footer_text = "made by gui기กีギ";
-        let footer = Paragraph::new(footer_text)
-            .style(Style::default().fg(colors.border))
-            .alignment(Alignment::Right);
+    fn render_output_picker(&mut self, f: &mut Frame, colors: &ThemeColors) {
+        let Some(picker) = self.output_picker.as_mut() else {
+            return;
+        };
 
-        let layout = Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Min(0), Constraint::Length(footer_text.len() as u16 + 2)])
+        let area = centered_rect(80, 60, f.size());
+        f.render_widget(Clear, area);
+        f.render_widget(
+            Block::default().style(Style::default().bg(colors.background)),
+            area,
+        );
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Min(3), Constraint::Length(3)])
             .split(area);
+        let panes = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(55), Constraint::Percentage(45)])
+            .split(rows[0]);
 
-        f.render_widget(shortcuts_paragraph, layout[0]);
-        f.render_widget(footer, layout[1]);
+        let items: Vec<ListItem> = picker
+            .devices
+            .iter()
+            .map(|device| {
+                let marker = if picker.active.as_ref() == Some(&device.id) {
+                    "● "
+                } else {
+                    "  "
+                };
+                let mut line = vec![Span::styled(
+                    format!("{marker}{}", device.id),
+                    Style::default().fg(colors.text),
+                )];
+                if device.is_default {
+                    line.push(Span::styled(" default", Style::default().fg(colors.accent)));
+                }
+                ListItem::new(Line::from(line))
+            })
+            .collect();
+        let list = List::new(items)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(format!("OUTPUT DEVICES ({})", picker.devices.len()))
+                    .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                    .border_style(Style::default().fg(colors.highlight)),
+            )
+            .highlight_style(Style::default().fg(colors.highlight).add_modifier(Modifier::BOLD))
+            .highlight_symbol("> ");
+        f.render_stateful_widget(list, panes[0], &mut picker.list_state);
+
+        let formats: Vec<ListItem> = match picker.selected() {
+            Some(device) if device.configs.is_empty() => vec![ListItem::new(Span::styled(
+                "The device doesn't report its formats",
+                Style::default().fg(colors.accent),
+            ))],
+            Some(device) => device
+                .configs
+                .iter()
+                .map(|range| {
+                    ListItem::new(Span::styled(
+                        sink::describe_range(range),
+                        Style::default().fg(colors.text),
+                    ))
+                })
+                .collect(),
+            None => Vec::new(),
+        };
+        let formats = List::new(formats).block(
+            Block::default()
+                .borders(Borders::ALL)
+                .title("SUPPORTED FORMATS")
+                .title_style(Style::default().fg(colors.accent).add_modifier(Modifier::BOLD))
+                .border_style(Style::default().fg(colors.border)),
+        );
+        f.render_widget(formats, panes[1]);
+
+        let hint = if picker.devices.is_empty() {
+            "No output devices found | D Default Device | ESC Close"
+        } else {
+            "ENTER Use Device | D Default Device | ESC Close"
+        };
+        let footer = Paragraph::new(hint)
+            .style(Style::default().fg(colors.text))
+            .alignment(Alignment::Center)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .border_style(Style::default().fg(colors.border)),
+            );
+        f.render_widget(footer, rows[1]);
     }
 
     pub async fn toggle_playback(&mut self) -> Result<()> {
-        let player = self.audio_player.lock().unwrap();
-        if player.is_playing() {
-            player.pause();
-        } else {
-            player.play();
+        let state = self.audio_player.lock().unwrap().state();
+        match state {
+            PlayerState::Playing => self.audio_player.lock().unwrap().pause(),
+            PlayerState::Paused => self.audio_player.lock().unwrap().play(),
+            // Nothing loaded any more, so start the current queue entry over
+            PlayerState::Stopped | PlayerState::Ended | PlayerState::Error(_) => {
+                let track = self.play_queue.now_playing().cloned();
+                self.play_track(track)?;
+            }
+            PlayerState::Loading(_) => {}
         }
         Ok(())
     }
 
+    /// Unloads the track; play starts it again from the beginning.
+    pub fn stop(&mut self) {
+        self.audio_player.lock().unwrap().stop();
+    }
+
+    /// Seeks forwards or backwards from the current position.
+    pub fn seek_relative(&mut self, offset_secs: i64) {
+        let player = self.audio_player.lock().unwrap();
+        let position = player.get_position();
+        let offset = Duration::from_secs(offset_secs.unsigned_abs());
+        let target = if offset_secs < 0 {
+            position.saturating_sub(offset)
+        } else {
+            position + offset
+        };
+        player.seek(target);
+    }
+
+    /// Jumps to a percentage of the current track's duration.
+    pub fn seek_to_percent(&mut self, percent: u32) {
+        let player = self.audio_player.lock().unwrap();
+        let target = player.get_duration() * percent.min(100) / 100;
+        player.seek(target);
+    }
+
     pub fn navigate_up(&mut self) {
         let mut library = self.music_library.lock().unwrap();
-        library.prev_album();
+        match self.focus {
+            Focus::Artists => library.prev_artist(),
+            Focus::Albums => library.prev_album(),
+            Focus::Tracks => library.prev_track(),
+            Focus::Queue => {
+                let selected = self.queue_list_state.selected().unwrap_or(0);
+                self.queue_list_state.select(Some(selected.saturating_sub(1)));
+            }
+        }
     }
 
     pub fn navigate_down(&mut self) {
         let mut library = self.music_library.lock().unwrap();
-        library.next_album();
+        match self.focus {
+            Focus::Artists => library.next_artist(),
+            Focus::Albums => library.next_album(),
+            Focus::Tracks => library.next_track(),
+            Focus::Queue => {
+                let last = self.play_queue.len().saturating_sub(1);
+                let selected = self.queue_list_state.selected().map_or(0, |i| i + 1);
+                self.queue_list_state.select(Some(selected.min(last)));
+            }
+        }
     }
 
-    pub fn navigate_left(&mut self) {
+    pub fn navigate_top(&mut self) {
         let mut library = self.music_library.lock().unwrap();
-        library.prev_track();
+        match self.focus {
+            Focus::Artists => library.set_artist(0),
+            Focus::Albums => {
+                let first = library.get_current_artist().map(|artist| artist.albums.start);
+                if let Some(first) = first {
+                    library.set_album(first);
+                }
+            }
+            Focus::Tracks => library.set_track(0),
+            Focus::Queue => {
+                if !self.play_queue.is_empty() {
+                    self.queue_list_state.select(Some(0));
+                }
+            }
+        }
     }
 
-    pub fn navigate_right(&mut self) {
+    pub fn navigate_bottom(&mut self) {
         let mut library = self.music_library.lock().unwrap();
-        library.next_track();
+        match self.focus {
+            Focus::Artists => {
+                let last = library.artists.len().saturating_sub(1);
+                library.set_artist(last);
+            }
+            Focus::Albums => {
+                let last = library.get_current_artist().map(|artist| artist.albums.end - 1);
+                if let Some(last) = last {
+                    library.set_album(last);
+                }
+            }
+            Focus::Tracks => {
+                let last = library.track_count().saturating_sub(1);
+                library.set_track(last);
+            }
+            Focus::Queue => {
+                if !self.play_queue.is_empty() {
+                    self.queue_list_state.select(Some(self.play_queue.len() - 1));
+                }
+            }
+        }
+    }
+
+    /// Moves one column left: queue, tracks, albums, artists.
+    pub fn navigate_left(&mut self) {
+        self.set_focus(match self.focus {
+            Focus::Artists | Focus::Albums => Focus::Artists,
+            Focus::Tracks => Focus::Albums,
+            Focus::Queue => Focus::Tracks,
+        });
+    }
+
+    pub fn navigate_right(&mut self) {
+        self.set_focus(match self.focus {
+            Focus::Artists => Focus::Albums,
+            Focus::Albums => Focus::Tracks,
+            Focus::Tracks | Focus::Queue => Focus::Queue,
+        });
+    }
+
+    fn set_focus(&mut self, focus: Focus) {
+        if focus != Focus::Queue {
+            self.library_focus = focus;
+        }
+        self.focus = focus;
     }
 
     pub async fn select_item(&mut self) -> Result<()> {
-        let track_path = {
+        if self.focus == Focus::Queue {
+            return match self.queue_list_state.selected() {
+                Some(index) => self.play_queue_index(index),
+                None => Ok(()),
+            };
+        }
+
+        // Play the whole artist, the album, or the album from the selected track onwards
+        let (tracks, index) = {
             let library = self.music_library.lock().unwrap();
-            library.get_current_track_path()
+            let album = library
+                .get_current_album()
+                .map(|album| album.tracks.clone())
+                .unwrap_or_default();
+            match self.focus {
+                Focus::Artists => (library.artist_tracks(), 0),
+                Focus::Albums => (album, 0),
+                _ => (album, library.current_track_index),
+            }
         };
+        if tracks.is_empty() {
+            return Ok(());
+        }
+
+        let shuffled = self.play_queue.is_shuffled();
+        self.play_queue.disable_shuffle();
+        self.play_queue.clear();
+        self.play_queue.enqueue_all(tracks);
+        self.play_queue_index(index)?;
+
+        // Keep shuffling, now starting from the chosen track
+        if shuffled {
+            self.enable_shuffle();
+        }
+        Ok(())
+    }
+
+    /// Opens a path given on the command line: folders become music roots, files and
+    /// playlists play.
+    pub fn open_path(&mut self, path: PathBuf) {
+        if path.is_dir() {
+            self.music_library.lock().unwrap().add_root(&path);
+            self.save_roots();
+            return;
+        }
+        if playlist::is_playlist(&path) {
+            match Playlist::load(&path) {
+                Ok(playlist) => self.play_playlist(&playlist, false),
+                Err(err) => {
+                    self.status_message = Some((
+                        format!("Can't read {}: {err}", path.display()),
+                        Instant::now(),
+                    ))
+                }
+            }
+            return;
+        }
+
+        let result = TrackMetadata::from_file(&path).and_then(|track| {
+            self.play_queue.disable_shuffle();
+            self.play_queue.clear();
+            self.play_queue.enqueue(track);
+            self.play_queue_index(0)
+        });
+        if let Err(err) = result {
+            self.status_message = Some((
+                format!("Can't play {}: {err}", path.display()),
+                Instant::now(),
+            ));
+        }
+    }
 
-        if let Some(path) = track_path {
-            let mut player = self.audio_player.lock().unwrap();
-            player.load_file(&path)?;
+    fn play_queue_index(&mut self, index: usize) -> Result<()> {
+        let track = self.play_queue.play_index(index).cloned();
+        self.play_track(track)
+    }
+
+    fn play_track(&mut self, track: Option<TrackMetadata>) -> Result<()> {
+        if let Some(track) = track {
+            let player = self.audio_player.lock().unwrap();
+            player.load_file(&track.file_path);
             player.play();
         }
+        self.queue_following_track();
 
         Ok(())
     }
 
+    pub fn next_track(&mut self) -> Result<()> {
+        let track = self.play_queue.skip_forward().cloned();
+        self.play_track(track)
+    }
+
+    pub fn previous_track(&mut self) -> Result<()> {
+        let track = self.play_queue.previous().cloned();
+        self.play_track(track)
+    }
+
+    pub fn toggle_shuffle(&mut self) {
+        if self.play_queue.is_shuffled() {
+            self.play_queue.disable_shuffle();
+        } else {
+            self.enable_shuffle();
+        }
+        self.queue_list_state.select(self.play_queue.current_index());
+        self.queue_following_track();
+    }
+
+    fn enable_shuffle(&mut self) {
+        let library = self.music_library.lock().unwrap();
+        self.play_queue
+            .enable_shuffle(&library.all_tracks, queue::random_seed());
+    }
+
+    pub fn cycle_repeat(&mut self) {
+        self.play_queue.cycle_repeat();
+        self.queue_following_track();
+    }
+
+    fn selected_library_track(&self) -> Option<TrackMetadata> {
+        self.music_library.lock().unwrap().get_current_track().cloned()
+    }
+
+    pub fn toggle_focus(&mut self) {
+        self.focus = match self.focus {
+            Focus::Queue => self.library_focus,
+            _ => Focus::Queue,
+        };
+    }
+
+    /// Adds the selected library track to the end of the queue.
+    pub fn enqueue_selected(&mut self) {
+        if let Some(track) = self.selected_library_track() {
+            self.play_queue.enqueue(track);
+            self.queue_following_track();
+        }
+    }
+
+    /// Queues the selected library track to play right after the current one.
+    pub fn play_selected_next(&mut self) {
+        if let Some(track) = self.selected_library_track() {
+            self.play_queue.play_next(track);
+            self.queue_following_track();
+        }
+    }
+
+    pub fn remove_from_queue(&mut self) {
+        if self.focus != Focus::Queue {
+            return;
+        }
+        if let Some(index) = self.queue_list_state.selected() {
+            self.play_queue.remove(index);
+            let last = self.play_queue.len().saturating_sub(1);
+            self.queue_list_state.select(Some(index.min(last)));
+            self.queue_following_track();
+        }
+    }
+
+    pub fn move_queue_entry_up(&mut self) {
+        if let (Focus::Queue, Some(index)) = (self.focus, self.queue_list_state.selected()) {
+            let index = self.play_queue.move_up(index);
+            self.queue_list_state.select(Some(index));
+            self.queue_following_track();
+        }
+    }
+
+    pub fn move_queue_entry_down(&mut self) {
+        if let (Focus::Queue, Some(index)) = (self.focus, self.queue_list_state.selected()) {
+            let index = self.play_queue.move_down(index);
+            self.queue_list_state.select(Some(index));
+            self.queue_following_track();
+        }
+    }
+
+    pub fn clear_queue(&mut self) {
+        self.play_queue.clear();
+        self.queue_list_state.select(None);
+        self.queue_following_track();
+    }
+
+    /// Lines up the next track of the album so playback continues without a gap.
+    fn queue_following_track(&self) {
+        let next_path = self
+            .play_queue
+            .peek_next()
+            .map(|track| track.file_path.clone());
+
+        self.audio_player
+            .lock()
+            .unwrap()
+            .queue_next(next_path.as_deref());
+    }
+
+    /// Rescans the music roots in the background and watches them for changes from then on.
+    /// Any scan already running is cancelled.
+    pub fn start_scan(&mut self) {
+        let roots = self.music_library.lock().unwrap().music_roots.clone();
+        self.scan = None;
+        self.watcher = None;
+        if roots.is_empty() {
+            return;
+        }
+
+        // Start watching first so nothing slips through while the scan runs
+        let extensions = self.config.library.extensions.clone();
+        match LibraryWatcher::start(&roots, extensions.clone(), Arc::clone(&self.cache)) {
+            Ok(watcher) => self.watcher = Some(watcher),
+            Err(err) => {
+                self.status_message =
+                    Some((format!("Not watching music folders: {err}"), Instant::now()));
+            }
+        }
+        self.scan = Some(LibraryScan::start(roots, extensions, Arc::clone(&self.cache)));
+    }
+
+    /// Remembers the music roots in the config file.
+    fn save_roots(&mut self) {
+        let roots = self.music_library.lock().unwrap().music_roots.clone();
+        if let Err(err) = self.config.save_roots(&roots) {
+            self.status_message = Some((format!("Couldn't save music folders: {err}"), Instant::now()));
+        }
+    }
+
+    pub fn cancel_scan(&mut self) {
+        if let Some(scan) = &self.scan {
+            scan.cancel();
+        }
+    }
+
+    /// Applies files added, changed or deleted under the music roots.
+    fn poll_watcher(&mut self) {
+        let Some(watcher) = &self.watcher else {
+            return;
+        };
+        let mut library = self.music_library.lock().unwrap();
+        for changes in watcher.poll_changes() {
+            library.remove_paths(&changes.removed);
+            library.merge_tracks(changes.updated);
+        }
+    }
+
+    /// Applies whatever the background scan found since the last frame.
+    fn poll_scan(&mut self) {
+        if let Some((_, shown_at)) = &self.status_message {
+            if shown_at.elapsed() > Duration::from_secs(5) {
+                self.status_message = None;
+            }
+        }
+
+        let Some(scan) = &self.scan else {
+            return;
+        };
+        let updates = scan.poll_updates();
+        if updates.is_empty() {
+            return;
+        }
+
+        let mut tracks = Vec::new();
+        let mut outcome = None;
+        for update in updates {
+            match update {
+                ScanUpdate::Tracks(batch) => tracks.extend(batch),
+                other => outcome = Some(other),
+            }
+        }
+
+        // One regroup per frame, however many batches arrived
+        let mut library = self.music_library.lock().unwrap();
+        library.merge_tracks(tracks);
+
+        let Some(outcome) = outcome else {
+            return;
+        };
+        let counts = scan.progress();
+        let message = match outcome {
+            ScanUpdate::Finished { found } => {
+                library.retain_paths(&found);
+                format!(
+                    "Scan finished: {} tracks, {} new or changed, {} unreadable",
+                    library.all_tracks.len(),
+                    counts.parsed,
+                    counts.failed
+                )
+            }
+            ScanUpdate::Cancelled => "Scan cancelled".to_string(),
+            ScanUpdate::Failed(err) => format!("Scan failed: {err}"),
+            ScanUpdate::Tracks(_) => unreachable!(),
+        };
+        drop(library);
+
+        self.scan = None;
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    /// Nudges the volume by `delta` (0.0 - 1.0 scale).
+    pub fn change_volume(&mut self, delta: f32) {
+        let player = self.audio_player.lock().unwrap();
+        player.set_volume(player.get_volume() + delta);
+    }
+
+    pub fn toggle_mute(&mut self) {
+        self.audio_player.lock().unwrap().toggle_mute();
+    }
+
+    /// Switches to the next album order and remembers it in the config.
+    pub fn cycle_sort(&mut self) {
+        let mut library = self.music_library.lock().unwrap();
+        let sort = library.sort.next();
+        library.set_sort(sort);
+        drop(library);
+
+        if let Err(err) = self.config.save_sort(sort) {
+            self.status_message = Some((format!("Couldn't save sort order: {err}"), Instant::now()));
+        }
+    }
+
     pub fn cycle_theme(&mut self) {
         let mut app_state = self.app_state.lock().unwrap();
         app_state.cycle_theme();
+        let theme = app_state.current_theme;
+        drop(app_state);
+
+        if let Err(err) = self.config.save_theme(theme) {
+            self.status_message = Some((format!("Couldn't save theme: {err}"), Instant::now()));
+        }
     }
 
     pub fn toggle_rainbow_mode(&mut self) {
@@ -947,5 +1950,559 @@ impl App {
     pub fn toggle_directory_selector(&mut self) {
         let mut app_state = self.app_state.lock().unwrap();
         app_state.toggle_directory_selector();
+        self.directory_selector = if app_state.show_directory_selector {
+            let library = self.music_library.lock().unwrap();
+            Some(DirectorySelector::for_roots(&library.music_roots))
+        } else {
+            None
+        };
+    }
+
+    pub fn toggle_help(&mut self) {
+        self.app_state.lock().unwrap().toggle_help();
+    }
+
+    pub fn help_open(&self) -> bool {
+        self.app_state.lock().unwrap().show_help
+    }
+
+    /// Runs a key press through the keymap.
+    pub fn handle_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        self.keymap.handle(code, modifiers)
+    }
+
+    /// Shows the details of the selected queue entry or library track, or of the track
+    /// playing when the artist or album column has focus.
+    pub fn open_track_details(&mut self) {
+        let selected_queue_entry = || {
+            self.queue_list_state
+                .selected()
+                .and_then(|index| self.play_queue.tracks().get(index).cloned())
+        };
+        self.track_details = match self.focus {
+            Focus::Queue => selected_queue_entry(),
+            Focus::Tracks => self.selected_library_track(),
+            Focus::Artists | Focus::Albums => self
+                .play_queue
+                .now_playing()
+                .cloned()
+                .or_else(|| self.selected_library_track()),
+        };
+    }
+
+    pub fn close_track_details(&mut self) {
+        self.track_details = None;
+    }
+
+    pub fn track_details_open(&self) -> bool {
+        self.track_details.is_some()
+    }
+
+    /// Marks or unmarks the selected track, so several can be edited at once.
+    pub fn toggle_mark(&mut self) {
+        let track = match self.focus {
+            Focus::Queue => self
+                .queue_list_state
+                .selected()
+                .and_then(|index| self.play_queue.tracks().get(index).cloned()),
+            _ => self.selected_library_track(),
+        };
+        if let Some(track) = track {
+            if !self.marked.remove(&track.file_path) {
+                self.marked.insert(track.file_path);
+            }
+        }
+    }
+
+    /// The marked tracks, or else what the focused column points at: the artist's
+    /// tracks, the album, the track or the queue entry.
+    fn selected_tracks(&self) -> Vec<TrackMetadata> {
+        let library = self.music_library.lock().unwrap();
+        if !self.marked.is_empty() {
+            return library
+                .all_tracks
+                .iter()
+                .filter(|track| self.marked.contains(&track.file_path))
+                .cloned()
+                .collect();
+        }
+        match self.focus {
+            Focus::Artists => library.artist_tracks(),
+            Focus::Albums => library
+                .get_current_album()
+                .map(|album| album.tracks.clone())
+                .unwrap_or_default(),
+            Focus::Tracks => library.get_current_track().cloned().into_iter().collect(),
+            Focus::Queue => self
+                .queue_list_state
+                .selected()
+                .and_then(|index| self.play_queue.tracks().get(index).cloned())
+                .into_iter()
+                .collect(),
+        }
+    }
+
+    pub fn open_tag_editor(&mut self) {
+        let tracks = self.selected_tracks();
+        if !tracks.is_empty() {
+            self.tag_editor = Some(TagEditor::new(tracks));
+        }
+    }
+
+    pub fn tag_editor_open(&self) -> bool {
+        self.tag_editor.is_some()
+    }
+
+    /// Keys while the tag editor is open: typing edits the selected field.
+    pub fn handle_tag_editor_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let Some(editor) = self.tag_editor.as_mut() else {
+            return;
+        };
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+        let clearing = control && code == KeyCode::Char('u');
+        if !clearing {
+            editor.confirm_clear = false;
+        }
+
+        match code {
+            KeyCode::Esc => self.tag_editor = None,
+            KeyCode::Enter if !editor.has_edits() => self.tag_editor = None,
+            KeyCode::Enter => match editor.save(&self.cache) {
+                Ok(saved) => {
+                    self.tag_editor = None;
+                    self.apply_saved_tags(saved);
+                }
+                Err(err) => editor.error = Some(err.to_string()),
+            },
+            KeyCode::Up | KeyCode::BackTab => editor.move_up(),
+            KeyCode::Down | KeyCode::Tab => editor.move_down(),
+            KeyCode::Backspace => editor.pop(),
+            KeyCode::Char('u') if control => editor.clear(),
+            KeyCode::Char(c) if !control && !modifiers.contains(KeyModifiers::ALT) => {
+                editor.push(c);
+                editor.error = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// Puts re-read tags into the library and the queue, regrouping albums so renamed
+    /// ones move to where they now belong.
+    fn apply_saved_tags(&mut self, saved: SavedTags) {
+        let total = saved.tracks.len() + saved.failed.len();
+        if let Some(first) = saved.tracks.first() {
+            let mut library = self.music_library.lock().unwrap();
+            let path = first.file_path.clone();
+            library.merge_tracks(saved.tracks.clone());
+            library.select_path(&path);
+        }
+        self.play_queue.update_tracks(&saved.tracks);
+        self.marked.clear();
+
+        let message = match saved.failed.first() {
+            None => format!("Saved tags of {total} track(s)"),
+            Some((path, err)) => format!(
+                "Couldn't write {} of {total} file(s), e.g. {path}: {err}",
+                saved.failed.len()
+            ),
+        };
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    pub fn toggle_playlists(&mut self) {
+        self.playlists = match self.playlists {
+            Some(_) => None,
+            None => {
+                let library = self.music_library.lock().unwrap();
+                Some(PlaylistBrowser::load(&library.music_roots, &library.all_tracks))
+            }
+        };
+    }
+
+    pub fn playlists_open(&self) -> bool {
+        self.playlists.is_some()
     }
+
+    /// Whether a new playlist's name is being typed, which takes every key.
+    pub fn naming_playlist(&self) -> bool {
+        self.playlists
+            .as_ref()
+            .is_some_and(|browser| browser.naming.is_some())
+    }
+
+    /// Keys while the playlists popup is open. Movement follows the keymap, the popup's
+    /// own commands are fixed keys.
+    pub fn handle_playlists_key(&mut self, code: KeyCode, action: Option<Action>) {
+        let Some(browser) = self.playlists.as_mut() else {
+            return;
+        };
+
+        match (action, code) {
+            (Some(Action::Playlists), _) | (_, KeyCode::Esc) => self.playlists = None,
+            (Some(Action::Up), _) => browser.move_up(),
+            (Some(Action::Down), _) => browser.move_down(),
+            (Some(Action::Select), _) => {
+                if let Some(playlist) = browser.selected().cloned() {
+                    self.playlists = None;
+                    self.play_playlist(&playlist, false);
+                }
+            }
+            (_, KeyCode::Char('a')) => {
+                if let Some(playlist) = browser.selected().cloned() {
+                    self.play_playlist(&playlist, true);
+                    if let Some(browser) = self.playlists.as_mut() {
+                        browser.message = self.status_message.take().map(|(message, _)| message);
+                    }
+                }
+            }
+            (_, KeyCode::Char('s')) => {
+                let tracks = self.play_queue.tracks().to_vec();
+                self.start_naming_playlist(tracks, "The queue is empty");
+            }
+            (_, KeyCode::Char('l')) => {
+                let tracks = self.selected_tracks();
+                self.start_naming_playlist(tracks, "Nothing is selected in the library");
+            }
+            _ => {}
+        }
+    }
+
+    pub fn toggle_output_picker(&mut self) {
+        self.output_picker = match self.output_picker {
+            Some(_) => None,
+            None => {
+                let active = self.audio_player.lock().unwrap().output_device();
+                Some(OutputPicker::load(active))
+            }
+        };
+    }
+
+    pub fn output_picker_open(&self) -> bool {
+        self.output_picker.is_some()
+    }
+
+    /// Keys while the output device popup is open. ENTER moves playback to the selected
+    /// device and D back to the system default; either is remembered in the config.
+    pub fn handle_output_picker_key(&mut self, code: KeyCode, action: Option<Action>) {
+        let Some(picker) = self.output_picker.as_mut() else {
+            return;
+        };
+
+        let device = match (action, code) {
+            (Some(Action::OutputDevices), _) | (_, KeyCode::Esc) => {
+                self.output_picker = None;
+                return;
+            }
+            (Some(Action::Up), _) => {
+                picker.move_up();
+                return;
+            }
+            (Some(Action::Down), _) => {
+                picker.move_down();
+                return;
+            }
+            (Some(Action::Select), _) => match picker.selected() {
+                Some(device) => Some(device.id.clone()),
+                None => return,
+            },
+            (_, KeyCode::Char('d' | 'D')) => None,
+            _ => return,
+        };
+
+        self.output_picker = None;
+        self.audio_player
+            .lock()
+            .unwrap()
+            .set_output_device(device.clone());
+        if let Err(err) = self.config.save_output_device(device.as_ref()) {
+            self.status_message = Some((format!("Couldn't save output device: {err}"), Instant::now()));
+        }
+    }
+
+    fn start_naming_playlist(&mut self, tracks: Vec<TrackMetadata>, empty_message: &str) {
+        let Some(browser) = self.playlists.as_mut() else {
+            return;
+        };
+        if tracks.is_empty() {
+            browser.message = Some(empty_message.to_string());
+            return;
+        }
+        browser.naming = Some(NewPlaylist {
+            name: String::new(),
+            tracks,
+        });
+    }
+
+    /// Keys while a new playlist's name is typed.
+    pub fn handle_playlist_name_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let roots = self.music_library.lock().unwrap().music_roots.clone();
+        let Some(browser) = self.playlists.as_mut() else {
+            return;
+        };
+        let Some(new) = browser.naming.as_mut() else {
+            return;
+        };
+
+        match code {
+            KeyCode::Esc => browser.naming = None,
+            KeyCode::Enter => match Playlist::create(&new.name, &roots, &new.tracks) {
+                Ok(playlist) => {
+                    self.marked.clear();
+                    let library = self.music_library.lock().unwrap();
+                    let mut reloaded = PlaylistBrowser::load(&roots, &library.all_tracks);
+                    reloaded.select_path(&playlist.path);
+                    reloaded.message = Some(format!("Saved {}", playlist.path.display()));
+                    *browser = reloaded;
+                }
+                Err(err) => browser.message = Some(err.to_string()),
+            },
+            KeyCode::Backspace => {
+                new.name.pop();
+            }
+            KeyCode::Char(c)
+                if !modifiers.intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                new.name.push(c);
+            }
+            _ => {}
+        }
+    }
+
+    /// Plays a playlist, or adds it to the end of the queue. Files that are gone are
+    /// skipped and counted in the status message.
+    fn play_playlist(&mut self, playlist: &Playlist, enqueue: bool) {
+        let tracks: Vec<TrackMetadata> = {
+            let library = self.music_library.lock().unwrap();
+            let by_path: HashMap<&str, &TrackMetadata> = library
+                .all_tracks
+                .iter()
+                .map(|track| (track.file_path.as_str(), track))
+                .collect();
+            // Files outside the music roots are read on the spot
+            playlist
+                .entries
+                .iter()
+                .filter(|entry| !entry.missing)
+                .filter_map(|entry| {
+                    let path = entry.path.to_string_lossy();
+                    match by_path.get(path.as_ref()) {
+                        Some(track) => Some((*track).clone()),
+                        None => TrackMetadata::from_file(&entry.path).ok(),
+                    }
+                })
+                .collect()
+        };
+        let skipped = playlist.entries.len() - tracks.len();
+        let count = tracks.len();
+
+        let result = if enqueue {
+            self.play_queue.enqueue_all(tracks);
+            self.queue_following_track();
+            Ok(())
+        } else if tracks.is_empty() {
+            Ok(())
+        } else {
+            self.play_queue.disable_shuffle();
+            self.play_queue.clear();
+            self.play_queue.enqueue_all(tracks);
+            self.play_queue_index(0)
+        };
+
+        let verb = if enqueue { "Queued" } else { "Playing" };
+        let mut message = format!("{verb} {} ({count} tracks", playlist.name);
+        if skipped > 0 {
+            message.push_str(&format!(", {skipped} missing"));
+        }
+        message.push(')');
+        if let Err(err) = result {
+            message = format!("Can't play {}: {err}", playlist.name);
+        }
+        self.status_message = Some((message, Instant::now()));
+    }
+
+    pub fn open_search(&mut self) {
+        self.search = Some(Search::new());
+    }
+
+    pub fn search_open(&self) -> bool {
+        self.search.is_some()
+    }
+
+    /// Keys while the search popup is open: typing edits the query and the results
+    /// follow straight away.
+    pub fn handle_search_key(&mut self, code: KeyCode, modifiers: KeyModifiers) {
+        let Some(search) = self.search.as_mut() else {
+            return;
+        };
+        let control = modifiers.contains(KeyModifiers::CONTROL);
+
+        match code {
+            KeyCode::Esc => self.search = None,
+            KeyCode::Enter => {
+                // Show the track in the library
+                if let Some(hit) = search.selected() {
+                    let path = hit.track.file_path.clone();
+                    self.music_library.lock().unwrap().select_path(&path);
+                    self.set_focus(Focus::Tracks);
+                }
+                self.search = None;
+            }
+            KeyCode::Char('a') if control => {
+                if let Some(track) = search.selected().map(|hit| hit.track.clone()) {
+                    search.message = Some(format!("Added {} to the queue", track.title));
+                    self.play_queue.enqueue(track);
+                    self.queue_following_track();
+                }
+            }
+            KeyCode::Up => search.move_up(),
+            KeyCode::Char('p') if control => search.move_up(),
+            KeyCode::Down => search.move_down(),
+            KeyCode::Char('n') if control => search.move_down(),
+            KeyCode::Backspace => {
+                search.query.pop();
+                search.update(&self.music_library.lock().unwrap().all_tracks);
+            }
+            KeyCode::Char('u') if control => {
+                search.query.clear();
+                search.update(&self.music_library.lock().unwrap().all_tracks);
+            }
+            KeyCode::Char(c) if !control && !modifiers.contains(KeyModifiers::ALT) => {
+                search.query.push(c);
+                search.message = None;
+                search.update(&self.music_library.lock().unwrap().all_tracks);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn directory_selector_open(&self) -> bool {
+        self.directory_selector.is_some()
+    }
+
+    /// Keys while the directory selector popup is open. Movement follows the keymap,
+    /// the popup's own commands are fixed keys.
+    pub fn handle_directory_selector_key(&mut self, code: KeyCode, action: Option<Action>) {
+        let Some(selector) = self.directory_selector.as_mut() else {
+            return;
+        };
+        let mut library = self.music_library.lock().unwrap();
+        let root_count = library.music_roots.len();
+
+        let in_browser = selector.pane == SelectorPane::Browser;
+        match (action, code) {
+            (Some(Action::DirectorySelector), _) | (_, KeyCode::Esc) => {
+                drop(library);
+                self.toggle_directory_selector();
+            }
+            (Some(Action::ToggleFocus), _) => selector.toggle_pane(),
+            (Some(Action::Up), _) => selector.move_up(root_count),
+            (Some(Action::Down), _) => selector.move_down(root_count),
+            (Some(Action::Select | Action::Right), _) if in_browser => selector.enter(),
+            (Some(Action::Left), _) | (_, KeyCode::Backspace) if in_browser => selector.leave(),
+            (_, KeyCode::Char('a')) => {
+                let dir = selector.selected_dir();
+                selector.message = if library.add_root(&dir) {
+                    // Saved straight away, so closing with Esc keeps it too
+                    match self.config.save_roots(&library.music_roots) {
+                        Ok(()) => Some(format!("Added {} - press S to scan", dir.display())),
+                        Err(err) => Some(format!("Couldn't save music folders: {err}")),
+                    }
+                } else {
+                    Some(format!("{} is already in the library", dir.display()))
+                };
+            }
+            (_, KeyCode::Char('x') | KeyCode::Delete) if !in_browser => {
+                if let Some(index) = selector.roots_state.selected() {
+                    library.remove_root(index);
+                    selector.clamp_roots(library.music_roots.len());
+                    selector.message = match self.config.save_roots(&library.music_roots) {
+                        Ok(()) => Some("Root removed - press S to rescan".to_string()),
+                        Err(err) => Some(format!("Couldn't save music folders: {err}")),
+                    };
+                }
+            }
+            (_, KeyCode::Char('s')) => {
+                drop(library);
+                self.toggle_directory_selector();
+                self.save_roots();
+                self.start_scan();
+            }
+            _ => {}
+        }
+    }
+}
+
+// What the shortcuts bar shows, with the keys looked up in the keymap
+const SHORTCUT_BAR: [(&[Action], &str); 22] = [
+    (&[Action::PlayPause], "Play/Pause"),
+    (&[Action::Up, Action::Down], "Move"),
+    (&[Action::Left, Action::Right], "Columns"),
+    (&[Action::Select], "Select"),
+    (&[Action::Search], "Search"),
+    (&[Action::SeekBack, Action::SeekForward], "Seek 5s"),
+    (&[Action::SeekBackLong, Action::SeekForwardLong], "Seek 30s"),
+    (&[Action::ToggleFocus], "Queue"),
+    (&[Action::CycleSort], "Sort"),
+    (&[Action::Enqueue], "Enqueue"),
+    (&[Action::PlayNext], "Play Next"),
+    (&[Action::PreviousTrack, Action::NextTrack], "Prev/Next"),
+    (&[Action::ToggleShuffle], "Shuffle"),
+    (&[Action::CycleRepeat], "Repeat"),
+    (&[Action::VolumeUp, Action::VolumeDown], "Volume"),
+    (&[Action::Mute], "Mute"),
+    (&[Action::CycleTheme], "Theme"),
+    (&[Action::ToggleRainbow], "Rainbow"),
+    (&[Action::ToggleShortcuts], "Shortcuts"),
+    (&[Action::DirectorySelector], "Directory"),
+    (&[Action::Help], "Help"),
+    (&[Action::Quit], "Quit"),
+];
+
+/// `text` as spans, with the characters at `positions` in `matched` style.
+fn highlight(text: &str, positions: &[usize], style: Style, matched: Style) -> Vec<Span<'static>> {
+    let mut spans = Vec::new();
+    let mut run = String::new();
+    let mut run_matched = false;
+    for (i, c) in text.chars().enumerate() {
+        let is_match = positions.contains(&i);
+        if is_match != run_matched && !run.is_empty() {
+            let style = if run_matched { matched } else { style };
+            spans.push(Span::styled(std::mem::take(&mut run), style));
+        }
+        run_matched = is_match;
+        run.push(c);
+    }
+    if !run.is_empty() {
+        spans.push(Span::styled(run, if run_matched { matched } else { style }));
+    }
+    spans
+}
+
+/// Runs symbol keys together like `↑↓`, and separates anything longer with `/`.
+fn join_key_labels(keys: &[String]) -> String {
+    let single_symbols = keys
+        .iter()
+        .all(|key| key.chars().count() == 1 && !key.chars().all(char::is_alphanumeric));
+    keys.join(if single_symbols { "" } else { "/" })
+}
+
+/// A rectangle of the given percentage size in the middle of `area`.
+fn centered_rect(percent_x: u16, percent_y: u16, area: Rect) -> Rect {
+    let vertical = Layout::default()
+        .direction(Direction::Vertical)
+        .constraints([
+            Constraint::Percentage((100 - percent_y) / 2),
+            Constraint::Percentage(percent_y),
+            Constraint::Percentage((100 - percent_y) / 2),
+        ])
+        .split(area);
+
+    Layout::default()
+        .direction(Direction::Horizontal)
+        .constraints([
+            Constraint::Percentage((100 - percent_x) / 2),
+            Constraint::Percentage(percent_x),
+            Constraint::Percentage((100 - percent_x) / 2),
+        ])
+        .split(vertical[1])[1]
 }
\ No newline at end of file
@@ -0,0 +1,148 @@
+use crate::{
+    cache::{FileStamp, SharedCache},
+    metadata::TrackMetadata,
+    scanner,
+};
+use anyhow::Result;
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use std::{
+    collections::BTreeSet,
+    fs,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+use walkdir::WalkDir;
+
+// Quiet period before a burst of events is handled, e.g. while an album is copied in
+const DEBOUNCE: Duration = Duration::from_secs(2);
+
+/// What changed on disk in one debounced burst.
+#[derive(Debug, Default)]
+pub struct LibraryChanges {
+    /// New files, and files whose tags or contents changed.
+    pub updated: Vec<TrackMetadata>,
+    /// Deleted files or directories; every track at or below these paths is gone.
+    pub removed: Vec<PathBuf>,
+}
+
+/// Watches the music roots and reports files as they come, change and go.
+pub struct LibraryWatcher {
+    // Watching stops when this is dropped
+    _debouncer: Debouncer<RecommendedWatcher>,
+    changes: Receiver<LibraryChanges>,
+}
+
+impl LibraryWatcher {
+    pub fn start(roots: &[PathBuf], extensions: Vec<String>, cache: SharedCache) -> Result<Self> {
+        let (paths_tx, paths_rx) = mpsc::channel::<Vec<PathBuf>>();
+        let (changes_tx, changes) = mpsc::channel();
+
+        let mut debouncer = new_debouncer(DEBOUNCE, move |result: DebounceEventResult| {
+            if let Ok(events) = result {
+                let _ = paths_tx.send(events.into_iter().map(|event| event.path).collect());
+            }
+        })?;
+        for root in roots {
+            debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+        }
+
+        // Tags are read off the debouncer's thread; this ends once the debouncer is dropped
+        thread::spawn(move || {
+            for paths in paths_rx {
+                let changes = collect_changes(paths, &extensions, &cache);
+                if changes.updated.is_empty() && changes.removed.is_empty() {
+                    continue;
+                }
+                if changes_tx.send(changes).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Ok(Self {
+            _debouncer: debouncer,
+            changes,
+        })
+    }
+
+    /// Everything that changed since the last call.
+    pub fn poll_changes(&self) -> Vec<LibraryChanges> {
+        self.changes.try_iter().collect()
+    }
+}
+
+/// Works out what the event paths mean for the library and updates the cache to match.
+fn collect_changes(
+    paths: Vec<PathBuf>,
+    extensions: &[String],
+    cache: &SharedCache,
+) -> LibraryChanges {
+    let mut changes = LibraryChanges::default();
+
+    let paths: BTreeSet<PathBuf> = paths.into_iter().collect();
+    for path in paths {
+        if path.is_dir() {
+            // A directory moved or copied in
+            for entry in WalkDir::new(&path)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.file_type().is_file())
+            {
+                check_file(entry.path(), extensions, cache, &mut changes);
+            }
+        } else if path.exists() {
+            check_file(&path, extensions, cache, &mut changes);
+        } else {
+            changes.removed.push(path);
+        }
+    }
+
+    let mut cache = cache.lock().unwrap();
+    if !changes.removed.is_empty() {
+        cache.retain(|file| {
+            !changes
+                .removed
+                .iter()
+                .any(|removed| Path::new(file).starts_with(removed))
+        });
+    }
+    if !changes.updated.is_empty() || !changes.removed.is_empty() {
+        let _ = cache.save();
+    }
+    changes
+}
+
+fn check_file(
+    path: &Path,
+    extensions: &[String],
+    cache: &SharedCache,
+    changes: &mut LibraryChanges,
+) {
+    if !scanner::is_supported(path, extensions) {
+        return;
+    }
+    let Ok(file_metadata) = fs::metadata(path) else {
+        return;
+    };
+    let key = path.to_string_lossy().to_string();
+    let stamp = FileStamp::from_metadata(&file_metadata);
+
+    // Events also fire for files that were only touched
+    if cache.lock().unwrap().get(&key, stamp).is_some() {
+        return;
+    }
+    match TrackMetadata::from_file(path) {
+        Ok(metadata) => {
+            let mut cache = cache.lock().unwrap();
+            changes.updated.push(cache.insert(stamp, metadata).clone());
+        }
+        // Possibly still being written; drop it until the next event for it
+        Err(_) => changes.removed.push(path.to_path_buf()),
+    }
+}
\ No newline at end of file